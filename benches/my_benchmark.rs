@@ -1,10 +1,14 @@
 use rand::prelude::*;
 use rand_chacha::ChaChaRng;
+use snapcd::chunker::{ChunkerAlgorithm, ChunkerConfig};
 use snapcd::file::put_data;
-use snapcd::DataStore;
+use snapcd::{key::Key, DataStore};
+use std::collections::HashSet;
 use std::io::{self, Read};
 use std::time::Duration;
 
+use snapcd::ds::{null::NullDS, sqlite::SqliteDS, Transactional};
+
 use criterion::{criterion_group, criterion_main, BatchSize, Criterion, Throughput};
 
 fn inner_bench<DS: DataStore, T: Fn() -> DS>(
@@ -32,7 +36,7 @@ fn inner_bench<DS: DataStore, T: Fn() -> DS>(
                 ds.begin_trans().unwrap();
                 ds
             },
-            |mut data| put_data(&mut data, &buf[..]).unwrap(),
+            |mut data| put_data(&mut data, &buf[..], &ChunkerConfig::default()).unwrap(),
             BatchSize::PerIteration,
         )
     });
@@ -54,16 +58,109 @@ fn perf_test_4MB_sqlite_memory(bench: &mut Criterion) {
 
 #[allow(non_snake_case)]
 fn perf_test_32B_null(bench: &mut Criterion) {
-    let ctor = || snapcd::ds::null::NullDS;
+    let ctor = || snapcd::ds::null::NullDS::default();
     inner_bench(&ctor, bench, 32, "put-data-32B-null");
 }
 
 #[allow(non_snake_case)]
 fn perf_test_4MB_null(bench: &mut Criterion) {
-    let ctor = || snapcd::ds::null::NullDS;
+    let ctor = || snapcd::ds::null::NullDS::default();
     inner_bench(&ctor, bench, 1 << 22, "put-data-4MB-null");
 }
 
+fn chunkers() -> Vec<(&'static str, ChunkerConfig)> {
+    vec![
+        ("gear", ChunkerConfig::default()),
+        (
+            "fastcdc",
+            ChunkerConfig {
+                algorithm: ChunkerAlgorithm::fastcdc(),
+                ..ChunkerConfig::default()
+            },
+        ),
+    ]
+}
+
+fn random_data(size: usize) -> Vec<u8> {
+    let mut buf = vec![0; size];
+    ChaChaRng::seed_from_u64(1).fill(&mut buf[..]);
+    buf
+}
+
+fn blob_keys<DS: DataStore>(ds: &DS, key: Key, out: &mut HashSet<Key>) {
+    let obj = ds.get_obj(key).unwrap();
+
+    if obj.keys().is_empty() {
+        out.insert(key);
+    }
+
+    for &child in obj.keys() {
+        blob_keys(ds, child, out);
+    }
+}
+
+fn chunker_throughput(bench: &mut Criterion) {
+    let buf = random_data(1 << 24);
+
+    let mut g = bench.benchmark_group("chunker-throughput");
+    g.throughput(Throughput::Bytes(buf.len() as u64));
+    g.sample_size(10);
+
+    for (name, config) in chunkers() {
+        g.bench_function(name, |b| {
+            b.iter(|| put_data(&mut NullDS::default(), &buf[..], &config).unwrap())
+        });
+    }
+
+    g.finish();
+}
+
+/// Puts data with a few bytes inserted near the start into a store that already has the original,
+/// which is where content defined chunking earns its keep. How many blobs were reused is printed
+/// alongside the timings.
+fn chunker_shifted_insert(bench: &mut Criterion) {
+    let original = random_data(1 << 22);
+
+    let mut shifted = original.clone();
+    shifted.splice(1000..1000, b"inserted".iter().copied());
+
+    let mut g = bench.benchmark_group("chunker-shifted-insert");
+    g.throughput(Throughput::Bytes(shifted.len() as u64));
+    g.sample_size(10);
+
+    for (name, config) in chunkers() {
+        let fresh_store = || {
+            let mut ds = SqliteDS::new(":memory:").unwrap();
+            ds.begin_trans().unwrap();
+            let key = put_data(&mut ds, &original[..], &config).unwrap();
+            (ds, key)
+        };
+
+        let (mut ds, key) = fresh_store();
+        let (mut before, mut after) = (HashSet::new(), HashSet::new());
+        blob_keys(&ds, key, &mut before);
+        let shifted_key = put_data(&mut ds, &shifted[..], &config).unwrap();
+        blob_keys(&ds, shifted_key, &mut after);
+
+        println!(
+            "{}: {} of {} blobs reused after the insertion",
+            name,
+            after.intersection(&before).count(),
+            after.len()
+        );
+
+        g.bench_function(name, |b| {
+            b.iter_batched(
+                || fresh_store().0,
+                |mut ds| put_data(&mut ds, &shifted[..], &config).unwrap(),
+                BatchSize::PerIteration,
+            )
+        });
+    }
+
+    g.finish();
+}
+
 criterion_group!(
     sqlite,
     perf_test_32B_sqlite_memory,
@@ -71,4 +168,6 @@ criterion_group!(
 );
 criterion_group!(null, perf_test_32B_null, perf_test_4MB_null);
 
-criterion_main!(sqlite, null);
+criterion_group!(chunker, chunker_throughput, chunker_shifted_insert);
+
+criterion_main!(sqlite, null, chunker);
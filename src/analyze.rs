@@ -0,0 +1,237 @@
+//! Working out how well a path would deduplicate, without storing anything.
+
+use crate::chunker::ChunkerConfig;
+use crate::dir::{self, FSItem};
+use crate::ds::{self, counting::CountingDS};
+use crate::key::{HashAlgorithm, Key, TypedKey};
+use crate::DataStore;
+use std::collections::{BTreeMap, HashSet};
+use std::convert::TryInto;
+use std::fs::DirEntry;
+use std::path::{Path, PathBuf};
+use thiserror::Error;
+
+#[derive(Debug, Error)]
+pub enum AnalyzeError {
+    #[error("error chunking path: {_0}")]
+    PutFsItemError(#[from] dir::PutFsItemError),
+
+    #[error("error reading chunked object: {_0}")]
+    GetObjError(#[from] ds::GetObjError),
+
+    #[error("error decoding chunked object: {_0}")]
+    DecodeError(#[from] serde_cbor::error::Error),
+
+    #[error("error checking the repository for an object: {_0}")]
+    RawExistsError(#[from] ds::RawExistsError),
+}
+
+/// What storing a path would look like.
+#[derive(Debug)]
+pub struct Analysis {
+    /// The key the path would be stored at.
+    pub root: Key,
+
+    /// Bytes of file data, counting duplicates.
+    pub total_bytes: u64,
+
+    /// Bytes of file data once duplicate blobs are removed.
+    pub unique_bytes: u64,
+
+    /// Blobs cut, counting duplicates.
+    pub chunks: u64,
+
+    /// Distinct blobs.
+    pub unique_chunks: u64,
+
+    /// Distinct blobs by size, keyed by `n` for sizes in `[2^n, 2^(n+1))`, with empty blobs under
+    /// 0 too.
+    pub histogram: BTreeMap<u32, u64>,
+
+    /// Directories down to the requested depth, starting with the path itself.
+    pub directories: Vec<DirectoryAnalysis>,
+}
+
+#[derive(Debug)]
+pub struct DirectoryAnalysis {
+    /// Relative to the analysed path.
+    pub path: PathBuf,
+    pub total_bytes: u64,
+    pub unique_bytes: u64,
+}
+
+impl Analysis {
+    /// How many times smaller the file data gets, 1 meaning no deduplication at all.
+    pub fn dedup_ratio(&self) -> f64 {
+        ratio(self.total_bytes, self.unique_bytes)
+    }
+}
+
+impl DirectoryAnalysis {
+    pub fn dedup_ratio(&self) -> f64 {
+        ratio(self.total_bytes, self.unique_bytes)
+    }
+}
+
+fn ratio(total: u64, unique: u64) -> f64 {
+    if unique == 0 {
+        1.0
+    } else {
+        total as f64 / unique as f64
+    }
+}
+
+/// What storing a path would add to an existing repository.
+#[derive(Debug, Default)]
+pub struct Incremental {
+    /// Objects that aren't in the repository yet.
+    pub new_objects: u64,
+
+    /// Bytes of file data in new blobs.
+    pub new_blob_bytes: u64,
+
+    /// Bytes of everything else that's new: directories, files and blob trees.
+    pub new_metadata_bytes: u64,
+}
+
+/// Chunks `path` the way `hash_algorithm` and `chunker_config` say, and reports on it. Directories
+/// are reported down to `depth` levels below `path`.
+///
+/// Nothing is stored, but everything but file data is kept in memory until this returns.
+pub fn analyze(
+    path: &Path,
+    filter: &dyn Fn(&DirEntry) -> bool,
+    hash_algorithm: HashAlgorithm,
+    chunker_config: ChunkerConfig,
+    depth: usize,
+) -> Result<(Analysis, CountingDS), AnalyzeError> {
+    let mut ds = CountingDS::new(hash_algorithm, chunker_config);
+
+    let root = dir::put_fs_item(&mut ds, path, filter)?;
+
+    let blob_sizes = ds.blob_sizes();
+
+    let mut histogram = BTreeMap::new();
+    for (_, size) in &blob_sizes {
+        *histogram.entry(size_bucket(*size)).or_default() += 1;
+    }
+
+    let mut directories = Vec::new();
+    analyze_directories(&ds, root.into(), PathBuf::new(), depth, &mut directories)?;
+
+    let (total_bytes, unique_bytes) = match directories.first() {
+        Some(d) => (d.total_bytes, d.unique_bytes),
+        None => file_bytes(&ds, root.into(), &mut HashSet::new())?,
+    };
+
+    let analysis = Analysis {
+        root,
+        total_bytes,
+        unique_bytes,
+        chunks: ds.blob_puts(),
+        unique_chunks: blob_sizes.len() as u64,
+        histogram,
+        directories,
+    };
+
+    Ok((analysis, ds))
+}
+
+/// Works out what of an analysed path isn't in `repo` already.
+pub fn incremental<DS: DataStore>(
+    repo: &DS,
+    counted: &CountingDS,
+) -> Result<Incremental, AnalyzeError> {
+    let mut result = Incremental::default();
+
+    for (key, size) in counted.blob_sizes() {
+        if !repo.raw_exists(&key.as_db_key())? {
+            result.new_objects += 1;
+            result.new_blob_bytes += size;
+        }
+    }
+
+    for (key, size) in counted.object_sizes() {
+        if !repo.raw_exists(&key.as_db_key())? {
+            result.new_objects += 1;
+            result.new_metadata_bytes += size;
+        }
+    }
+
+    Ok(result)
+}
+
+fn size_bucket(size: u64) -> u32 {
+    (63 - size.leading_zeros() as i32).max(0) as u32
+}
+
+fn analyze_directories(
+    ds: &CountingDS,
+    key: TypedKey<FSItem>,
+    path: PathBuf,
+    depth: usize,
+    out: &mut Vec<DirectoryAnalysis>,
+) -> Result<(), AnalyzeError> {
+    let item: FSItem = ds.get_obj(key.inner())?.try_into()?;
+
+    if !item.is_dir() {
+        return Ok(());
+    }
+
+    let (total_bytes, unique_bytes) = file_bytes(ds, key, &mut HashSet::new())?;
+
+    out.push(DirectoryAnalysis {
+        path: path.clone(),
+        total_bytes,
+        unique_bytes,
+    });
+
+    if depth > 0 {
+        for (name, child) in item.children() {
+            analyze_directories(ds, child, path.join(name), depth - 1, out)?;
+        }
+    }
+
+    Ok(())
+}
+
+/// The file bytes under `key`, and how many of them are in blobs not already in `seen`.
+fn file_bytes(
+    ds: &CountingDS,
+    key: TypedKey<FSItem>,
+    seen: &mut HashSet<Key>,
+) -> Result<(u64, u64), AnalyzeError> {
+    let item: FSItem = ds.get_obj(key.inner())?.try_into()?;
+
+    match item.data_key() {
+        Some(data) => Ok((item.size(), blob_bytes(ds, data, seen)?)),
+        None => {
+            let (mut total, mut unique) = (0, 0);
+
+            for (_, child) in item.children() {
+                let (t, u) = file_bytes(ds, child, seen)?;
+                total += t;
+                unique += u;
+            }
+
+            Ok((total, unique))
+        }
+    }
+}
+
+fn blob_bytes(ds: &CountingDS, key: Key, seen: &mut HashSet<Key>) -> Result<u64, AnalyzeError> {
+    if !seen.insert(key) {
+        return Ok(0);
+    }
+
+    if let Some(size) = ds.blob_size(key) {
+        return Ok(size);
+    }
+
+    let mut unique = 0;
+    for &child in ds.get_obj(key)?.keys() {
+        unique += blob_bytes(ds, child, seen)?;
+    }
+
+    Ok(unique)
+}
@@ -7,9 +7,64 @@ use crate::{ds, key};
 
 #[derive(Copy, Clone, Debug)]
 pub struct CacheKey {
+    pub dev: u64,
     pub inode: u64,
     pub mtime: i64,
+    pub mtime_nsec: i64,
     pub size: u64,
+
+    /// Changes with permissions and ownership too, which are stored with files.
+    pub ctime: i64,
+    pub ctime_nsec: i64,
+
+    /// Whether extended attributes were stored with the file, which gives it a different key.
+    pub xattrs: bool,
+}
+
+impl CacheKey {
+    pub fn from_metadata(meta: &std::fs::Metadata) -> Self {
+        use std::os::unix::fs::MetadataExt;
+
+        Self {
+            dev: meta.dev(),
+            inode: meta.ino(),
+            mtime: meta.mtime(),
+            mtime_nsec: meta.mtime_nsec(),
+            size: meta.size(),
+            ctime: meta.ctime(),
+            ctime_nsec: meta.ctime_nsec(),
+            xattrs: false,
+        }
+    }
+
+    /// Whether the file was modified or changed this second, in which case it could be modified
+    /// again without its times changing (on file systems that only keep them to the second, or
+    /// close to it, or if its mtime is set back), so it can't be trusted to be what was stored.
+    fn is_racy(self) -> bool {
+        let now = std::time::SystemTime::now()
+            .duration_since(std::time::UNIX_EPOCH)
+            .map_or(0, |d| d.as_secs());
+
+        self.mtime.max(self.ctime) >= now as i64
+    }
+
+    fn to_bytes(self) -> Vec<u8> {
+        let mut data = Vec::with_capacity(8 * 7 + 1);
+
+        data.extend(self.inode.to_le_bytes().iter());
+        data.extend(self.mtime.to_le_bytes().iter());
+        data.extend(self.size.to_le_bytes().iter());
+        data.extend(self.ctime.to_le_bytes().iter());
+        data.extend(self.dev.to_le_bytes().iter());
+        data.extend(self.mtime_nsec.to_le_bytes().iter());
+        data.extend(self.ctime_nsec.to_le_bytes().iter());
+
+        if self.xattrs {
+            data.push(1);
+        }
+
+        data
+    }
 }
 
 #[derive(Debug, Error)]
@@ -44,12 +99,11 @@ pub trait Cache: ds::Transactional {
     fn raw_put(&self, cachekey: &[u8], value: &[u8]) -> Result<(), RawPutCacheError>;
 
     fn get(&self, cachekey: CacheKey) -> Result<Option<key::Key>, GetCacheError> {
-        let mut data = Vec::with_capacity(8 * 3);
-        data.extend(cachekey.inode.to_le_bytes().iter());
-        data.extend(cachekey.mtime.to_le_bytes().iter());
-        data.extend(cachekey.size.to_le_bytes().iter());
+        if cachekey.is_racy() {
+            return Ok(None);
+        }
 
-        let cache_result = self.raw_get(&data)?;
+        let cache_result = self.raw_get(&cachekey.to_bytes())?;
 
         match cache_result {
             Some(data) => {
@@ -61,13 +115,11 @@ pub trait Cache: ds::Transactional {
     }
 
     fn put(&self, cachekey: CacheKey, value: key::Key) -> Result<(), PutCacheError> {
-        let mut data = Vec::with_capacity(8 * 3);
-
-        data.extend(cachekey.inode.to_le_bytes().iter());
-        data.extend(cachekey.mtime.to_le_bytes().iter());
-        data.extend(cachekey.size.to_le_bytes().iter());
+        if cachekey.is_racy() {
+            return Ok(());
+        }
 
-        self.raw_put(&data, &value.as_db_key())?;
+        self.raw_put(&cachekey.to_bytes(), &value.as_db_key())?;
 
         Ok(())
     }
@@ -0,0 +1,89 @@
+//! Canonical CBOR, so the same object always encodes to the same bytes, and so the same key.
+//!
+//! Values are encoded through [`serde_cbor::Value`], whose maps are ordered by the RFC 7049
+//! canonical ordering (shorter keys first, then bytewise). `serde_cbor` already writes integers
+//! and floats in their shortest form, and lengths are always definite once in a `Value`.
+
+use crate::object::{ObjType, Object};
+use serde::Serialize;
+use serde_cbor::Value;
+
+/// Encodes `value` as canonical CBOR. Every object written to the store goes through this.
+pub fn to_vec<T: Serialize + ?Sized>(value: &T) -> Result<Vec<u8>, serde_cbor::error::Error> {
+    serde_cbor::to_vec(&serde_cbor::value::to_value(value)?)
+}
+
+/// Whether `data` is CBOR that re-encodes to exactly the same bytes.
+pub fn is_canonical(data: &[u8]) -> bool {
+    match serde_cbor::from_slice::<Value>(data) {
+        Ok(value) => to_vec(&value).is_ok_and(|encoded| encoded == data),
+        Err(_) => false,
+    }
+}
+
+/// Whether the stored object `raw` (which decoded to `obj`) is canonical, along with its data
+/// section for types that store CBOR there.
+pub fn is_canonical_object(raw: &[u8], obj: &Object) -> bool {
+    let data_is_cbor = match obj.objtype() {
+        ObjType::Commit
+        | ObjType::FSItemDir
+        | ObjType::FSItemFile
+        | ObjType::FSItemSymlink
+        | ObjType::FSItemSpecial
+        | ObjType::ParityGroup
+        | ObjType::XattrSet
+        | ObjType::ZeroRun => true,
+        // Legacy trees have no sizes, and so no data.
+        ObjType::FileBlobTree => !obj.data().is_empty(),
+        ObjType::FileBlob | ObjType::Unknown => false,
+    };
+
+    if data_is_cbor && !is_canonical(obj.data()) {
+        return false;
+    }
+
+    to_vec(obj).is_ok_and(|encoded| encoded == raw)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::HashMap;
+
+    proptest::proptest! {
+        #[test]
+        fn canonical_encoding_is_stable(map: HashMap<String, Vec<u32>>, data: Vec<u8>, neg: i64) {
+            let value = (map, serde_bytes::ByteBuf::from(data), neg);
+
+            let encoded = to_vec(&value).unwrap();
+
+            assert!(is_canonical(&encoded));
+
+            let decoded: Value = serde_cbor::from_slice(&encoded).unwrap();
+            assert_eq!(to_vec(&decoded).unwrap(), encoded);
+        }
+    }
+
+    #[test]
+    fn object_encoding_is_unchanged() {
+        // Objects were always written in canonical order, so existing keys stay the same.
+        let child = crate::key::HashAlgorithm::Blake3B.hash(b"x");
+        let obj = Object::new(b"some data", &[child], ObjType::FileBlob);
+
+        assert_eq!(to_vec(&obj).unwrap(), serde_cbor::to_vec(&obj).unwrap());
+    }
+
+    #[test]
+    fn flags_unsorted_maps() {
+        // {"bb": 1, "a": 2}, with the longer key first.
+        let unsorted = [0xa2, 0x62, b'b', b'b', 0x01, 0x61, b'a', 0x02];
+        assert!(!is_canonical(&unsorted));
+
+        // The same, but with the integer 1 padded out to two bytes.
+        let padded = [0xa2, 0x61, b'a', 0x02, 0x62, b'b', b'b', 0x18, 0x01];
+        assert!(!is_canonical(&padded));
+
+        let sorted = [0xa2, 0x61, b'a', 0x02, 0x62, b'b', b'b', 0x01];
+        assert!(is_canonical(&sorted));
+    }
+}
@@ -0,0 +1,360 @@
+//! How file data is cut into blobs, and how those blobs are gathered into trees.
+//!
+//! Every client writing to a repository has to chunk the same way, or the same file would get
+//! different keys, so the parameters are chosen at `init` and recorded in the repository.
+
+use thiserror::Error;
+
+/// How blobs are cut out of file data.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, serde::Serialize, serde::Deserialize)]
+pub enum ChunkerAlgorithm {
+    /// A gear hash match, only taken once the blob is `2^blob_zero_count_max` bytes long. This is
+    /// what every repository used before FastCDC.
+    #[default]
+    Gear,
+
+    /// FastCDC with normalized chunking: cuts are harder to find before `avg_size` and easier
+    /// after, so blob sizes bunch up around it. Sizes are in bytes.
+    FastCdc {
+        min_size: u32,
+        avg_size: u32,
+        max_size: u32,
+    },
+}
+
+impl ChunkerAlgorithm {
+    /// FastCDC with blobs averaging 8 KiB, between 2 KiB and 64 KiB.
+    pub fn fastcdc() -> Self {
+        Self::FastCdc {
+            min_size: 1 << 11,
+            avg_size: 1 << 13,
+            max_size: 1 << 16,
+        }
+    }
+}
+
+#[derive(Debug, Error)]
+#[error("Unknown chunker '{_0}', expected gear or fastcdc")]
+pub struct ParseChunkerAlgorithmError(String);
+
+impl std::str::FromStr for ChunkerAlgorithm {
+    type Err = ParseChunkerAlgorithmError;
+
+    /// Parses a chunker name, with FastCDC getting its default sizes.
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "gear" => Ok(Self::Gear),
+            "fastcdc" => Ok(Self::fastcdc()),
+            _ => Err(ParseChunkerAlgorithmError(s.to_string())),
+        }
+    }
+}
+
+/// Parameters for [`put_data`](crate::file::put_data). Apart from FastCDC's, all sizes are powers
+/// of two, given as the number of bits.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, serde::Serialize, serde::Deserialize)]
+pub struct ChunkerConfig {
+    /// How blobs are cut. Configs stored before there was a choice don't have this, and used gear.
+    #[serde(default)]
+    pub algorithm: ChunkerAlgorithm,
+
+    /// Leading zero bits the rolling hash needs for a cut, so cut points come about every
+    /// `2^blob_zero_count` bytes.
+    pub blob_zero_count: u32,
+
+    /// Blobs are only cut once they're `2^blob_zero_count_max` bytes long, at the next cut point,
+    /// or after another `2^blob_zero_count_max` bytes if there isn't one.
+    pub blob_zero_count_max: u32,
+
+    /// Extra zero bits the hash at a cut needs to close a tree at each level, so trees average
+    /// `2^per_level_count` children.
+    ///
+    /// Gear cuts count their bits past `blob_zero_count`, and FastCDC cuts count theirs past the
+    /// bits of `avg_size`.
+    pub per_level_count: u32,
+
+    /// Trees are closed at `2^per_level_count_max` children whatever the hash says.
+    pub per_level_count_max: u32,
+
+    /// Runs of at least `2^zero_run_bits` zero bytes are stored as their length, and not chunked.
+    /// Repositories from before this was an option don't have it, and chunk zeros like anything
+    /// else.
+    #[serde(default)]
+    pub zero_run_bits: Option<u32>,
+}
+
+impl Default for ChunkerConfig {
+    /// The parameters every repository used before they were configurable.
+    fn default() -> Self {
+        Self {
+            algorithm: ChunkerAlgorithm::Gear,
+            blob_zero_count: 13,
+            blob_zero_count_max: 15,
+            per_level_count: 7,
+            per_level_count_max: 9,
+            zero_run_bits: None,
+        }
+    }
+}
+
+#[derive(Debug, Error)]
+pub enum InvalidChunkerConfig {
+    #[error("blob zero count must be between {MIN_BLOB_BITS} and {MAX_BLOB_BITS}, got {_0}")]
+    BlobZeroCount(u32),
+
+    #[error("maximum blob zero count must be between {min} and {MAX_BLOB_BITS}, got {got}")]
+    BlobZeroCountMax { min: u32, got: u32 },
+
+    #[error("per level count must be between 1 and {MAX_LEVEL_BITS}, got {_0}")]
+    PerLevelCount(u32),
+
+    #[error("maximum per level count must be between {min} and {MAX_LEVEL_BITS}, got {got}")]
+    PerLevelCountMax { min: u32, got: u32 },
+
+    #[error("FastCDC sizes must satisfy 2^{MIN_BLOB_BITS} <= min <= avg <= max <= 2^{MAX_BLOB_BITS}, got {min}, {avg}, {max}")]
+    FastCdcSizes { min: u32, avg: u32, max: u32 },
+
+    #[error("FastCDC average size must be a power of two, got {_0}")]
+    FastCdcAverage(u32),
+
+    #[error("zero run bits must be between {MIN_ZERO_RUN_BITS} and {MAX_ZERO_RUN_BITS}, got {_0}")]
+    ZeroRunBits(u32),
+}
+
+/// Blobs smaller than 64 bytes would be mostly overhead.
+const MIN_BLOB_BITS: u32 = 6;
+
+/// Blobs are held in memory whole, so they're kept under 1 GiB.
+const MAX_BLOB_BITS: u32 = 30;
+
+const MAX_LEVEL_BITS: u32 = 16;
+
+/// Runs shorter than a filesystem block aren't worth it.
+const MIN_ZERO_RUN_BITS: u32 = 12;
+
+/// Zeros are held in memory until they're known not to be a run, so this keeps that small.
+const MAX_ZERO_RUN_BITS: u32 = 24;
+
+/// What `init` uses: 64 KiB.
+pub const DEFAULT_ZERO_RUN_BITS: u32 = 16;
+
+impl ChunkerConfig {
+    /// Checks the parameters are usable, before anything is chunked with them.
+    pub fn validate(&self) -> Result<(), InvalidChunkerConfig> {
+        if !(MIN_BLOB_BITS..=MAX_BLOB_BITS).contains(&self.blob_zero_count) {
+            return Err(InvalidChunkerConfig::BlobZeroCount(self.blob_zero_count));
+        }
+
+        if !(self.blob_zero_count..=MAX_BLOB_BITS).contains(&self.blob_zero_count_max) {
+            return Err(InvalidChunkerConfig::BlobZeroCountMax {
+                min: self.blob_zero_count,
+                got: self.blob_zero_count_max,
+            });
+        }
+
+        if !(1..=MAX_LEVEL_BITS).contains(&self.per_level_count) {
+            return Err(InvalidChunkerConfig::PerLevelCount(self.per_level_count));
+        }
+
+        if !(self.per_level_count..=MAX_LEVEL_BITS).contains(&self.per_level_count_max) {
+            return Err(InvalidChunkerConfig::PerLevelCountMax {
+                min: self.per_level_count,
+                got: self.per_level_count_max,
+            });
+        }
+
+        if let ChunkerAlgorithm::FastCdc {
+            min_size: min,
+            avg_size: avg,
+            max_size: max,
+        } = self.algorithm
+        {
+            if !(1 << MIN_BLOB_BITS <= min && min <= avg && avg <= max && max <= 1 << MAX_BLOB_BITS)
+            {
+                return Err(InvalidChunkerConfig::FastCdcSizes { min, avg, max });
+            }
+
+            if !avg.is_power_of_two() {
+                return Err(InvalidChunkerConfig::FastCdcAverage(avg));
+            }
+        }
+
+        if let Some(bits) = self.zero_run_bits {
+            if !(MIN_ZERO_RUN_BITS..=MAX_ZERO_RUN_BITS).contains(&bits) {
+                return Err(InvalidChunkerConfig::ZeroRunBits(bits));
+            }
+        }
+
+        Ok(())
+    }
+
+    /// The shortest run of zeros stored as a run, if they are at all.
+    pub(crate) fn min_zero_run(&self) -> Option<u64> {
+        self.zero_run_bits.map(|bits| 1 << bits)
+    }
+
+    /// The mask for `gearhash`, matching hashes with `blob_zero_count` leading zeros.
+    pub(crate) fn blob_bitmask(&self) -> u64 {
+        top_bits(self.blob_zero_count)
+    }
+}
+
+/// A mask of the top `bits` bits. The gear hash shifts left, so these depend on the most bytes.
+fn top_bits(bits: u32) -> u64 {
+    !(u64::MAX >> bits)
+}
+
+/// How much harder (and easier) FastCDC makes cuts before (and after) the average size.
+const NORMALIZATION_LEVEL: u32 = 2;
+
+/// Finds where FastCDC would cut the start of `data`, returning the length of the blob and the
+/// hash at the cut, or `None` if `data` ends first. `data` should be at least `max_size` long
+/// unless it's the end of the file.
+///
+/// Nothing before `min_size` is hashed, which is FastCDC's cut-point skipping: there's no point
+/// looking for a cut where one isn't allowed.
+pub(crate) fn fastcdc_cut(
+    data: &[u8],
+    min_size: u32,
+    avg_size: u32,
+    max_size: u32,
+) -> Option<(usize, u64)> {
+    let (min_size, avg_size, max_size) = (min_size as usize, avg_size as usize, max_size as usize);
+
+    if data.len() <= min_size {
+        return None;
+    }
+
+    let bits = avg_size.trailing_zeros();
+    let hard_mask = top_bits(bits + NORMALIZATION_LEVEL);
+    let easy_mask = top_bits(bits.saturating_sub(NORMALIZATION_LEVEL).max(1));
+
+    let limit = data.len().min(max_size);
+    let normal = avg_size.min(limit);
+
+    let mut hasher = gearhash::Hasher::new(&gearhash::DEFAULT_TABLE);
+
+    if min_size < normal {
+        if let Some(n) = hasher.next_match(&data[min_size..normal], hard_mask) {
+            return Some((min_size + n, hasher.get_hash()));
+        }
+    }
+
+    let start = normal.max(min_size);
+
+    if let Some(n) = hasher.next_match(&data[start..limit], easy_mask) {
+        return Some((start + n, hasher.get_hash()));
+    }
+
+    if data.len() >= max_size {
+        // No cut by the maximum, so force one there.
+        return Some((max_size, hasher.get_hash()));
+    }
+
+    None
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    proptest::proptest! {
+        #[test]
+        fn fastcdc_cuts_within_bounds(data: Vec<u8>, min_bits in 6u32..9, extra_bits in 0u32..3) {
+            let min = 1 << min_bits;
+            let avg = min << extra_bits;
+            let max = avg << 2;
+
+            match fastcdc_cut(&data, min, avg, max) {
+                Some((len, _)) => {
+                    assert!(len > min as usize && len <= max as usize);
+                    assert!(len <= data.len());
+                }
+                None => assert!(data.len() < max as usize),
+            }
+        }
+    }
+
+    #[test]
+    fn default_is_valid() {
+        ChunkerConfig::default().validate().unwrap();
+
+        ChunkerConfig {
+            algorithm: ChunkerAlgorithm::fastcdc(),
+            ..ChunkerConfig::default()
+        }
+        .validate()
+        .unwrap();
+    }
+
+    #[test]
+    fn configs_without_an_algorithm_are_gear() {
+        #[derive(serde::Serialize)]
+        struct OldConfig {
+            blob_zero_count: u32,
+            blob_zero_count_max: u32,
+            per_level_count: u32,
+            per_level_count_max: u32,
+        }
+
+        let old = serde_cbor::to_vec(&OldConfig {
+            blob_zero_count: 10,
+            blob_zero_count_max: 12,
+            per_level_count: 7,
+            per_level_count_max: 9,
+        })
+        .unwrap();
+
+        let config: ChunkerConfig = serde_cbor::from_slice(&old).unwrap();
+        assert_eq!(config.algorithm, ChunkerAlgorithm::Gear);
+        assert_eq!(config.blob_zero_count, 10);
+    }
+
+    #[test]
+    fn rejects_bad_configs() {
+        let default = ChunkerConfig::default();
+
+        let bad = [
+            ChunkerConfig {
+                blob_zero_count: 2,
+                ..default
+            },
+            ChunkerConfig {
+                blob_zero_count_max: 12,
+                ..default
+            },
+            ChunkerConfig {
+                blob_zero_count_max: 40,
+                ..default
+            },
+            ChunkerConfig {
+                per_level_count: 0,
+                ..default
+            },
+            ChunkerConfig {
+                per_level_count_max: 6,
+                ..default
+            },
+            ChunkerConfig {
+                algorithm: ChunkerAlgorithm::FastCdc {
+                    min_size: 4096,
+                    avg_size: 2048,
+                    max_size: 65536,
+                },
+                ..default
+            },
+            ChunkerConfig {
+                algorithm: ChunkerAlgorithm::FastCdc {
+                    min_size: 2048,
+                    avg_size: 5000,
+                    max_size: 65536,
+                },
+                ..default
+            },
+        ];
+
+        for config in &bad {
+            assert!(config.validate().is_err(), "{:?} was accepted", config);
+        }
+    }
+}
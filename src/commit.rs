@@ -1,5 +1,5 @@
 use crate::key::TypedKey;
-use std::collections::HashMap;
+use std::collections::{HashMap, HashSet};
 use std::convert::TryInto;
 use thiserror::Error;
 
@@ -7,7 +7,7 @@ use serde::{Deserialize, Serialize};
 
 use crate::{key::Key, DataStore, Object};
 
-use crate::ds::PutObjError;
+use crate::ds::{GetObjError, PutObjError, Resolvable, ResolveError};
 use crate::object::ObjType;
 
 use crate::dir;
@@ -33,15 +33,43 @@ impl CommitAttrs {
     pub fn set_message(&mut self, msg: String) {
         self.message = msg;
     }
+
+    pub fn extra(&self) -> &HashMap<String, serde_cbor::Value> {
+        &self.extra
+    }
+
+    pub fn extra_mut(&mut self) -> &mut HashMap<String, serde_cbor::Value> {
+        &mut self.extra
+    }
+}
+
+impl Resolvable for Commit {
+    const NAME: &'static str = "commit";
+
+    fn peel<DS: DataStore + ?Sized>(
+        _ds: &DS,
+        key: Key,
+        obj: Object,
+    ) -> Result<Option<TypedKey<Self>>, ResolveError> {
+        match obj.objtype() {
+            ObjType::Commit => Ok(Some(key.into())),
+            _ => Ok(None),
+        }
+    }
+}
+
+#[derive(Debug, Error)]
+pub enum FromKeyError {
+    #[error("error getting commit: {_0}")]
+    GetObjError(#[from] GetObjError),
+
+    #[error("error decoding commit: {_0}")]
+    DecodeError(#[from] serde_cbor::error::Error),
 }
 
 impl Commit {
-    pub fn from_key(ds: &impl DataStore, key: TypedKey<Commit>) -> Self {
-        ds.get_obj(key.inner())
-            .unwrap()
-            .into_owned()
-            .try_into()
-            .unwrap()
+    pub fn from_key(ds: &impl DataStore, key: TypedKey<Commit>) -> Result<Self, FromKeyError> {
+        Ok(ds.get_obj(key.inner())?.into_owned().try_into()?)
     }
 
     pub fn parents(&self) -> &[TypedKey<Commit>] {
@@ -65,7 +93,7 @@ impl TryInto<Object> for Commit {
     type Error = serde_cbor::error::Error;
 
     fn try_into(self) -> Result<Object, serde_cbor::error::Error> {
-        let attrs = serde_cbor::to_vec(&self.attrs)?;
+        let attrs = crate::canonical::to_vec(&self.attrs)?;
 
         let mut keys: Vec<Key> = vec![];
 
@@ -87,10 +115,10 @@ impl TryInto<Commit> for Object {
 
         let attrs: CommitAttrs = serde_cbor::value::from_value(item)?;
 
-        let mut owned_keys = self.keys().to_vec();
-
-        let tree = owned_keys.remove(0);
-        let parents = owned_keys;
+        let (tree, parents) = match self.keys() {
+            [tree, parents @ ..] => (*tree, parents.to_vec()),
+            [] => return Err(serde::de::Error::custom("commit has no tree")),
+        };
 
         let mut new_parents = Vec::new();
         for parent in parents {
@@ -135,3 +163,39 @@ pub fn commit_tree<DS: DataStore>(
 
     Ok(ret.into())
 }
+
+#[derive(Debug, Error)]
+pub enum IsAncestorError {
+    #[error("error getting commit: {_0}")]
+    GetObjError(#[from] GetObjError),
+
+    #[error("error decoding commit: {_0}")]
+    DecodeError(#[from] serde_cbor::error::Error),
+}
+
+/// Whether `ancestor` is reachable by following parents from `descendant`. A commit counts as its
+/// own ancestor.
+pub fn is_ancestor<DS: DataStore + ?Sized>(
+    ds: &DS,
+    ancestor: TypedKey<Commit>,
+    descendant: TypedKey<Commit>,
+) -> Result<bool, IsAncestorError> {
+    let mut seen = HashSet::new();
+    let mut to_visit = vec![descendant];
+
+    while let Some(key) = to_visit.pop() {
+        if key == ancestor {
+            return Ok(true);
+        }
+
+        if !seen.insert(key) {
+            continue;
+        }
+
+        let commit: Commit = ds.get_obj(key.inner())?.try_into()?;
+
+        to_visit.extend(commit.parents());
+    }
+
+    Ok(false)
+}
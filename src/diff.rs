@@ -1,9 +1,12 @@
 use crate::key::TypedKey;
 use crate::DataStore;
-use crate::{cache, dir, file, filter};
+use crate::{cache, dir, ds, file, filter};
 use itertools::Itertools;
-use std::collections::{HashMap, HashSet};
-use std::path::PathBuf;
+use std::collections::{BTreeSet, HashMap, HashSet};
+use std::convert::TryInto;
+use std::hash::Hash;
+use std::os::unix::ffi::OsStrExt;
+use std::path::{Path, PathBuf};
 use thiserror::Error;
 
 #[derive(Debug)]
@@ -24,6 +27,12 @@ pub struct ModifiedDiffResult {
     path: PathBuf,
     original_key: TypedKey<dir::FSItem>,
     new_key: TypedKey<dir::FSItem>,
+
+    /// Where it pointed before, if it was a symlink.
+    original_target: Option<PathBuf>,
+
+    /// Where it points now, if it's a symlink.
+    new_target: Option<PathBuf>,
 }
 
 #[derive(Debug, PartialEq, Eq, PartialOrd, Ord, Clone)]
@@ -33,11 +42,24 @@ pub struct AddedDiffResult {
     new_key: Option<TypedKey<dir::FSItem>>,
 }
 
+/// A file whose hard links to other files changed.
+#[derive(Debug, PartialEq, Eq, PartialOrd, Ord, Clone)]
+pub struct RelinkedDiffResult {
+    path: PathBuf,
+
+    /// What it was hard linked to.
+    original_links: Vec<PathBuf>,
+
+    /// What it's hard linked to now.
+    new_links: Vec<PathBuf>,
+}
+
 #[derive(Debug)]
 pub struct DiffResult {
     deleted: Vec<DeletedDiffResult>,
     added: Vec<AddedDiffResult>,
     modified: Vec<ModifiedDiffResult>,
+    relinked: Vec<RelinkedDiffResult>,
 }
 
 #[derive(Debug, Error)]
@@ -50,6 +72,12 @@ pub enum CompareError {
     WalkError(#[from] dir::WalkFsItemsError),
     #[error("error when walking filesystem items: {_0}")]
     RealWalkError(#[from] dir::WalkRealFsItemsError),
+    #[error("a cache is needed to compare against the filesystem")]
+    NoCache,
+    #[error("error getting object: {_0}")]
+    GetObjError(#[from] ds::GetObjError),
+    #[error("error decoding object: {_0}")]
+    DecodeError(#[from] serde_cbor::error::Error),
 }
 
 pub fn compare<'a>(
@@ -62,23 +90,29 @@ pub fn compare<'a>(
     let cache = cache.as_ref();
 
     let from_path;
+    let from_links;
     let from_map = match from {
         DiffTarget::FileSystem(path, filters, folder_path) => {
             let exclude = filter::make_filter_fn(&filters, folder_path);
-            let fs_items = dir::walk_real_fs_items(&path, &exclude)?;
+            let walk = dir::walk_real_fs_items_and_links(&path, &exclude)?;
             from_path = Some(path);
-            either::Left(fs_items)
+            from_links = link_partners(&walk.links);
+            either::Left(walk.items)
         }
         DiffTarget::Database(key) => {
-            let db_items = dir::walk_fs_items(ds, key)?;
+            let walk = dir::walk_fs_items_and_links(ds, key)?;
             from_path = None;
-            either::Right(db_items)
+            from_links = link_partners(&walk.links);
+            either::Right(walk.items)
         }
     };
 
-    let to_map = match to {
-        Some(t) => dir::walk_fs_items(ds, t)?,
-        None => HashMap::new(),
+    let (to_map, to_links) = match to {
+        Some(t) => {
+            let walk = dir::walk_fs_items_and_links(ds, t)?;
+            (walk.items, link_partners(&walk.links))
+        }
+        None => (HashMap::new(), HashMap::new()),
     };
 
     let from_keys: HashSet<PathBuf> = from_map.clone().either(
@@ -88,39 +122,43 @@ pub fn compare<'a>(
 
     let to_keys: HashSet<PathBuf> = to_map.keys().cloned().collect();
 
-    let mut in_from_only: Vec<AddedDiffResult> = from_keys
-        .difference(&to_keys)
-        .map(|x| AddedDiffResult {
+    let mut in_from_only = Vec::new();
+
+    for x in from_keys.difference(&to_keys) {
+        let (new_key, is_dir) = match &from_map {
+            either::Left(fs_items) => {
+                if fs_items[x] {
+                    // directories don't have a hash
+                    (None, true)
+                } else {
+                    let h = dir::hash_fs_item(
+                        ds,
+                        &from_path
+                            .as_ref()
+                            .expect("should have been populated")
+                            .join(x),
+                        *cache.ok_or(CompareError::NoCache)?,
+                        false,
+                    )?;
+                    (Some(h.into()), false)
+                }
+            }
+            either::Right(db_items) => (Some(db_items[x].0), db_items[x].1),
+        };
+
+        in_from_only.push(AddedDiffResult {
             path: x.clone(),
-            new_key: from_map.as_ref().either(
-                |y| {
-                    if !y[x] {
-                        // this is a file
-                        match dir::hash_fs_item(
-                            ds,
-                            x,
-                            *cache.expect("you must pass a cache if you're hashing the fs"),
-                        ) {
-                            Ok(h) => Some(h.into()),
-                            Err(e) => panic!(e),
-                        }
-                    } else {
-                        // directories don't have a hash
-                        None
-                    }
-                },
-                |y| Some(y[x].0),
-            ),
-            is_dir: from_map.as_ref().either(|y| y[x], |y| y[x].1),
-        })
-        .collect();
+            new_key,
+            is_dir,
+        });
+    }
 
     let mut in_to_only: Vec<DeletedDiffResult> = to_keys
         .difference(&from_keys)
         .map(|x| DeletedDiffResult {
             path: x.clone(),
             original_key: Some(to_map[x].0),
-            is_dir: from_map.as_ref().either(|y| y[x], |y| y[x].1),
+            is_dir: to_map[x].1,
         })
         .collect();
 
@@ -129,6 +167,8 @@ pub fn compare<'a>(
     let mut modified = Vec::new();
 
     for path in in_both {
+        let t = to_map[path];
+
         let f;
         match &from_map {
             either::Left(fs_items) => {
@@ -136,32 +176,59 @@ pub fn compare<'a>(
                     continue;
                 }
 
+                // Extended attributes are only looked at if they were stored, so leaving them out
+                // doesn't make everything look modified.
+                let stored: dir::FSItem = ds.get_obj(t.0.inner())?.try_into()?;
+
                 f = dir::hash_fs_item(
                     ds,
                     &from_path
                         .as_ref()
                         .expect("should have been populated")
                         .join(path),
-                    *cache.expect("you must pass a cache if you're hashing the fs"),
+                    *cache.ok_or(CompareError::NoCache)?,
+                    stored.xattrs().is_some(),
                 )?
                 .into();
             }
             either::Right(db_items) => f = db_items[path].0,
         }
 
-        let t = to_map[path];
-
         if f != t.0 {
             let dr = ModifiedDiffResult {
                 original_key: t.0,
                 new_key: f,
                 path: path.clone(),
+                original_target: symlink_target(ds, t.0)?,
+                new_target: symlink_target(ds, f)?,
             };
 
             modified.push(dr);
         }
     }
 
+    // Only for files on both sides, as anything else is already added or deleted.
+    let mut relinked = Vec::new();
+
+    let linked: BTreeSet<&PathBuf> = from_links.keys().chain(to_links.keys()).collect();
+
+    for path in linked {
+        if !from_keys.contains(path) || !to_keys.contains(path) {
+            continue;
+        }
+
+        let original_links = to_links.get(path).cloned().unwrap_or_default();
+        let new_links = from_links.get(path).cloned().unwrap_or_default();
+
+        if original_links != new_links {
+            relinked.push(RelinkedDiffResult {
+                path: path.clone(),
+                original_links: original_links.into_iter().collect(),
+                new_links: new_links.into_iter().collect(),
+            });
+        }
+    }
+
     in_from_only.sort_unstable();
     in_to_only.sort_unstable();
     modified.sort_unstable();
@@ -170,9 +237,65 @@ pub fn compare<'a>(
         deleted: in_to_only,
         added: in_from_only,
         modified,
+        relinked,
     })
 }
 
+/// The other paths each path is hard linked to, from the group each is in.
+fn link_partners<G: Hash + Eq>(links: &dir::PathMap<G>) -> HashMap<PathBuf, BTreeSet<PathBuf>> {
+    let mut groups: HashMap<&G, Vec<&PathBuf>> = HashMap::new();
+
+    for (path, group) in links {
+        groups.entry(group).or_default().push(path);
+    }
+
+    let mut partners = HashMap::new();
+
+    for paths in groups.values().filter(|paths| paths.len() > 1) {
+        for &path in paths {
+            let others = paths
+                .iter()
+                .filter(|&&other| other != path)
+                .map(|&other| other.clone())
+                .collect();
+
+            partners.insert(path.clone(), others);
+        }
+    }
+
+    partners
+}
+
+fn symlink_target(
+    ds: &impl DataStore,
+    key: TypedKey<dir::FSItem>,
+) -> Result<Option<PathBuf>, CompareError> {
+    let item: dir::FSItem = ds.get_obj(key.inner())?.try_into()?;
+
+    Ok(item.symlink_target().map(Path::to_path_buf))
+}
+
+/// What to diff for an item: a file's data, where a symlink points, or what a special file is.
+fn item_data(
+    ds: &impl DataStore,
+    key: TypedKey<dir::FSItem>,
+) -> Result<Vec<u8>, file::ReadDataError> {
+    let item: dir::FSItem = ds.get_obj(key.inner())?.try_into()?;
+
+    if let Some(special) = item.special() {
+        return Ok(special.to_string().into_bytes());
+    }
+
+    match item.symlink_target() {
+        Some(target) => Ok(target.as_os_str().as_bytes().to_vec()),
+        None => {
+            let mut data = Vec::new();
+            file::read_data(ds, key.into(), &mut data)?;
+            Ok(data)
+        }
+    }
+}
+
 pub fn simplify(r: DiffResult) -> DiffResult {
     let mut deleted = Vec::new();
     let mut added = Vec::new();
@@ -203,6 +326,7 @@ pub fn simplify(r: DiffResult) -> DiffResult {
         added,
         modified: r.modified,
         deleted,
+        relinked: r.relinked,
     }
 
     // Directories can't be modified, so we don't need to simplify here
@@ -235,15 +359,58 @@ pub fn print_diff_result(r: DiffResult) {
     if !r.modified.is_empty() {
         println!("{}", "modified:".blue());
         for p in r.modified {
-            println!("  {}", p.path.display());
+            match (p.original_target, p.new_target) {
+                (None, None) => println!("  {}", p.path.display()),
+                (Some(from), Some(to)) => println!(
+                    "  {} -> {} (was {})",
+                    p.path.display(),
+                    to.display(),
+                    from.display()
+                ),
+                (None, Some(to)) => {
+                    println!("  {} -> {} (was a file)", p.path.display(), to.display())
+                }
+                (Some(from), None) => println!(
+                    "  {} (was a symlink to {})",
+                    p.path.display(),
+                    from.display()
+                ),
+            }
+        }
+    }
+
+    if !r.relinked.is_empty() {
+        println!("{}", "relinked:".yellow());
+        for p in r.relinked {
+            let list = |paths: &[PathBuf]| paths.iter().map(|x| x.display()).join(", ");
+
+            match (p.original_links.is_empty(), p.new_links.is_empty()) {
+                (true, _) => println!("  {} => {}", p.path.display(), list(&p.new_links)),
+                (false, true) => println!(
+                    "  {} (was linked to {})",
+                    p.path.display(),
+                    list(&p.original_links)
+                ),
+                (false, false) => println!(
+                    "  {} => {} (was {})",
+                    p.path.display(),
+                    list(&p.new_links),
+                    list(&p.original_links)
+                ),
+            }
         }
     }
 }
 
-pub fn print_stat_diff_result(ds: &impl DataStore, r: DiffResult) {
-    let stat = line_stat(ds, r);
+pub fn print_stat_diff_result(
+    ds: &impl DataStore,
+    r: DiffResult,
+) -> Result<(), file::ReadDataError> {
+    let stat = line_stat(ds, r)?;
 
     print_line_stat(stat);
+
+    Ok(())
 }
 
 pub fn print_patch_diff_result(ds: &impl DataStore, r: DiffResult) -> Result<(), DiffPatchError> {
@@ -264,7 +431,10 @@ pub struct FileStatResult {
     removed: usize,
 }
 
-pub fn line_stat(ds: &impl DataStore, r: DiffResult) -> LineStatResult {
+pub fn line_stat(
+    ds: &impl DataStore,
+    r: DiffResult,
+) -> Result<LineStatResult, file::ReadDataError> {
     ldbg!(&r);
 
     let mut items = Vec::new();
@@ -273,7 +443,7 @@ pub fn line_stat(ds: &impl DataStore, r: DiffResult) -> LineStatResult {
         if let Some(k) = added.new_key {
             items.push(FileStatResult {
                 fname: added.path,
-                added: line_ct(ds, k),
+                added: line_ct(ds, k)?,
                 removed: 0,
             });
         }
@@ -284,17 +454,14 @@ pub fn line_stat(ds: &impl DataStore, r: DiffResult) -> LineStatResult {
             items.push(FileStatResult {
                 fname: removed.path,
                 added: 0,
-                removed: line_ct(ds, k),
+                removed: line_ct(ds, k)?,
             });
         }
     }
 
     for modified in r.modified {
-        let mut before = Vec::new();
-        file::read_data(ds, modified.original_key.into(), &mut before).unwrap();
-
-        let mut after = Vec::new();
-        file::read_data(ds, modified.new_key.into(), &mut after).unwrap();
+        let before = item_data(ds, modified.original_key)?;
+        let after = item_data(ds, modified.new_key)?;
 
         let before_str = String::from_utf8_lossy(&before);
         let after_str = String::from_utf8_lossy(&after);
@@ -318,7 +485,7 @@ pub fn line_stat(ds: &impl DataStore, r: DiffResult) -> LineStatResult {
         });
     }
 
-    LineStatResult { items }
+    Ok(LineStatResult { items })
 }
 
 pub fn print_line_stat(mut lsr: LineStatResult) {
@@ -334,17 +501,19 @@ pub fn print_line_stat(mut lsr: LineStatResult) {
     }
 }
 
-pub fn line_ct(ds: &impl DataStore, key: TypedKey<dir::FSItem>) -> usize {
-    let mut data = Vec::new();
-    file::read_data(ds, key.into(), &mut data).unwrap();
+pub fn line_ct(
+    ds: &impl DataStore,
+    key: TypedKey<dir::FSItem>,
+) -> Result<usize, file::ReadDataError> {
+    let data = item_data(ds, key)?;
 
     #[allow(clippy::naive_bytecount)]
     // This whole function will be cached in the store at some point, this is just for testing
-    data.iter().filter(|x| **x == b'\n').count()
+    Ok(data.iter().filter(|x| **x == b'\n').count())
 }
 
 pub fn diff_result_empty(r: &DiffResult) -> bool {
-    r.added.is_empty() && r.deleted.is_empty() && r.modified.is_empty()
+    r.added.is_empty() && r.deleted.is_empty() && r.modified.is_empty() && r.relinked.is_empty()
 }
 
 pub fn format_patch(p: &patch::Patch<'_>) -> String {
@@ -358,6 +527,9 @@ const AFTER_CONTEXT_LINES: usize = 5;
 pub enum DiffPatchError {
     #[error("tried to make patch with non-UTF8 files")]
     Binary,
+
+    #[error("error reading file data: {_0}")]
+    ReadDataError(#[from] file::ReadDataError),
 }
 
 pub fn create_diff_patch_result(
@@ -382,8 +554,7 @@ pub fn create_diff_patch_result(
 
             let mut hunks = Vec::new();
 
-            let mut data = Vec::new();
-            file::read_data(ds, k.into(), &mut data).unwrap();
+            let data = item_data(ds, k)?;
 
             let data = std::str::from_utf8(&data);
 
@@ -435,8 +606,7 @@ pub fn create_diff_patch_result(
             let new = patch::File { path, meta }; // For now we're just saying old == new
 
             let mut hunks = Vec::new();
-            let mut data = Vec::new();
-            file::read_data(ds, k.into(), &mut data).unwrap();
+            let data = item_data(ds, k)?;
 
             let data = std::str::from_utf8(&data);
 
@@ -476,14 +646,10 @@ pub fn create_diff_patch_result(
     }
 
     for modified in r.modified {
-        let mut before = Vec::new();
-
         ldbg!(&modified);
 
-        file::read_data(ds, modified.original_key.into(), &mut before).unwrap();
-
-        let mut after = Vec::new();
-        file::read_data(ds, modified.new_key.into(), &mut after).unwrap();
+        let before = item_data(ds, modified.original_key)?;
+        let after = item_data(ds, modified.new_key)?;
 
         let before_str = String::from_utf8_lossy(&before);
         let after_str = String::from_utf8_lossy(&after);
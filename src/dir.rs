@@ -1,18 +1,43 @@
+use crate::ds::{Resolvable, ResolveError};
 use crate::object::ObjType;
-use crate::{cache, ds};
+use crate::{cache, commit, ds};
 use crate::{
     cache::Cache,
     cache::CacheKey,
     file,
-    key::{Key, TypedKey},
+    key::{HashAlgorithm, Key, TypedKey},
+    metadata::{RestoreMetadata, UnixMetadata},
+    xattr::XattrSet,
     DataStore, Object,
 };
+use std::collections::hash_map::Entry;
 use std::collections::{HashMap, HashSet};
 use std::convert::TryInto;
+use std::ffi::{CString, OsStr};
 use std::fs::DirEntry;
+use std::os::unix::ffi::{OsStrExt, OsStringExt};
+use std::os::unix::fs::{FileTypeExt, MetadataExt};
 use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, Ordering};
 use thiserror::Error;
 
+/// The newest revision of the `FSItem` format this version reads. Revision 0 had no metadata,
+/// revision 1 no symlinks, revision 2 no extended attributes, and revision 3 no FIFOs, sockets
+/// or device nodes.
+const FSITEM_REVISION: u32 = 4;
+
+/// The revision items are written with unless they need a newer one, so they keep their keys.
+const METADATA_REVISION: u32 = 1;
+
+/// The revision of symlinks, which older versions would take for files.
+const SYMLINK_REVISION: u32 = 2;
+
+/// The revision of items with extended attributes.
+const XATTRS_REVISION: u32 = 3;
+
+/// The revision of FIFOs, sockets and device nodes, which older versions don't know.
+const SPECIAL_REVISION: u32 = 4;
+
 #[derive(Debug, serde::Serialize, serde::Deserialize)]
 pub struct FSItem {
     size: u64,
@@ -20,32 +45,296 @@ pub struct FSItem {
     children_names: Vec<PathBuf>,
     #[serde(skip)]
     children: Vec<TypedKey<FSItem>>,
+
+    // Left out when empty, so revision 0 items keep their keys.
+    #[serde(default, skip_serializing_if = "is_zero")]
+    revision: u32,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    metadata: Option<UnixMetadata>,
+
+    /// What a symlink points to, byte for byte.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    target: Option<serde_bytes::ByteBuf>,
+
+    /// Whether the last key is `xattrs`, rather than a child.
+    #[serde(default, skip_serializing_if = "is_false")]
+    has_xattrs: bool,
+    #[serde(skip)]
+    xattrs: Option<TypedKey<XattrSet>>,
+
+    /// For each child of a directory, which group of hard links it's in, if it's a file with
+    /// other links in the same tree. Left out when no child has any.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    link_groups: Vec<Option<LinkGroup>>,
+
+    /// Which device a device node is for.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    device: Option<DeviceNumber>,
+}
+
+fn is_zero(n: &u32) -> bool {
+    *n == 0
+}
+
+fn is_false(b: &bool) -> bool {
+    !*b
 }
 
 #[derive(Debug, serde::Serialize, serde::Deserialize)]
 enum FSItemType {
     Dir,
     File,
+    Symlink,
+    Fifo,
+    Socket,
+    CharDevice,
+    BlockDevice,
+}
+
+/// A device's major and minor numbers.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, serde::Serialize, serde::Deserialize)]
+pub struct DeviceNumber {
+    pub major: u32,
+    pub minor: u32,
+}
+
+impl DeviceNumber {
+    fn from_dev(dev: libc::dev_t) -> Self {
+        // SAFETY: these only do arithmetic.
+        unsafe {
+            Self {
+                major: libc::major(dev),
+                minor: libc::minor(dev),
+            }
+        }
+    }
+
+    fn to_dev(self) -> libc::dev_t {
+        // SAFETY: as in from_dev.
+        unsafe { libc::makedev(self.major, self.minor) }
+    }
+}
+
+/// A FIFO, socket or device node. Sockets are recorded, but can't be restored.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SpecialFile {
+    Fifo,
+    Socket,
+    CharDevice(DeviceNumber),
+    BlockDevice(DeviceNumber),
+}
+
+impl SpecialFile {
+    /// What `meta` is, if it's one of these.
+    fn from_metadata(meta: &std::fs::Metadata) -> Option<Self> {
+        let file_type = meta.file_type();
+
+        if file_type.is_fifo() {
+            Some(SpecialFile::Fifo)
+        } else if file_type.is_socket() {
+            Some(SpecialFile::Socket)
+        } else if file_type.is_char_device() {
+            Some(SpecialFile::CharDevice(DeviceNumber::from_dev(meta.rdev())))
+        } else if file_type.is_block_device() {
+            Some(SpecialFile::BlockDevice(DeviceNumber::from_dev(
+                meta.rdev(),
+            )))
+        } else {
+            None
+        }
+    }
+}
+
+impl std::fmt::Display for SpecialFile {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            SpecialFile::Fifo => write!(f, "fifo"),
+            SpecialFile::Socket => write!(f, "socket"),
+            SpecialFile::CharDevice(dev) => {
+                write!(f, "character device {}:{}", dev.major, dev.minor)
+            }
+            SpecialFile::BlockDevice(dev) => write!(f, "block device {}:{}", dev.major, dev.minor),
+        }
+    }
+}
+
+/// Which group of hard links a file is in. Groups are numbered within the lowest directory that
+/// has all their members under it, `up` levels above the file's, in the order of their first
+/// members. A directory's key then only depends on the links under it, and those reaching out.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, serde::Serialize, serde::Deserialize)]
+pub struct LinkGroup {
+    pub up: u32,
+    pub index: u64,
+}
+
+/// A [`LinkGroup`] named by the directory it's numbered in, as [`LinkGroup::scoped`] gives.
+pub type ScopedLinkGroup = (PathBuf, u32, u64);
+
+impl LinkGroup {
+    /// Names this group uniquely among the others in a walk, for the file at `path`. Groups that
+    /// reach past the top of `path` are named by how much further up they go.
+    pub fn scoped(self, path: &Path) -> ScopedLinkGroup {
+        let mut dir = path.parent().unwrap_or(path);
+        let mut up = self.up;
+
+        while up > 0 {
+            match dir.parent() {
+                Some(parent) => dir = parent,
+                None => break,
+            }
+
+            up -= 1;
+        }
+
+        (dir.to_path_buf(), up, self.index)
+    }
+}
+
+impl FSItem {
+    fn symlink(target: PathBuf, meta: &std::fs::Metadata) -> Self {
+        let target = target.into_os_string().into_vec();
+
+        FSItem {
+            children: vec![],
+            children_names: vec![],
+            itemtype: FSItemType::Symlink,
+            size: target.len() as u64,
+            revision: SYMLINK_REVISION,
+            metadata: Some(UnixMetadata::from_fs(meta)),
+            target: Some(serde_bytes::ByteBuf::from(target)),
+            has_xattrs: false,
+            xattrs: None,
+            link_groups: vec![],
+            device: None,
+        }
+    }
+
+    fn from_special(special: SpecialFile, meta: &std::fs::Metadata) -> Self {
+        let (itemtype, device) = match special {
+            SpecialFile::Fifo => (FSItemType::Fifo, None),
+            SpecialFile::Socket => (FSItemType::Socket, None),
+            SpecialFile::CharDevice(dev) => (FSItemType::CharDevice, Some(dev)),
+            SpecialFile::BlockDevice(dev) => (FSItemType::BlockDevice, Some(dev)),
+        };
+
+        FSItem {
+            children: vec![],
+            children_names: vec![],
+            itemtype,
+            size: 0,
+            revision: SPECIAL_REVISION,
+            metadata: Some(UnixMetadata::from_fs(meta)),
+            target: None,
+            has_xattrs: false,
+            xattrs: None,
+            link_groups: vec![],
+            device,
+        }
+    }
+
+    fn with_xattrs(self, xattrs: Option<Key>) -> Self {
+        match xattrs {
+            Some(key) => Self {
+                revision: self.revision.max(XATTRS_REVISION),
+                has_xattrs: true,
+                xattrs: Some(key.into()),
+                ..self
+            },
+            None => self,
+        }
+    }
+
+    pub fn is_dir(&self) -> bool {
+        matches!(self.itemtype, FSItemType::Dir)
+    }
+
+    pub fn is_symlink(&self) -> bool {
+        matches!(self.itemtype, FSItemType::Symlink)
+    }
+
+    /// Whether a directory's children are in order of the bytes of their names, as they're
+    /// stored now. Directories stored by older versions might not be.
+    pub fn children_sorted(&self) -> bool {
+        self.children_names
+            .windows(2)
+            .all(|pair| pair[0].as_os_str().as_bytes() < pair[1].as_os_str().as_bytes())
+    }
+
+    /// What a FIFO, socket or device node is, or `None` for anything else.
+    pub fn special(&self) -> Option<SpecialFile> {
+        match self.itemtype {
+            FSItemType::Fifo => Some(SpecialFile::Fifo),
+            FSItemType::Socket => Some(SpecialFile::Socket),
+            FSItemType::CharDevice => self.device.map(SpecialFile::CharDevice),
+            FSItemType::BlockDevice => self.device.map(SpecialFile::BlockDevice),
+            FSItemType::Dir | FSItemType::File | FSItemType::Symlink => None,
+        }
+    }
+
+    /// What a symlink points to.
+    pub fn symlink_target(&self) -> Option<&Path> {
+        self.target
+            .as_ref()
+            .map(|target| Path::new(OsStr::from_bytes(target)))
+    }
+
+    /// The length in bytes for files and symlink targets, the number of children for
+    /// directories, and 0 for anything else.
+    pub fn size(&self) -> u64 {
+        self.size
+    }
+
+    /// The key of a file's data, which [`read_data`](crate::file::read_data) reads.
+    pub fn data_key(&self) -> Option<Key> {
+        match self.itemtype {
+            FSItemType::File => self.children.first().map(TypedKey::inner),
+            _ => None,
+        }
+    }
+
+    /// Permissions, times and ownership, for items stored since they were recorded.
+    pub fn metadata(&self) -> Option<&UnixMetadata> {
+        self.metadata.as_ref()
+    }
+
+    /// The key of the item's extended attributes, if they were stored and there were any.
+    pub fn xattrs(&self) -> Option<TypedKey<XattrSet>> {
+        self.xattrs
+    }
+
+    /// The name and key of each child of a directory.
+    pub fn children(&self) -> impl Iterator<Item = (&Path, TypedKey<FSItem>)> {
+        self.children_names
+            .iter()
+            .map(PathBuf::as_path)
+            .zip(self.children.iter().copied())
+    }
 }
 
 impl TryInto<Object> for FSItem {
     type Error = serde_cbor::error::Error;
 
     fn try_into(self) -> Result<Object, serde_cbor::error::Error> {
-        let value = serde_cbor::value::to_value(&self)?;
-
-        let obj = serde_cbor::to_vec(&value)?;
+        let obj = crate::canonical::to_vec(&self)?;
 
         let objtype = match self.itemtype {
             FSItemType::Dir => ObjType::FSItemDir,
             FSItemType::File => ObjType::FSItemFile,
+            FSItemType::Symlink => ObjType::FSItemSymlink,
+            FSItemType::Fifo
+            | FSItemType::Socket
+            | FSItemType::CharDevice
+            | FSItemType::BlockDevice => ObjType::FSItemSpecial,
         };
 
-        Ok(Object::new_owned(
-            obj,
-            self.children.iter().map(|&x| x.into()).collect(),
-            objtype,
-        ))
+        let keys = self
+            .children
+            .iter()
+            .map(|&x| x.into())
+            .chain(self.xattrs.map(|key| key.inner()))
+            .collect();
+
+        Ok(Object::new_owned(obj, keys, objtype))
     }
 }
 
@@ -59,10 +348,127 @@ impl TryInto<FSItem> for Object {
 
         fsitem.children = self.keys().iter().map(|&x| x.into()).collect();
 
+        if fsitem.has_xattrs {
+            match fsitem.children.pop() {
+                Some(key) => fsitem.xattrs = Some(key.inner().into()),
+                None => {
+                    return Err(serde::de::Error::custom(
+                        "item with extended attributes has no keys",
+                    ))
+                }
+            }
+        }
+
+        if fsitem.revision > FSITEM_REVISION {
+            return Err(serde::de::Error::custom(format!(
+                "tree revision {} is from a newer version",
+                fsitem.revision
+            )));
+        }
+
+        match fsitem.itemtype {
+            FSItemType::File if fsitem.children.len() != 1 => {
+                return Err(serde::de::Error::custom(
+                    "file should have exactly one child",
+                ));
+            }
+            FSItemType::Dir if fsitem.children.len() != fsitem.children_names.len() => {
+                return Err(serde::de::Error::custom(
+                    "directory has a different number of names and children",
+                ));
+            }
+            FSItemType::Symlink if !fsitem.children.is_empty() || fsitem.target.is_none() => {
+                return Err(serde::de::Error::custom(
+                    "symlink should have a target and no children",
+                ));
+            }
+            FSItemType::Fifo
+            | FSItemType::Socket
+            | FSItemType::CharDevice
+            | FSItemType::BlockDevice
+                if !fsitem.children.is_empty() =>
+            {
+                return Err(serde::de::Error::custom(
+                    "fifos, sockets and devices have no children",
+                ));
+            }
+            _ => {}
+        }
+
+        let is_device = matches!(
+            fsitem.itemtype,
+            FSItemType::CharDevice | FSItemType::BlockDevice
+        );
+
+        if fsitem.device.is_some() != is_device {
+            return Err(serde::de::Error::custom(
+                "device nodes, and only device nodes, have device numbers",
+            ));
+        }
+
+        if fsitem.target.is_some() && !fsitem.is_symlink() {
+            return Err(serde::de::Error::custom("only symlinks have targets"));
+        }
+
+        if !fsitem.link_groups.is_empty()
+            && (!fsitem.is_dir() || fsitem.link_groups.len() != fsitem.children.len())
+        {
+            return Err(serde::de::Error::custom(
+                "link groups don't match the directory's children",
+            ));
+        }
+
+        // Names are joined onto the restore path, so one like `..` could point anywhere.
+        for name in &fsitem.children_names {
+            let mut components = name.components();
+
+            if !matches!(
+                (components.next(), components.next()),
+                (Some(std::path::Component::Normal(_)), None)
+            ) {
+                return Err(serde::de::Error::custom(format!(
+                    "{:?} is not a valid directory entry name",
+                    name
+                )));
+            }
+        }
+
+        let mut seen = HashSet::new();
+        for name in &fsitem.children_names {
+            if !seen.insert(name) {
+                return Err(serde::de::Error::custom(format!(
+                    "{:?} appears twice in one directory",
+                    name
+                )));
+            }
+        }
+
         Ok(fsitem)
     }
 }
 
+impl Resolvable for FSItem {
+    const NAME: &'static str = "tree";
+
+    fn peel<DS: DataStore + ?Sized>(
+        _ds: &DS,
+        key: Key,
+        obj: Object,
+    ) -> Result<Option<TypedKey<Self>>, ResolveError> {
+        match obj.objtype() {
+            ObjType::FSItemDir
+            | ObjType::FSItemFile
+            | ObjType::FSItemSymlink
+            | ObjType::FSItemSpecial => Ok(Some(key.into())),
+            ObjType::Commit => {
+                let commit: commit::Commit = obj.try_into()?;
+                Ok(Some(commit.tree()))
+            }
+            _ => Ok(None),
+        }
+    }
+}
+
 #[derive(Debug, Error)]
 pub enum PutFsItemError {
     #[error("io error: {_0}")]
@@ -76,67 +482,526 @@ pub enum PutFsItemError {
 
     #[error("serialisation error")]
     SerialisationError(#[from] serde_cbor::error::Error),
+
+    #[error("error getting chunker config: {_0}")]
+    ChunkerConfigError(#[from] ds::ChunkerConfigError),
+
+    #[error("error getting hash algorithm: {_0}")]
+    HashAlgorithmError(#[from] ds::HashAlgorithmError),
+
+    #[error("cache error: {_0}")]
+    CacheError(#[from] cache::GetCacheError),
+
+    #[error("error checking for a stored object: {_0}")]
+    RawExistsError(#[from] ds::RawExistsError),
+
+    #[error("error committing a checkpoint: {_0}")]
+    CommitTransError(#[from] ds::CommitTransError),
+
+    #[error("error starting a transaction after a checkpoint: {_0}")]
+    BeginTransError(#[from] ds::BeginTransError),
+
+    #[error("interrupted; what was stored so far was kept, and running again picks up from there")]
+    Cancelled,
+
+    #[error("{} contains itself through a symlink", _0.display())]
+    SymlinkLoop(PathBuf),
+
+    #[error("{} is a kind of file that can't be stored", _0.display())]
+    UnsupportedFileType(PathBuf),
+
+    #[error("error storing extended attributes: {_0}")]
+    PutXattrsError(#[from] PutXattrsError),
+}
+
+/// How much file data [`put_fs_item_checkpointed`] stores between commits, unless told otherwise.
+pub const DEFAULT_CHECKPOINT_BYTES: u64 = 256 << 20;
+
+/// When [`put_fs_item_checkpointed`] commits, and whether it should stop.
+#[derive(Debug)]
+pub struct Checkpoints<'a> {
+    every: u64,
+    pending: u64,
+    cancel: &'a AtomicBool,
+}
+
+impl<'a> Checkpoints<'a> {
+    /// Commits after every `every` bytes of file data, and as soon as `cancel` is set, before
+    /// stopping.
+    pub fn new(every: u64, cancel: &'a AtomicBool) -> Self {
+        Self {
+            every,
+            pending: 0,
+            cancel,
+        }
+    }
+}
+
+/// What [`put_fs_item`]'s walk does around each file.
+trait PutHooks<DS: DataStore> {
+    /// The key already stored for a file, if it doesn't need reading again.
+    fn cached(&mut self, ds: &DS, meta: &std::fs::Metadata) -> Result<Option<Key>, PutFsItemError>;
+
+    /// Called once a file has been stored, or found in the cache.
+    fn stored(
+        &mut self,
+        ds: &mut DS,
+        meta: &std::fs::Metadata,
+        key: Key,
+        read: bool,
+    ) -> Result<(), PutFsItemError>;
+}
+
+impl<DS: DataStore> PutHooks<DS> for () {
+    fn cached(&mut self, _: &DS, _: &std::fs::Metadata) -> Result<Option<Key>, PutFsItemError> {
+        Ok(None)
+    }
+
+    fn stored(
+        &mut self,
+        _: &mut DS,
+        _: &std::fs::Metadata,
+        _: Key,
+        _: bool,
+    ) -> Result<(), PutFsItemError> {
+        Ok(())
+    }
+}
+
+struct Checkpointing<'a, 'b, C> {
+    cache: &'a mut C,
+    checkpoints: &'a mut Checkpoints<'b>,
+    algorithm: HashAlgorithm,
+    xattrs: bool,
+}
+
+impl<C> Checkpointing<'_, '_, C> {
+    fn cache_key(&self, meta: &std::fs::Metadata) -> CacheKey {
+        CacheKey {
+            xattrs: self.xattrs,
+            ..CacheKey::from_metadata(meta)
+        }
+    }
 }
 
+impl<'a, 'b, DS: DataStore, C: Cache> PutHooks<DS> for Checkpointing<'a, 'b, C> {
+    fn cached(&mut self, ds: &DS, meta: &std::fs::Metadata) -> Result<Option<Key>, PutFsItemError> {
+        match self.cache.get(self.cache_key(meta))? {
+            // The cache is shared between repositories, so the file might not be in this one.
+            Some(key)
+                if key.algorithm() == self.algorithm && ds.raw_exists(&key.as_db_key())? =>
+            {
+                Ok(Some(key))
+            }
+            _ => Ok(None),
+        }
+    }
+
+    fn stored(
+        &mut self,
+        ds: &mut DS,
+        meta: &std::fs::Metadata,
+        key: Key,
+        read: bool,
+    ) -> Result<(), PutFsItemError> {
+        if read {
+            let cache_key = self.cache_key(meta);
+
+            if let Err(e) = self.cache.put(cache_key, key) {
+                log::warn!(
+                    "Error {:?} putting cache entry {:?} as {}",
+                    e,
+                    cache_key,
+                    key
+                );
+            }
+
+            self.checkpoints.pending += meta.len();
+        }
+
+        let cancelled = self.checkpoints.cancel.load(Ordering::Relaxed);
+
+        if cancelled || self.checkpoints.pending >= self.checkpoints.every {
+            // The store first, so the cache never points at objects that aren't there.
+            ds.commit()?;
+            self.cache.commit()?;
+
+            log::debug!(
+                "checkpoint after {} bytes of file data",
+                self.checkpoints.pending
+            );
+            self.checkpoints.pending = 0;
+
+            ds.begin_trans()?;
+            self.cache.begin_trans()?;
+
+            if cancelled {
+                return Err(PutFsItemError::Cancelled);
+            }
+        }
+
+        Ok(())
+    }
+}
+
+/// What [`put_fs_item_checkpointed`] stores besides what [`put_fs_item`] does.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct PutOptions {
+    /// Store what symlinks point to in their place, except for links to nothing.
+    pub follow_symlinks: bool,
+
+    /// Store extended attributes, including POSIX ACLs.
+    pub xattrs: bool,
+}
+
+/// Stores `path`, and everything under it that `filter` lets through. Symlinks under `path` are
+/// stored as links, but `path` itself is followed.
 pub fn put_fs_item<DS: DataStore>(
     ds: &mut DS,
     path: &Path,
     filter: &dyn Fn(&DirEntry) -> bool,
 ) -> Result<Key, PutFsItemError> {
-    let meta = std::fs::metadata(path)?;
+    put_fs_item_with(ds, path, filter, PutOptions::default(), &mut ())
+}
 
+/// Like [`put_fs_item`], but commits `ds` and `cache` every so often, so stopping part way
+/// through keeps what was stored. It stops with [`PutFsItemError::Cancelled`] once `checkpoints`
+/// says to, after committing.
+///
+/// Both `ds` and `cache` must be in a transaction, and are left in one.
+/// Files are looked up in `cache` first, and aren't read again if they're already stored.
+pub fn put_fs_item_checkpointed<DS: DataStore, C: Cache>(
+    ds: &mut DS,
+    path: &Path,
+    filter: &dyn Fn(&DirEntry) -> bool,
+    options: PutOptions,
+    cache: &mut C,
+    checkpoints: &mut Checkpoints<'_>,
+) -> Result<Key, PutFsItemError> {
+    let mut hooks = Checkpointing {
+        algorithm: ds.hash_algorithm()?,
+        cache,
+        checkpoints,
+        xattrs: options.xattrs,
+    };
+
+    put_fs_item_with(ds, path, filter, options, &mut hooks)
+}
+
+/// The parts of a [`put_fs_item`] walk that stay the same all the way down.
+struct PutWalk<'a, DS> {
+    filter: &'a dyn Fn(&DirEntry) -> bool,
+    options: PutOptions,
+    hooks: &'a mut dyn PutHooks<DS>,
+
+    /// The device and inode of each directory being stored, to stop at symlink loops.
+    ancestors: Vec<(u64, u64)>,
+
+    /// The paths of each file with more than one link found so far, by device and inode, in the
+    /// order they were found.
+    linked: HashMap<(u64, u64), Vec<PathBuf>>,
+
+    /// The key of each of those, so the other links to it aren't read again.
+    links: HashMap<(u64, u64), Key>,
+}
+
+/// What storing something in a [`put_fs_item`] walk gave.
+enum PutEntry {
+    Stored(Key),
+
+    /// A directory with files under it that have other links, which can't be stored until the
+    /// whole walk has found which of those links are in the tree.
+    Pending(Box<PendingPutDir>),
+}
+
+struct PendingPutDir {
+    /// Everything but the children and their link groups.
+    item: FSItem,
+
+    /// The path of each child and what storing it gave, in order.
+    children: Vec<(PathBuf, PutEntry)>,
+}
+
+impl PendingPutDir {
+    /// Stores this and the directories under it, with the links in `groups`.
+    fn store<DS: DataStore>(
+        self,
+        ds: &mut DS,
+        groups: &HashMap<PathBuf, LinkGroup>,
+    ) -> Result<Key, PutFsItemError> {
+        let mut item = self.item;
+
+        for (path, entry) in self.children {
+            let key = match entry {
+                PutEntry::Stored(key) => key,
+                PutEntry::Pending(dir) => dir.store(ds, groups)?,
+            };
+
+            item.children.push(key.into());
+            item.link_groups.push(groups.get(&path).copied());
+        }
+
+        if item.link_groups.iter().all(Option::is_none) {
+            item.link_groups.clear();
+        }
+
+        let object: Object = item.try_into()?;
+
+        Ok(ds.put_obj(&object)?)
+    }
+}
+
+fn put_fs_item_with<DS: DataStore>(
+    ds: &mut DS,
+    path: &Path,
+    filter: &dyn Fn(&DirEntry) -> bool,
+    options: PutOptions,
+    hooks: &mut dyn PutHooks<DS>,
+) -> Result<Key, PutFsItemError> {
+    let mut walk = PutWalk {
+        filter,
+        options,
+        hooks,
+        ancestors: Vec::new(),
+        linked: HashMap::new(),
+        links: HashMap::new(),
+    };
+
+    match put_fs_entry(ds, path, std::fs::metadata(path)?, &mut walk)? {
+        PutEntry::Stored(key) => Ok(key),
+        PutEntry::Pending(dir) => dir.store(ds, &link_groups(walk.linked)),
+    }
+}
+
+/// Which [`LinkGroup`] each file is in, from the paths of each file's links, in the order
+/// [`put_fs_entry`] found them. Files with no other links in the tree aren't in one.
+fn link_groups(linked: HashMap<(u64, u64), Vec<PathBuf>>) -> HashMap<PathBuf, LinkGroup> {
+    // By the lowest directory each group is entirely under.
+    let mut scopes: HashMap<PathBuf, Vec<Vec<PathBuf>>> = HashMap::new();
+
+    for paths in linked.into_values().filter(|paths| paths.len() > 1) {
+        let mut scope = paths[0]
+            .parent()
+            .map_or_else(PathBuf::new, Path::to_path_buf);
+
+        for other in &paths[1..] {
+            while !other.starts_with(&scope) && scope.pop() {}
+        }
+
+        scopes.entry(scope).or_default().push(paths);
+    }
+
+    let mut groups = HashMap::new();
+
+    for (scope, mut found) in scopes {
+        // Members were found in order, so this orders groups by their first.
+        found.sort();
+
+        let depth = scope.components().count();
+
+        for (index, paths) in found.into_iter().enumerate() {
+            for member in paths {
+                let up = member.components().count() - 1 - depth;
+
+                groups.insert(
+                    member,
+                    LinkGroup {
+                        up: up as u32,
+                        index: index as u64,
+                    },
+                );
+            }
+        }
+    }
+
+    groups
+}
+
+fn put_fs_entry<DS: DataStore>(
+    ds: &mut DS,
+    path: &Path,
+    meta: std::fs::Metadata,
+    walk: &mut PutWalk<'_, DS>,
+) -> Result<PutEntry, PutFsItemError> {
     if meta.is_dir() {
-        let mut result = Vec::new();
-        let mut result_names = Vec::new();
+        let id = (meta.dev(), meta.ino());
+
+        if walk.ancestors.contains(&id) {
+            return Err(PutFsItemError::SymlinkLoop(path.to_path_buf()));
+        }
 
-        let entries = std::fs::read_dir(path)?;
+        walk.ancestors.push(id);
 
-        for entry in entries {
-            match entry {
-                Ok(direntry) => {
-                    if filter(&direntry) {
-                        result.push(put_fs_item(ds, &direntry.path(), filter)?);
-                        result_names.push(direntry.file_name().into());
+        let mut children = Vec::new();
+        let mut children_names = Vec::new();
+
+        // Whether any of the files under it are linked to each other isn't known yet.
+        let mut pending = false;
+
+        let mut entries = std::fs::read_dir(path)?.collect::<Result<Vec<_>, _>>()?;
+
+        // By the bytes of their names, rather than in whatever order the file system gives, so
+        // the same directory gets the same key wherever it's stored from.
+        entries.sort_by_cached_key(|entry| entry.file_name().into_vec());
+
+        for direntry in entries {
+            if (walk.filter)(&direntry) {
+                let child = direntry.path();
+                let child_meta = entry_metadata(&child, walk.options.follow_symlinks)?;
+
+                let id = (child_meta.dev(), child_meta.ino());
+                let linked = child_meta.is_file() && child_meta.nlink() > 1;
+
+                let entry = match walk.links.get(&id) {
+                    Some(&key) if linked => PutEntry::Stored(key),
+                    _ => {
+                        let entry = put_fs_entry(ds, &child, child_meta, walk)?;
+
+                        if let (true, PutEntry::Stored(key)) = (linked, &entry) {
+                            walk.links.insert(id, *key);
+                        }
+
+                        entry
                     }
+                };
+
+                if linked {
+                    walk.linked.entry(id).or_default().push(child.clone());
                 }
-                Err(e) => return Err(e.into()),
+
+                pending |= linked || matches!(entry, PutEntry::Pending(_));
+                children.push((child, entry));
+                children_names.push(direntry.file_name().into());
             }
         }
 
-        let size = result.len() as u64;
+        walk.ancestors.pop();
 
-        let obj = FSItem {
-            children: result.iter().map(|&x| x.into()).collect(),
-            children_names: result_names,
+        let item = FSItem {
+            children: Vec::new(),
+            size: children_names.len() as u64,
+            children_names,
             itemtype: FSItemType::Dir,
-            size,
+            revision: METADATA_REVISION,
+            metadata: Some(UnixMetadata::from_fs(&meta)),
+            target: None,
+            has_xattrs: false,
+            xattrs: None,
+            link_groups: Vec::new(),
+            device: None,
         };
 
-        let object = obj.try_into()?;
+        let dir = PendingPutDir {
+            item: item.with_xattrs(put_xattrs(ds, path, true, walk.options.xattrs)?),
+            children,
+        };
 
-        return Ok(ds.put_obj(&object)?);
+        if pending {
+            return Ok(PutEntry::Pending(Box::new(dir)));
+        }
+
+        return Ok(PutEntry::Stored(dir.store(ds, &HashMap::new())?));
     }
 
     if meta.is_file() {
-        let f = std::fs::File::open(path)?;
+        let mut f = std::fs::File::open(path)?;
+
+        // From the open file, so it's the same file that gets read.
+        let meta = f.metadata()?;
 
-        let reader = std::io::BufReader::new(f);
+        if let Some(key) = walk.hooks.cached(ds, &meta)? {
+            walk.hooks.stored(ds, &meta, key, false)?;
+            return Ok(PutEntry::Stored(key));
+        }
 
-        let hash = file::put_data(ds, reader)?;
+        let hash = file::put_file(ds, &mut f, &ds.chunker_config()?)?;
 
         let obj = FSItem {
             children: vec![hash.into()],
             children_names: vec![],
             itemtype: FSItemType::File,
             size: meta.len(),
+            revision: METADATA_REVISION,
+            metadata: Some(UnixMetadata::from_fs(&meta)),
+            target: None,
+            has_xattrs: false,
+            xattrs: None,
+            link_groups: vec![],
+            device: None,
         };
 
-        let object = obj.try_into()?;
+        let object = obj
+            .with_xattrs(put_xattrs(ds, path, true, walk.options.xattrs)?)
+            .try_into()?;
 
-        return Ok(ds.put_obj(&object)?);
+        let key = ds.put_obj(&object)?;
+        walk.hooks.stored(ds, &meta, key, true)?;
+
+        return Ok(PutEntry::Stored(key));
+    }
+
+    if meta.file_type().is_symlink() {
+        let object = FSItem::symlink(std::fs::read_link(path)?, &meta)
+            .with_xattrs(put_xattrs(ds, path, false, walk.options.xattrs)?)
+            .try_into()?;
+
+        return Ok(PutEntry::Stored(ds.put_obj(&object)?));
+    }
+
+    if let Some(special) = SpecialFile::from_metadata(&meta) {
+        let object = FSItem::from_special(special, &meta)
+            .with_xattrs(put_xattrs(ds, path, true, walk.options.xattrs)?)
+            .try_into()?;
+
+        return Ok(PutEntry::Stored(ds.put_obj(&object)?));
     }
 
-    unimplemented!("meta is not a file or a directory?")
+    Err(PutFsItemError::UnsupportedFileType(path.to_path_buf()))
+}
+
+#[derive(Debug, Error)]
+pub enum PutXattrsError {
+    #[error("error reading extended attributes: {_0}")]
+    IOError(#[from] std::io::Error),
+
+    #[error("error putting extended attributes: {_0}")]
+    PutObjError(#[from] ds::PutObjError),
+
+    #[error("error encoding extended attributes: {_0}")]
+    EncodeError(#[from] serde_cbor::error::Error),
+}
+
+/// Stores the extended attributes of `path` if `xattrs` says to and it has any.
+fn put_xattrs<DS: DataStore>(
+    ds: &mut DS,
+    path: &Path,
+    follow_symlinks: bool,
+    xattrs: bool,
+) -> Result<Option<Key>, PutXattrsError> {
+    if !xattrs {
+        return Ok(None);
+    }
+
+    let attrs = XattrSet::read(path, follow_symlinks)?;
+
+    if attrs.is_empty() {
+        return Ok(None);
+    }
+
+    Ok(Some(ds.put_obj(&attrs.try_into()?)?))
+}
+
+/// The metadata of `path`, or of what it points to when following symlinks that point at
+/// something.
+fn entry_metadata(path: &Path, follow_symlinks: bool) -> Result<std::fs::Metadata, std::io::Error> {
+    if follow_symlinks {
+        match std::fs::metadata(path) {
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => {}
+            result => return result,
+        }
+    }
+
+    std::fs::symlink_metadata(path)
 }
 
 #[derive(Debug, Error)]
@@ -156,45 +1021,85 @@ pub enum HashFsItemError {
     #[error("error when encoding object: {_0}")]
     EncodeError(#[from] serde_cbor::error::Error),
 
-    #[error("hashing non-files is not supported")]
+    #[error("error getting hash algorithm: {_0}")]
+    HashAlgorithmError(#[from] ds::HashAlgorithmError),
+
+    #[error("error getting chunker config: {_0}")]
+    ChunkerConfigError(#[from] ds::ChunkerConfigError),
+
+    #[error("error storing extended attributes: {_0}")]
+    PutXattrsError(#[from] PutXattrsError),
+
+    #[error("error checking for a stored object: {_0}")]
+    RawExistsError(#[from] ds::RawExistsError),
+
+    #[error("directories can't be hashed")]
     NonFileError,
 }
 
+/// Stores anything but a directory the way [`put_fs_item`] would, with its extended attributes if
+/// `xattrs` is set.
 pub fn hash_fs_item<DS: DataStore, C: Cache>(
     ds: &mut DS,
     path: &Path,
     cache: &C,
+    xattrs: bool,
 ) -> Result<Key, HashFsItemError> {
-    let meta = std::fs::metadata(path)?;
+    let meta = std::fs::symlink_metadata(path)?;
 
-    if meta.is_file() {
-        use std::os::unix::fs::MetadataExt;
+    if meta.file_type().is_symlink() {
+        let object = FSItem::symlink(std::fs::read_link(path)?, &meta)
+            .with_xattrs(put_xattrs(ds, path, false, xattrs)?)
+            .try_into()?;
+
+        return Ok(ds.put_obj(&object)?);
+    }
 
-        let f = std::fs::File::open(path)?;
+    if let Some(special) = SpecialFile::from_metadata(&meta) {
+        let object = FSItem::from_special(special, &meta)
+            .with_xattrs(put_xattrs(ds, path, false, xattrs)?)
+            .try_into()?;
 
-        let ext_metadata = f.metadata()?;
+        return Ok(ds.put_obj(&object)?);
+    }
+
+    if meta.is_file() {
+        let mut f = std::fs::File::open(path)?;
+
+        // From the open file, so it's the same file that gets read.
+        let meta = f.metadata()?;
         let cache_key = CacheKey {
-            mtime: ext_metadata.mtime(),
-            inode: ext_metadata.ino(),
-            size: ext_metadata.size(),
+            xattrs,
+            ..CacheKey::from_metadata(&meta)
         };
 
         if let Some(h) = cache.get(cache_key)? {
-            return Ok(h);
+            // The cache is shared between repositories, which don't all hash or chunk the same
+            // way, so only a key this one has stored is any use.
+            if h.algorithm() == ds.hash_algorithm()? && ds.raw_exists(&h.as_db_key())? {
+                return Ok(h);
+            }
         }
 
-        let reader = std::io::BufReader::new(f);
-
-        let hash = file::put_data(ds, reader)?;
+        let hash = file::put_file(ds, &mut f, &ds.chunker_config()?)?;
 
         let obj = FSItem {
             children: vec![hash.into()],
             children_names: vec![],
             itemtype: FSItemType::File,
             size: meta.len(),
+            revision: METADATA_REVISION,
+            metadata: Some(UnixMetadata::from_fs(&meta)),
+            target: None,
+            has_xattrs: false,
+            xattrs: None,
+            link_groups: vec![],
+            device: None,
         };
 
-        let object = obj.try_into()?;
+        let object = obj
+            .with_xattrs(put_xattrs(ds, path, true, xattrs)?)
+            .try_into()?;
 
         let obj_hash = ds.put_obj(&object)?;
 
@@ -214,16 +1119,310 @@ pub fn hash_fs_item<DS: DataStore, C: Cache>(
     Err(HashFsItemError::NonFileError)
 }
 
+#[derive(Debug, Error)]
+pub enum RestoreFileError {
+    #[error("io error: {_0}")]
+    IOError(#[from] std::io::Error),
+
+    #[error("read data error: {_0}")]
+    ReadDataError(#[from] file::ReadDataError),
+}
+
+/// What walking a tree to restore found to do, once the walk is done.
+#[derive(Debug, Default)]
+pub(crate) struct RestorePlan {
+    pub(crate) files: Vec<PendingFile>,
+
+    pub(crate) symlinks: Vec<PendingSymlink>,
+
+    /// Files linked to others in `files`, made once those are written.
+    pub(crate) hard_links: Vec<PendingHardLink>,
+
+    pub(crate) specials: Vec<PendingSpecial>,
+
+    /// Directories, parents first, to create if they're empty and give their metadata.
+    pub(crate) dirs: Vec<PendingDir>,
+
+    /// Extended attribute sets read so far, as lots of items tend to share a few.
+    xattr_sets: HashMap<TypedKey<XattrSet>, XattrSet>,
+
+    /// The first path found in each group of hard links, which the rest are linked to.
+    link_groups: HashMap<ScopedLinkGroup, PathBuf>,
+}
+
+#[derive(Debug, Error)]
+pub enum LoadXattrsError {
+    #[error("error getting extended attributes: {_0}")]
+    GetObjError(#[from] ds::GetObjError),
+
+    #[error("error decoding extended attributes: {_0}")]
+    DecodeError(#[from] serde_cbor::error::Error),
+}
+
+impl RestorePlan {
+    /// Creates hard links, FIFOs, device nodes and symlinks, then creates directories and
+    /// restores their metadata, once the files are written.
+    pub(crate) fn finish(&self, options: RestoreMetadata) -> Result<(), std::io::Error> {
+        for link in &self.hard_links {
+            link.restore()?;
+        }
+
+        for special in &self.specials {
+            special.restore(options)?;
+        }
+
+        for dir in &self.dirs {
+            std::fs::create_dir_all(&dir.path)?;
+        }
+
+        // After the files and directories, so none of them can be made through a link.
+        for symlink in &self.symlinks {
+            symlink.restore(options)?;
+        }
+
+        // Last, so adding entries doesn't disturb the times or find a directory read-only.
+        for dir in self.dirs.iter().rev() {
+            if let Some(metadata) = &dir.metadata {
+                metadata.restore_dir(&dir.path, options)?;
+            }
+
+            if let (true, Some(xattrs)) = (options.xattrs, &dir.xattrs) {
+                xattrs.apply(&dir.path, false)?;
+            }
+        }
+
+        Ok(())
+    }
+
+    /// The extended attributes `item` was stored with.
+    fn load_xattrs<DS: DataStore>(
+        &mut self,
+        ds: &DS,
+        item: &FSItem,
+    ) -> Result<Option<XattrSet>, LoadXattrsError> {
+        let key = match item.xattrs() {
+            Some(key) => key,
+            None => return Ok(None),
+        };
+
+        let xattrs = match self.xattr_sets.entry(key) {
+            Entry::Occupied(entry) => entry.get().clone(),
+            Entry::Vacant(entry) => entry.insert(ds.get_obj(key.inner())?.try_into()?).clone(),
+        };
+
+        Ok(Some(xattrs))
+    }
+
+    /// Whether the file at `path` is in a group of hard links that already has a file to write,
+    /// in which case it's linked to that instead.
+    fn link(&mut self, path: &Path, link_group: Option<LinkGroup>) -> bool {
+        let group = match link_group {
+            Some(group) => group.scoped(path),
+            None => return false,
+        };
+
+        match self.link_groups.entry(group) {
+            Entry::Occupied(first) => {
+                self.hard_links.push(PendingHardLink {
+                    path: path.to_path_buf(),
+                    original: first.get().clone(),
+                });
+
+                true
+            }
+            Entry::Vacant(entry) => {
+                entry.insert(path.to_path_buf());
+
+                false
+            }
+        }
+    }
+}
+
+/// A directory found while walking a tree to restore, to be created if it's empty and given its
+/// metadata once everything in it is written.
+#[derive(Debug)]
+pub(crate) struct PendingDir {
+    pub(crate) path: PathBuf,
+    pub(crate) metadata: Option<UnixMetadata>,
+    pub(crate) xattrs: Option<XattrSet>,
+}
+
+/// A file found while walking a tree to restore, to be written out once the walk is done.
+#[derive(Debug)]
+pub(crate) struct PendingFile {
+    pub(crate) path: PathBuf,
+    pub(crate) data: Key,
+    pub(crate) size: u64,
+    pub(crate) metadata: Option<UnixMetadata>,
+    pub(crate) xattrs: Option<XattrSet>,
+
+    /// Whether to fail if something is already at `path`, rather than overwrite it.
+    pub(crate) create_new: bool,
+}
+
+impl PendingFile {
+    pub(crate) fn restore<DS: DataStore>(
+        &self,
+        ds: &DS,
+        options: RestoreMetadata,
+    ) -> Result<(), RestoreFileError> {
+        let mut f = if self.create_new {
+            if let Some(parent) = self.path.parent() {
+                std::fs::create_dir_all(parent)?;
+            }
+
+            std::fs::OpenOptions::new()
+                .write(true)
+                .create_new(true)
+                .open(&self.path)?
+        } else {
+            std::fs::File::create(&self.path)?
+        };
+
+        file::restore_data(ds, self.data, &mut f)?;
+
+        if let Some(metadata) = &self.metadata {
+            metadata.restore(&f, options)?;
+        }
+
+        // After the permissions, which would change an ACL's mask.
+        if let (true, Some(xattrs)) = (options.xattrs, &self.xattrs) {
+            xattrs.apply(&self.path, false)?;
+        }
+
+        Ok(())
+    }
+}
+
+/// A symlink found while walking a tree to restore. Anything that was at `path` is already gone.
+#[derive(Debug)]
+pub(crate) struct PendingSymlink {
+    pub(crate) path: PathBuf,
+    pub(crate) target: PathBuf,
+    pub(crate) metadata: Option<UnixMetadata>,
+    pub(crate) xattrs: Option<XattrSet>,
+}
+
+impl PendingSymlink {
+    fn restore(&self, options: RestoreMetadata) -> Result<(), std::io::Error> {
+        if let Some(parent) = self.path.parent() {
+            std::fs::create_dir_all(parent)?;
+        }
+
+        std::os::unix::fs::symlink(&self.target, &self.path)?;
+
+        if let (true, Some(xattrs)) = (options.xattrs, &self.xattrs) {
+            xattrs.apply(&self.path, false)?;
+        }
+
+        if let Some(metadata) = &self.metadata {
+            metadata.restore_symlink(&self.path, options)?;
+        }
+
+        Ok(())
+    }
+}
+
+/// A hard link found while walking a tree to restore. Anything that was at `path` is already gone.
+#[derive(Debug)]
+pub(crate) struct PendingHardLink {
+    pub(crate) path: PathBuf,
+
+    /// The file it's linked to, which gives it its contents and metadata.
+    pub(crate) original: PathBuf,
+}
+
+impl PendingHardLink {
+    fn restore(&self) -> Result<(), std::io::Error> {
+        if let Some(parent) = self.path.parent() {
+            std::fs::create_dir_all(parent)?;
+        }
+
+        std::fs::hard_link(&self.original, &self.path)
+    }
+}
+
+/// A FIFO, socket or device node found while walking a tree to restore. Anything that was at `path`
+/// is already gone, unless it's a socket.
+#[derive(Debug)]
+pub(crate) struct PendingSpecial {
+    pub(crate) path: PathBuf,
+    pub(crate) special: SpecialFile,
+    pub(crate) metadata: Option<UnixMetadata>,
+    pub(crate) xattrs: Option<XattrSet>,
+}
+
+impl PendingSpecial {
+    /// Creates the FIFO or device node. Sockets, and devices without the privileges to create
+    /// them, are skipped with a warning.
+    fn restore(&self, options: RestoreMetadata) -> Result<(), std::io::Error> {
+        let (kind, dev) = match self.special {
+            SpecialFile::Fifo => (libc::S_IFIFO, 0),
+            SpecialFile::Socket => {
+                log::warn!("skipped socket {}", self.path.display());
+                return Ok(());
+            }
+            SpecialFile::CharDevice(dev) => (libc::S_IFCHR, dev.to_dev()),
+            SpecialFile::BlockDevice(dev) => (libc::S_IFBLK, dev.to_dev()),
+        };
+
+        if let Some(parent) = self.path.parent() {
+            std::fs::create_dir_all(parent)?;
+        }
+
+        let c_path = CString::new(self.path.as_os_str().as_bytes())?;
+        let mode = self.metadata.as_ref().map_or(0o644, |m| m.mode);
+
+        // SAFETY: c_path is NUL-terminated.
+        let ret = unsafe {
+            if kind == libc::S_IFIFO {
+                libc::mkfifo(c_path.as_ptr(), mode)
+            } else {
+                libc::mknod(c_path.as_ptr(), kind | mode, dev)
+            }
+        };
+
+        if ret != 0 {
+            let err = std::io::Error::last_os_error();
+
+            if err.raw_os_error() == Some(libc::EPERM) {
+                log::warn!(
+                    "skipped {} {}, as creating it needs more privileges",
+                    self.special,
+                    self.path.display()
+                );
+                return Ok(());
+            }
+
+            return Err(err);
+        }
+
+        if let Some(metadata) = &self.metadata {
+            metadata.restore_special(&self.path, options)?;
+        }
+
+        if let (true, Some(xattrs)) = (options.xattrs, &self.xattrs) {
+            xattrs.apply(&self.path, false)?;
+        }
+
+        Ok(())
+    }
+}
+
 #[derive(Debug, Error)]
 pub enum GetFsItemError {
     #[error("io error: {_0}")]
     IOError(#[from] std::io::Error),
 
+    #[error("error loading extended attributes: {_0}")]
+    LoadXattrsError(#[from] LoadXattrsError),
+
     #[error("get obj error: {_0}")]
     GetObjError(#[from] ds::GetObjError),
 
-    #[error("read data error: {_0}")]
-    ReadDataError(#[from] file::ReadDataError),
+    #[error("error restoring file: {_0}")]
+    RestoreFileError(#[from] RestoreFileError),
 
     #[error("error when decoding object: {_0}")]
     DecodeError(#[from] serde_cbor::error::Error),
@@ -233,28 +1432,91 @@ pub fn get_fs_item<DS: DataStore>(
     ds: &DS,
     key: TypedKey<FSItem>,
     path: &Path,
+    options: RestoreMetadata,
+) -> Result<(), GetFsItemError> {
+    let mut plan = RestorePlan::default();
+    plan_get_fs_item(ds, key, path, None, &mut plan)?;
+
+    for file in &plan.files {
+        file.restore(ds, options)?;
+    }
+
+    plan.finish(options)?;
+
+    Ok(())
+}
+
+/// Walks the tree `get_fs_item` would restore, collecting what to write. `link_group` is the
+/// group of hard links `key` is in, if it's a file with others.
+pub(crate) fn plan_get_fs_item<DS: DataStore>(
+    ds: &DS,
+    key: TypedKey<FSItem>,
+    path: &Path,
+    link_group: Option<LinkGroup>,
+    plan: &mut RestorePlan,
 ) -> Result<(), GetFsItemError> {
     let obj = ds.get_obj(key.into())?;
 
     let fsobj: FSItem = obj.try_into()?;
+    let xattrs = plan.load_xattrs(ds, &fsobj)?;
 
     match fsobj.itemtype {
         FSItemType::Dir => {
-            for (&child, name) in fsobj.children.iter().zip(fsobj.children_names.iter()) {
-                get_fs_item(ds, child, &path.join(&name))?;
+            plan.dirs.push(PendingDir {
+                path: path.to_path_buf(),
+                metadata: fsobj.metadata.clone(),
+                xattrs,
+            });
+
+            for (i, (&child, name)) in fsobj
+                .children
+                .iter()
+                .zip(fsobj.children_names.iter())
+                .enumerate()
+            {
+                let link_group = fsobj.link_groups.get(i).copied().flatten();
+
+                plan_get_fs_item(ds, child, &path.join(&name), link_group, plan)?;
             }
         }
         FSItemType::File => {
-            if let Some(parent) = path.parent() {
-                std::fs::create_dir_all(parent)?;
+            if plan.link(path, link_group) {
+                return Ok(());
             }
 
-            let mut f = std::fs::OpenOptions::new()
-                .write(true)
-                .create_new(true)
-                .open(path)?;
-
-            file::read_data(ds, fsobj.children[0].into(), &mut f)?;
+            if let Some(data) = fsobj.data_key() {
+                plan.files.push(PendingFile {
+                    path: path.to_path_buf(),
+                    data,
+                    size: fsobj.size,
+                    xattrs,
+                    metadata: fsobj.metadata,
+                    create_new: true,
+                });
+            }
+        }
+        FSItemType::Symlink => {
+            if let Some(target) = fsobj.symlink_target() {
+                plan.symlinks.push(PendingSymlink {
+                    path: path.to_path_buf(),
+                    target: target.to_path_buf(),
+                    metadata: fsobj.metadata.clone(),
+                    xattrs,
+                });
+            }
+        }
+        FSItemType::Fifo
+        | FSItemType::Socket
+        | FSItemType::CharDevice
+        | FSItemType::BlockDevice => {
+            if let Some(special) = fsobj.special() {
+                plan.specials.push(PendingSpecial {
+                    path: path.to_path_buf(),
+                    special,
+                    metadata: fsobj.metadata,
+                    xattrs,
+                });
+            }
         }
     }
 
@@ -266,17 +1528,20 @@ pub enum CheckoutFsItemError {
     #[error("io error: {_0}")]
     IOError(#[from] std::io::Error),
 
+    #[error("error loading extended attributes: {_0}")]
+    LoadXattrsError(#[from] LoadXattrsError),
+
     #[error("get obj error: {_0}")]
     GetObjError(#[from] ds::GetObjError),
 
-    #[error("read data error: {_0}")]
-    ReadDataError(#[from] file::ReadDataError),
+    #[error("error restoring file: {_0}")]
+    RestoreFileError(#[from] RestoreFileError),
 
     #[error("error when decoding object: {_0}")]
     DecodeError(#[from] serde_cbor::error::Error),
 
-    #[error("found unimplemented file type")]
-    UnimplementedFileTypeFound,
+    #[error("{} is outside the checkout at {}", _0.display(), _1.display())]
+    OutsideCheckout(PathBuf, PathBuf),
 }
 
 pub fn checkout_fs_item<DS: DataStore>(
@@ -284,13 +1549,59 @@ pub fn checkout_fs_item<DS: DataStore>(
     key: TypedKey<FSItem>,
     path: &Path,
     filter: &dyn Fn(&DirEntry) -> bool,
+    options: RestoreMetadata,
+) -> Result<(), CheckoutFsItemError> {
+    let mut plan = RestorePlan::default();
+    plan_checkout_fs_item(ds, key, path, path, filter, None, &mut plan)?;
+
+    for file in &plan.files {
+        file.restore(ds, options)?;
+    }
+
+    plan.finish(options)?;
+
+    Ok(())
+}
+
+/// Walks the tree `checkout_fs_item` would restore to `root`, removing what's not in it and
+/// collecting the files to write. `link_group` is as in [`plan_get_fs_item`].
+pub(crate) fn plan_checkout_fs_item<DS: DataStore>(
+    ds: &DS,
+    key: TypedKey<FSItem>,
+    root: &Path,
+    path: &Path,
+    filter: &dyn Fn(&DirEntry) -> bool,
+    link_group: Option<LinkGroup>,
+    plan: &mut RestorePlan,
 ) -> Result<(), CheckoutFsItemError> {
     let obj = ds.get_obj(key.into())?;
 
     let fsobj: FSItem = obj.try_into()?;
+    let xattrs = plan.load_xattrs(ds, &fsobj)?;
 
     match fsobj.itemtype {
         FSItemType::Dir => {
+            // A symlink would have what it points to checked out over, and anything else that
+            // isn't a directory can't be listed.
+            match std::fs::symlink_metadata(path) {
+                Ok(meta) if meta.is_dir() => {}
+                Ok(_) => {
+                    check_in_checkout(root, path)?;
+                    std::fs::remove_file(path)?;
+                    std::fs::create_dir(path)?;
+                }
+                Err(e) if e.kind() == std::io::ErrorKind::NotFound => {
+                    std::fs::create_dir_all(path)?
+                }
+                Err(e) => return Err(e.into()),
+            }
+
+            plan.dirs.push(PendingDir {
+                path: path.to_path_buf(),
+                metadata: fsobj.metadata.clone(),
+                xattrs,
+            });
+
             let db_items: HashSet<PathBuf> = fsobj.children_names.iter().cloned().collect();
 
             let mut fs_items = HashSet::new();
@@ -306,37 +1617,131 @@ pub fn checkout_fs_item<DS: DataStore>(
             let extra: Vec<_> = fs_items.difference(&db_items).collect();
 
             for item in extra.iter() {
-                assert!(path.starts_with("/home/jess/src/snapcd/repo"));
-                let p = path.join(item);
-                let ft = std::fs::metadata(&p)?.file_type();
-
-                if ft.is_dir() {
-                    std::fs::remove_dir_all(p)?;
-                } else if ft.is_file() {
-                    std::fs::remove_file(p)?;
-                } else if ft.is_symlink() {
-                    return Err(CheckoutFsItemError::UnimplementedFileTypeFound);
-                }
+                let extra = path.join(item);
+                check_in_checkout(root, &extra)?;
+                remove_existing(&extra)?;
             }
 
-            for (&child, name) in fsobj.children.iter().zip(fsobj.children_names.iter()) {
+            for (i, (&child, name)) in fsobj
+                .children
+                .iter()
+                .zip(fsobj.children_names.iter())
+                .enumerate()
+            {
                 std::fs::create_dir_all(&path)?;
 
-                checkout_fs_item(ds, child, &path.join(&name), filter)?;
+                let link_group = fsobj.link_groups.get(i).copied().flatten();
+
+                plan_checkout_fs_item(
+                    ds,
+                    child,
+                    root,
+                    &path.join(&name),
+                    filter,
+                    link_group,
+                    plan,
+                )?;
             }
         }
         FSItemType::File => {
-            let mut f = std::fs::File::create(path)?;
+            check_in_checkout(root, path)?;
+
+            let linked = plan.link(path, link_group);
+
+            // A link can't be made over anything, and a file would otherwise be written through a
+            // symlink, or through the other links to it.
+            match std::fs::symlink_metadata(path) {
+                Ok(meta) if linked || meta.file_type().is_symlink() || meta.nlink() > 1 => {
+                    std::fs::remove_file(path)?
+                }
+                Ok(_) => {}
+                Err(e) if e.kind() == std::io::ErrorKind::NotFound => {}
+                Err(e) => return Err(e.into()),
+            }
 
-            assert!(path.starts_with("/home/jess/src/snapcd/repo"));
+            if linked {
+                return Ok(());
+            }
 
-            file::read_data(ds, fsobj.children[0].into(), &mut f)?;
+            if let Some(data) = fsobj.data_key() {
+                plan.files.push(PendingFile {
+                    path: path.to_path_buf(),
+                    data,
+                    size: fsobj.size,
+                    xattrs,
+                    metadata: fsobj.metadata,
+                    create_new: false,
+                });
+            }
+        }
+        FSItemType::Symlink => {
+            check_in_checkout(root, path)?;
+
+            remove_existing(path)?;
+
+            if let Some(target) = fsobj.symlink_target() {
+                plan.symlinks.push(PendingSymlink {
+                    path: path.to_path_buf(),
+                    target: target.to_path_buf(),
+                    metadata: fsobj.metadata.clone(),
+                    xattrs,
+                });
+            }
+        }
+        FSItemType::Fifo
+        | FSItemType::Socket
+        | FSItemType::CharDevice
+        | FSItemType::BlockDevice => {
+            check_in_checkout(root, path)?;
+
+            if let Some(special) = fsobj.special() {
+                // Sockets aren't restored, so whatever's there is left alone.
+                if special != SpecialFile::Socket {
+                    remove_existing(path)?;
+                }
+
+                plan.specials.push(PendingSpecial {
+                    path: path.to_path_buf(),
+                    special,
+                    metadata: fsobj.metadata,
+                    xattrs,
+                });
+            }
         }
     }
 
     Ok(())
 }
 
+/// Removes whatever's at `path`, if anything.
+fn remove_existing(path: &Path) -> Result<(), std::io::Error> {
+    match std::fs::symlink_metadata(path) {
+        Ok(meta) if meta.is_dir() => std::fs::remove_dir_all(path),
+        Ok(_) => std::fs::remove_file(path),
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(()),
+        Err(e) => Err(e),
+    }
+}
+
+/// Makes sure `path` is inside the checkout at `root`, before anything there is replaced.
+fn check_in_checkout(root: &Path, path: &Path) -> Result<(), CheckoutFsItemError> {
+    let inside = match path.strip_prefix(root) {
+        Ok(rest) => rest
+            .components()
+            .all(|c| matches!(c, std::path::Component::Normal(_))),
+        Err(_) => false,
+    };
+
+    if !inside {
+        return Err(CheckoutFsItemError::OutsideCheckout(
+            path.to_path_buf(),
+            root.to_path_buf(),
+        ));
+    }
+
+    Ok(())
+}
+
 #[derive(Debug, Error)]
 pub enum WalkFsItemsError {
     #[error("get obj error: {_0}")]
@@ -350,16 +1755,42 @@ pub fn walk_fs_items<DS: DataStore>(
     ds: &DS,
     key: TypedKey<FSItem>,
 ) -> Result<HashMap<PathBuf, (TypedKey<FSItem>, bool)>, WalkFsItemsError> {
-    internal_walk_fs_items(ds, key, &PathBuf::new())
+    Ok(walk_fs_items_and_links(ds, key)?.items)
 }
 
-pub fn internal_walk_fs_items<DS: DataStore>(
+/// Like [`walk_fs_items`], also finding hard links.
+pub fn walk_fs_items_and_links<DS: DataStore>(
     ds: &DS,
     key: TypedKey<FSItem>,
-    path: &Path,
-) -> Result<HashMap<PathBuf, (TypedKey<FSItem>, bool)>, WalkFsItemsError> {
+) -> Result<TreeWalk<(TypedKey<FSItem>, bool), ScopedLinkGroup>, WalkFsItemsError> {
     let mut results = HashMap::new();
+    let mut links = HashMap::new();
+
+    internal_walk_fs_items(ds, key, &PathBuf::new(), &mut results, &mut links)?;
+
+    Ok(TreeWalk {
+        items: results,
+        links,
+    })
+}
+
+/// Something for each path in a tree, relative to the tree.
+pub type PathMap<T> = HashMap<PathBuf, T>;
+
+/// What walking a tree found: `items`, and the group of hard links each file in one is in.
+#[derive(Debug)]
+pub struct TreeWalk<I, G> {
+    pub items: PathMap<I>,
+    pub links: PathMap<G>,
+}
 
+pub fn internal_walk_fs_items<DS: DataStore>(
+    ds: &DS,
+    key: TypedKey<FSItem>,
+    path: &Path,
+    results: &mut HashMap<PathBuf, (TypedKey<FSItem>, bool)>,
+    links: &mut PathMap<ScopedLinkGroup>,
+) -> Result<(), WalkFsItemsError> {
     let obj = ds.get_obj(key.inner())?;
 
     let fsobj: FSItem = obj.try_into()?;
@@ -372,44 +1803,73 @@ pub fn internal_walk_fs_items<DS: DataStore>(
                 results.insert(path.to_path_buf(), (key, true));
             }
 
-            for (&child, name) in fsobj.children.iter().zip(fsobj.children_names.iter()) {
-                results.extend(internal_walk_fs_items(ds, child, &path.join(&name))?);
+            for (i, (&child, name)) in fsobj
+                .children
+                .iter()
+                .zip(fsobj.children_names.iter())
+                .enumerate()
+            {
+                let child_path = path.join(&name);
+
+                if let Some(&Some(group)) = fsobj.link_groups.get(i) {
+                    links.insert(child_path.clone(), group.scoped(&child_path));
+                }
+
+                internal_walk_fs_items(ds, child, &child_path, results, links)?;
             }
         }
-        FSItemType::File => {
+        _ => {
             results.insert(path.to_path_buf(), (key, false));
         }
     }
 
-    Ok(results)
+    Ok(())
 }
 
 #[derive(Debug, Error)]
 pub enum WalkRealFsItemsError {
     #[error("io error: {_0}")]
     IOError(#[from] std::io::Error),
-
-    #[error("found unimplemented file type")]
-    UnimplementedFileTypeFound,
 }
 
 pub fn walk_real_fs_items(
     base_path: &Path,
     filter: &dyn Fn(&DirEntry) -> bool,
 ) -> Result<HashMap<PathBuf, bool>, WalkRealFsItemsError> {
-    internal_walk_real_fs_items(base_path, &PathBuf::new(), filter)
+    Ok(walk_real_fs_items_and_links(base_path, filter)?.items)
 }
 
-pub fn internal_walk_real_fs_items(
+/// Like [`walk_real_fs_items`], also finding hard links, which are grouped by device and inode.
+pub fn walk_real_fs_items_and_links(
     base_path: &Path,
-    path: &Path,
     filter: &dyn Fn(&DirEntry) -> bool,
-) -> Result<HashMap<PathBuf, bool>, WalkRealFsItemsError> {
+) -> Result<TreeWalk<bool, (u64, u64)>, WalkRealFsItemsError> {
     let mut results = HashMap::new();
+    let mut links = HashMap::new();
+
+    internal_walk_real_fs_items(base_path, &PathBuf::new(), filter, &mut results, &mut links)?;
+
+    Ok(TreeWalk {
+        items: results,
+        links,
+    })
+}
 
+pub fn internal_walk_real_fs_items(
+    base_path: &Path,
+    path: &Path,
+    filter: &dyn Fn(&DirEntry) -> bool,
+    results: &mut HashMap<PathBuf, bool>,
+    links: &mut PathMap<(u64, u64)>,
+) -> Result<(), WalkRealFsItemsError> {
     let curr_path = base_path.join(path);
 
-    let meta = std::fs::metadata(&curr_path)?;
+    // Like put_fs_item, only the base path is followed.
+    let meta = if path.as_os_str().is_empty() {
+        std::fs::metadata(&curr_path)?
+    } else {
+        std::fs::symlink_metadata(&curr_path)?
+    };
 
     if meta.is_dir() {
         let entries = std::fs::read_dir(&curr_path)?;
@@ -425,20 +1885,21 @@ pub fn internal_walk_real_fs_items(
                     if filter(&direntry) {
                         let p = path.join(direntry.file_name());
 
-                        results.extend(internal_walk_real_fs_items(base_path, &p, filter)?);
+                        internal_walk_real_fs_items(base_path, &p, filter, results, links)?;
                     }
                 }
                 Err(e) => return Err(e.into()),
             }
         }
 
-        return Ok(results);
+        return Ok(());
     }
 
-    if meta.is_file() {
-        results.insert(path.to_path_buf(), false);
-        return Ok(results);
+    if meta.is_file() && meta.nlink() > 1 {
+        links.insert(path.to_path_buf(), (meta.dev(), meta.ino()));
     }
 
-    Err(WalkRealFsItemsError::UnimplementedFileTypeFound)
+    results.insert(path.to_path_buf(), false);
+
+    Ok(())
 }
@@ -1,6 +1,14 @@
 use colored::*;
 
-use crate::{commit, diff, file, key::Key, object, DataStore};
+use crate::{
+    commit, diff,
+    dir::FSItem,
+    ds, file,
+    key::{Key, TypedKey},
+    object,
+    xattr::XattrSet,
+    DataStore,
+};
 use std::convert::TryInto;
 use thiserror::Error;
 
@@ -11,6 +19,24 @@ pub enum ShowError {
 
     #[error("error showing diff patch: {_0}")]
     DiffPatch(#[from] diff::DiffPatchError),
+
+    #[error("error getting object: {_0}")]
+    GetObjError(#[from] ds::GetObjError),
+
+    #[error("error decoding object: {_0}")]
+    DecodeError(#[from] serde_cbor::error::Error),
+
+    #[error("error reading commit: {_0}")]
+    FromKeyError(#[from] commit::FromKeyError),
+
+    #[error("error comparing trees: {_0}")]
+    CompareError(#[from] diff::CompareError),
+
+    #[error("file {key} should point at one piece of data, but points at {count}")]
+    InvalidFile { key: Key, count: usize },
+
+    #[error("{key} is an object of unknown type '{name}' (created by a newer version?)")]
+    UnknownType { key: Key, name: String },
 }
 
 #[derive(Debug, Clone, Copy)]
@@ -19,63 +45,77 @@ pub enum Kind {
     Patch,
 }
 
-pub fn log_obj(ds: &mut impl DataStore, key: Key, kind: Kind) -> Result<(), ShowError> {
-    let obj = ds.get_obj(key).unwrap().into_owned();
-
-    match obj.objtype() {
-        object::ObjType::Commit => {
-            display_obj(ds, key, kind)?;
-
-            println!();
-            println!();
-
-            let mut obj = ds.get_obj(key).unwrap().into_owned();
+/// How keys are printed.
+#[derive(Debug, Clone, Copy)]
+pub enum KeyStyle {
+    /// The whole key.
+    Full,
+    /// The shortest prefix that's unique in the store, but at least this many characters.
+    Short(usize),
+}
 
-            let mut commit_obj: commit::Commit = obj
-                .into_owned()
-                .try_into()
-                .expect("failed to convert commit obj");
+impl KeyStyle {
+    pub fn format<DS: DataStore + ?Sized>(self, ds: &DS, key: Key) -> String {
+        match self {
+            KeyStyle::Full => key.as_user_key(),
+            KeyStyle::Short(min_len) => ds.abbreviate(key, min_len).unwrap_or_else(|e| {
+                log::warn!("failed to abbreviate {}: {}", key, e);
+                key.as_user_key()
+            }),
+        }
+    }
+}
 
-            while let Some(p) = commit_obj.parents().get(0).copied() {
-                display_obj(ds, p.into(), kind)?;
+pub fn log_obj(
+    ds: &mut impl DataStore,
+    key: TypedKey<commit::Commit>,
+    kind: Kind,
+    style: KeyStyle,
+) -> Result<(), ShowError> {
+    let mut next = Some(key);
 
-                println!();
-                println!();
+    while let Some(key) = next {
+        display_obj(ds, key.inner(), kind, style)?;
 
-                obj = ds.get_obj(p.inner()).unwrap().into_owned();
+        println!();
+        println!();
 
-                commit_obj = obj
-                    .into_owned()
-                    .try_into()
-                    .expect("failed to convert commit obj");
-            }
-        }
-        _ => panic!("Invalid key type"),
+        next = commit::Commit::from_key(ds, key)?
+            .parents()
+            .first()
+            .copied();
     }
 
     Ok(())
 }
 
-pub fn display_obj(ds: &mut impl DataStore, key: Key, kind: Kind) -> Result<(), ShowError> {
-    let obj = ds.get_obj(key).unwrap().into_owned();
+pub fn display_obj(
+    ds: &mut impl DataStore,
+    key: Key,
+    kind: Kind,
+    style: KeyStyle,
+) -> Result<(), ShowError> {
+    let obj = ds.get_obj(key)?.into_owned();
 
     use object::ObjType;
 
     match obj.objtype() {
-        ObjType::FileBlobTree | ObjType::FileBlob => {
+        ObjType::FileBlobTree | ObjType::FileBlob | ObjType::ZeroRun => {
             file::read_data(ds, key, &mut std::io::stdout())?;
         }
-        ObjType::FSItemFile => {
-            assert!(obj.keys().len() == 1);
-            display_obj(ds, obj.keys()[0], kind)?;
-        }
+        ObjType::FSItemFile => match obj.keys() {
+            &[data] => display_obj(ds, data, kind, style)?,
+            keys => {
+                return Err(ShowError::InvalidFile {
+                    key,
+                    count: keys.len(),
+                })
+            }
+        },
         ObjType::Commit => {
-            println!("{}", format!("commit: {}", key).yellow());
+            println!("{}", format!("commit: {}", style.format(ds, key)).yellow());
 
-            let commit_obj: commit::Commit = obj
-                .into_owned()
-                .try_into()
-                .expect("failed to convert commit obj");
+            let commit_obj: commit::Commit = obj.into_owned().try_into()?;
 
             println!();
 
@@ -87,14 +127,7 @@ pub fn display_obj(ds: &mut impl DataStore, key: Key, kind: Kind) -> Result<(),
 
             let tree;
             if let Some(p) = parent {
-                let parent_obj = ds.get_obj(p.inner()).unwrap();
-
-                let parent_cmt: commit::Commit = parent_obj
-                    .into_owned()
-                    .try_into()
-                    .expect("failed to convert commit obj");
-
-                tree = Some(parent_cmt.tree());
+                tree = Some(commit::Commit::from_key(ds, p)?.tree());
             } else {
                 tree = None;
             }
@@ -104,12 +137,11 @@ pub fn display_obj(ds: &mut impl DataStore, key: Key, kind: Kind) -> Result<(),
                 diff::DiffTarget::Database(commit_obj.tree()),
                 tree,
                 None,
-            )
-            .unwrap();
+            )?;
 
             match kind {
                 Kind::Stat => {
-                    diff::print_stat_diff_result(ds, dr);
+                    diff::print_stat_diff_result(ds, dr)?;
                 }
                 Kind::Patch => {
                     diff::print_patch_diff_result(ds, dr)?;
@@ -117,10 +149,44 @@ pub fn display_obj(ds: &mut impl DataStore, key: Key, kind: Kind) -> Result<(),
             }
         }
         ObjType::FSItemDir => {
-            println!("{}", format!("tree: {}", key).yellow());
+            println!("{}", format!("tree: {}", style.format(ds, key)).yellow());
+        }
+        ObjType::FSItemSymlink => {
+            let item: FSItem = obj.try_into()?;
+
+            if let Some(target) = item.symlink_target() {
+                println!("{}", target.display());
+            }
+        }
+        ObjType::FSItemSpecial => {
+            let item: FSItem = obj.try_into()?;
+
+            if let Some(special) = item.special() {
+                println!("{}", special);
+            }
+        }
+        ObjType::XattrSet => {
+            let attrs: XattrSet = obj.try_into()?;
+
+            for (name, value) in attrs.iter() {
+                println!(
+                    "{}={}",
+                    String::from_utf8_lossy(name),
+                    String::from_utf8_lossy(value)
+                );
+            }
+        }
+        ObjType::ParityGroup => {
+            println!(
+                "{}",
+                format!("parity group: {}", style.format(ds, key)).yellow()
+            );
         }
         ObjType::Unknown => {
-            panic!("cannot display unknown object");
+            return Err(ShowError::UnknownType {
+                key,
+                name: obj.type_name().to_string(),
+            });
         }
     }
 
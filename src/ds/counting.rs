@@ -0,0 +1,145 @@
+use crate::canonical;
+use crate::chunker::ChunkerConfig;
+use crate::commit;
+use crate::ds::null::NullDS;
+use crate::ds::{
+    self, ChunkerConfigError, DSError, GetReflogError, HashAlgorithmError, PutError, PutObjError,
+    RawBetweenError, RawExistsError, RawGetError, RawGetStateError, RawPutError, RawPutStateError,
+    RawReflogNamesError, RawReflogPushError, WalkReflogError,
+};
+use crate::key::{HashAlgorithm, Key, TypedKey};
+use crate::object::{ObjType, Object};
+use crate::{DataStore, Reflog};
+use std::borrow::Cow;
+use std::cell::{Cell, RefCell};
+use std::collections::HashMap;
+
+/// A [`NullDS`] that counts what would have been stored.
+///
+/// File data is thrown away once it's been counted, so only blob sizes are kept. Everything else
+/// (directories, files, blob trees) is kept in memory, so the result can still be walked.
+#[derive(Debug, Default)]
+pub struct CountingDS {
+    null: NullDS,
+
+    /// The size of each distinct blob.
+    blobs: RefCell<HashMap<Key, u64>>,
+
+    /// Blobs put, counting duplicates.
+    blob_puts: Cell<u64>,
+
+    objects: RefCell<HashMap<Vec<u8>, Vec<u8>>>,
+}
+
+impl CountingDS {
+    pub fn new(hash_algorithm: HashAlgorithm, chunker_config: ChunkerConfig) -> Self {
+        Self {
+            null: NullDS::new(hash_algorithm, chunker_config),
+            ..Self::default()
+        }
+    }
+
+    /// The size of the blob at `key`, or `None` if it isn't a blob.
+    pub fn blob_size(&self, key: Key) -> Option<u64> {
+        self.blobs.borrow().get(&key).copied()
+    }
+
+    /// Every distinct blob, and its size.
+    pub fn blob_sizes(&self) -> Vec<(Key, u64)> {
+        self.blobs.borrow().iter().map(|(&k, &v)| (k, v)).collect()
+    }
+
+    pub fn blob_puts(&self) -> u64 {
+        self.blob_puts.get()
+    }
+
+    /// Every object that isn't a blob, and its encoded size.
+    pub fn object_sizes(&self) -> Vec<(Key, u64)> {
+        self.objects
+            .borrow()
+            .iter()
+            .filter_map(|(k, v)| Some((Key::from_db_key(k).ok()?, v.len() as u64)))
+            .collect()
+    }
+}
+
+impl ds::Transactional for CountingDS {}
+
+impl DataStore for CountingDS {
+    fn raw_get(&self, key: &[u8]) -> Result<Cow<'_, [u8]>, RawGetError> {
+        match self.objects.borrow().get(key) {
+            Some(data) => Ok(Cow::Owned(data.clone())),
+            None => Err(DSError::NotStored.into()),
+        }
+    }
+    fn raw_put(&self, key: &[u8], data: &[u8]) -> Result<(), RawPutError> {
+        self.objects
+            .borrow_mut()
+            .insert(key.to_vec(), data.to_vec());
+        Ok(())
+    }
+    fn raw_replace(&self, key: &[u8], data: &[u8]) -> Result<(), RawPutError> {
+        self.raw_put(key, data)
+    }
+    fn raw_exists(&self, key: &[u8]) -> Result<bool, RawExistsError> {
+        let is_blob = Key::from_db_key(key).is_ok_and(|k| self.blobs.borrow().contains_key(&k));
+
+        Ok(is_blob || self.objects.borrow().contains_key(key))
+    }
+    fn raw_get_state(&self, key: &[u8]) -> Result<Option<Vec<u8>>, RawGetStateError> {
+        self.null.raw_get_state(key)
+    }
+    fn hash_algorithm(&self) -> Result<HashAlgorithm, HashAlgorithmError> {
+        self.null.hash_algorithm()
+    }
+    fn chunker_config(&self) -> Result<ChunkerConfig, ChunkerConfigError> {
+        self.null.chunker_config()
+    }
+
+    fn put_obj(&self, obj: &Object) -> Result<Key, PutObjError> {
+        let data = canonical::to_vec(obj)?;
+        let key = self.hash(&data).map_err(PutError::from)?;
+
+        if let ObjType::FileBlob = obj.objtype() {
+            self.blob_puts.set(self.blob_puts.get() + 1);
+            self.blobs.borrow_mut().insert(key, obj.data().len() as u64);
+        } else {
+            self.objects.borrow_mut().insert(key.as_db_key(), data);
+        }
+
+        Ok(key)
+    }
+
+    fn raw_put_state(&self, key: &[u8], data: &[u8]) -> Result<(), RawPutStateError> {
+        self.null.raw_put_state(key, data)
+    }
+    fn raw_reflog_push(&self, data: &Reflog) -> Result<(), RawReflogPushError> {
+        self.null.raw_reflog_push(data)
+    }
+    fn reflog_get(
+        &self,
+        refname: &str,
+        remote: Option<&str>,
+    ) -> Result<TypedKey<commit::Commit>, GetReflogError> {
+        self.null.reflog_get(refname, remote)
+    }
+    fn reflog_walk(
+        &self,
+        refname: &str,
+        remote: Option<&str>,
+    ) -> Result<Vec<TypedKey<commit::Commit>>, WalkReflogError> {
+        self.null.reflog_walk(refname, remote)
+    }
+
+    fn reflog_names(&self) -> Result<Vec<(String, Option<String>)>, RawReflogNamesError> {
+        self.null.reflog_names()
+    }
+
+    fn raw_between(
+        &self,
+        start: &[u8],
+        end: Option<&[u8]>,
+    ) -> Result<Vec<Vec<u8>>, RawBetweenError> {
+        self.null.raw_between(start, end)
+    }
+}
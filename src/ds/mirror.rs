@@ -0,0 +1,233 @@
+use std::borrow::Cow;
+
+use crate::commit;
+use crate::ds::{
+    BeginTransError, CommitTransError, DSError, DataStore, GetReflogError, RawBetweenError,
+    RawExistsError, RawGetError, RawGetStateError, RawPutError, RawPutStateError,
+    RawReflogNamesError, RawReflogPushError, RollbackTransError, Transactional, WalkReflogError,
+};
+use crate::key::{Key, TypedKey};
+use crate::Reflog;
+use thiserror::Error;
+
+/// A [`DataStore`] that writes everything to all of its children, and reads from the first one
+/// that has an intact copy.
+///
+/// Objects read back are checked against their key, so a corrupt or missing object in one child
+/// falls back to the next. If `heal` is set, the good copy is then written back over the bad ones.
+pub struct MirrorDS {
+    children: Vec<Box<dyn DataStore>>,
+    heal: bool,
+}
+
+impl std::fmt::Debug for MirrorDS {
+    fn fmt(&self, fmt: &mut std::fmt::Formatter<'_>) -> Result<(), std::fmt::Error> {
+        fmt.debug_struct("MirrorDS")
+            .field("children", &self.children.len())
+            .field("heal", &self.heal)
+            .finish()
+    }
+}
+
+#[derive(Debug, Error)]
+pub enum NewMirrorError {
+    #[error("a mirror needs at least one child store")]
+    NoChildren,
+}
+
+impl MirrorDS {
+    pub fn new(children: Vec<Box<dyn DataStore>>, heal: bool) -> Result<Self, NewMirrorError> {
+        if children.is_empty() {
+            return Err(NewMirrorError::NoChildren);
+        }
+
+        Ok(Self { children, heal })
+    }
+
+    pub fn children(&self) -> &[Box<dyn DataStore>] {
+        &self.children
+    }
+
+    /// Checks `data` was stored under `key`. Keys that aren't object keys can't be checked, so they
+    /// always pass.
+    fn is_intact(&self, key: &[u8], data: &[u8]) -> bool {
+        match Key::from_db_key(key) {
+            Ok(k) => k.verify(data),
+            Err(_) => true,
+        }
+    }
+
+    /// Tries each child in order, returning the first result that isn't an error.
+    fn first_ok<T, E: std::fmt::Display>(
+        &self,
+        mut f: impl FnMut(&dyn DataStore) -> Result<T, E>,
+    ) -> Result<T, E> {
+        // There's always at least one child, so this is always safe.
+        let mut result = f(self.children[0].as_ref());
+
+        for (idx, child) in self.children.iter().enumerate().skip(1) {
+            match &result {
+                Ok(_) => break,
+                Err(e) => log::warn!("mirror child {} failed, trying next: {}", idx - 1, e),
+            }
+
+            result = f(child.as_ref());
+        }
+
+        result
+    }
+
+    /// Runs `f` on every child, stopping at the first error.
+    fn all<E>(&self, mut f: impl FnMut(&dyn DataStore) -> Result<(), E>) -> Result<(), E> {
+        for child in &self.children {
+            f(child.as_ref())?;
+        }
+
+        Ok(())
+    }
+}
+
+impl Transactional for MirrorDS {
+    fn begin_trans(&mut self) -> Result<(), BeginTransError> {
+        for child in &mut self.children {
+            child.begin_trans()?;
+        }
+
+        Ok(())
+    }
+
+    fn commit(&mut self) -> Result<(), CommitTransError> {
+        // Keep going after a failure, it's better to have the data in some mirrors than none.
+        let mut first_err = None;
+
+        for (idx, child) in self.children.iter_mut().enumerate() {
+            if let Err(e) = child.commit() {
+                log::warn!("error committing mirror child {}: {}", idx, e);
+                first_err.get_or_insert(e);
+            }
+        }
+
+        match first_err {
+            Some(e) => Err(e),
+            None => Ok(()),
+        }
+    }
+
+    fn rollback(&mut self) -> Result<(), RollbackTransError> {
+        let mut first_err = None;
+
+        for (idx, child) in self.children.iter_mut().enumerate() {
+            if let Err(e) = child.rollback() {
+                log::warn!("error rolling back mirror child {}: {}", idx, e);
+                first_err.get_or_insert(e);
+            }
+        }
+
+        match first_err {
+            Some(e) => Err(e),
+            None => Ok(()),
+        }
+    }
+}
+
+impl DataStore for MirrorDS {
+    fn raw_get<'a>(&'a self, key: &[u8]) -> Result<Cow<'a, [u8]>, RawGetError> {
+        let mut bad: Vec<usize> = Vec::new();
+        let mut last_err = None;
+
+        for (idx, child) in self.children.iter().enumerate() {
+            match child.raw_get(key) {
+                Ok(data) if self.is_intact(key, &data) => {
+                    if self.heal {
+                        for bad_idx in bad {
+                            if let Err(e) = self.children[bad_idx].raw_replace(key, &data) {
+                                log::warn!("failed to heal mirror child {}: {}", bad_idx, e);
+                            }
+                        }
+                    }
+
+                    return Ok(Cow::Owned(data.into_owned()));
+                }
+                Ok(_) => {
+                    log::warn!("mirror child {} has a corrupt copy of {:?}", idx, key);
+                    bad.push(idx);
+                }
+                Err(e) => {
+                    log::warn!("mirror child {} failed to read {:?}: {}", idx, key, e);
+                    bad.push(idx);
+                    last_err = Some(e);
+                }
+            }
+        }
+
+        match last_err {
+            Some(e) => Err(e),
+            None => Err(DSError::NoIntactCopy.into()),
+        }
+    }
+
+    fn raw_put(&self, key: &[u8], data: &[u8]) -> Result<(), RawPutError> {
+        self.all(|child| child.raw_put(key, data))
+    }
+
+    fn raw_replace(&self, key: &[u8], data: &[u8]) -> Result<(), RawPutError> {
+        self.all(|child| child.raw_replace(key, data))
+    }
+
+    fn raw_exists(&self, key: &[u8]) -> Result<bool, RawExistsError> {
+        // If any child has it, a read will find it.
+        for child in &self.children {
+            if child.raw_exists(key)? {
+                return Ok(true);
+            }
+        }
+
+        Ok(false)
+    }
+
+    fn raw_get_state(&self, key: &[u8]) -> Result<Option<Vec<u8>>, RawGetStateError> {
+        self.first_ok(|child| child.raw_get_state(key))
+    }
+
+    fn raw_put_state(&self, key: &[u8], data: &[u8]) -> Result<(), RawPutStateError> {
+        self.all(|child| child.raw_put_state(key, data))
+    }
+
+    fn raw_reflog_push(&self, data: &Reflog) -> Result<(), RawReflogPushError> {
+        self.all(|child| child.raw_reflog_push(data))
+    }
+
+    fn reflog_get(
+        &self,
+        refname: &str,
+        remote: Option<&str>,
+    ) -> Result<TypedKey<commit::Commit>, GetReflogError> {
+        // A missing ref is an answer, not a failure, so only fall back on other errors.
+        let result = self.first_ok(|child| match child.reflog_get(refname, remote) {
+            Err(GetReflogError::NotFound) => Ok(None),
+            other => other.map(Some),
+        });
+
+        result?.ok_or(GetReflogError::NotFound)
+    }
+
+    fn reflog_walk(
+        &self,
+        refname: &str,
+        remote: Option<&str>,
+    ) -> Result<Vec<TypedKey<commit::Commit>>, WalkReflogError> {
+        self.first_ok(|child| child.reflog_walk(refname, remote))
+    }
+
+    fn reflog_names(&self) -> Result<Vec<(String, Option<String>)>, RawReflogNamesError> {
+        self.first_ok(|child| child.reflog_names())
+    }
+
+    fn raw_between(
+        &self,
+        start: &[u8],
+        end: Option<&[u8]>,
+    ) -> Result<Vec<Vec<u8>>, RawBetweenError> {
+        self.first_ok(|child| child.raw_between(start, end))
+    }
+}
@@ -1,17 +1,23 @@
+pub mod counting;
+pub mod mirror;
 pub mod null;
+pub mod sharded;
 //pub mod sled;
 pub mod sqlite;
 //pub mod rocks;
 
-use blake3::hash;
-
 use std::borrow::Cow;
+use std::convert::TryInto;
 
 use thiserror::Error;
 
+use crate::canonical;
+use crate::chunker::{self, ChunkerConfig};
 use crate::commit;
 use crate::key;
 use crate::key::TypedKey;
+use crate::keyish::ParentStep;
+use crate::object::ObjType;
 use crate::Keyish;
 use crate::Object;
 
@@ -29,8 +35,93 @@ pub enum CanonicalizeError {
     #[error("error when converting db key: {_0}")]
     FromDbKeyError(#[from] key::FromDbKeyError),
 
+    #[error("error when looking up key: {_0}")]
+    RawExistsError(#[from] RawExistsError),
+
+    #[error("error when looking up key prefix: {_0}")]
+    RawBetweenError(#[from] RawBetweenError),
+
+    #[error("error when looking up rehashed key: {_0}")]
+    RawGetStateError(#[from] RawGetStateError),
+
+    #[error("error when getting hash algorithm: {_0}")]
+    HashAlgorithmError(#[from] HashAlgorithmError),
+
     #[error("error when getting reflog: {_0}")]
     GetReflogError(#[from] GetReflogError),
+
+    #[error("error when getting HEAD: {_0}")]
+    GetHeadError(#[from] GetHeadError),
+
+    #[error("HEAD is not set")]
+    NoHead,
+
+    #[error("'{orig}' needs {key} to be a commit, but it is a {objtype}")]
+    NotACommit {
+        orig: String,
+        key: key::Key,
+        objtype: ObjType,
+    },
+
+    #[error("'{orig}' asks for parent {parent} of {key}, which only has {count}")]
+    NoSuchParent {
+        orig: String,
+        key: key::Key,
+        parent: usize,
+        count: usize,
+    },
+
+    #[error("error when getting commit: {_0}")]
+    GetObjError(#[from] GetObjError),
+
+    #[error("error when decoding commit: {_0}")]
+    DecodeError(#[from] serde_cbor::error::Error),
+}
+
+#[derive(Debug, Error)]
+pub enum ResolveError {
+    #[error(transparent)]
+    CanonicalizeError(#[from] CanonicalizeError),
+
+    #[error("error when getting object: {_0}")]
+    GetObjError(#[from] GetObjError),
+
+    #[error("error when decoding object: {_0}")]
+    DecodeError(#[from] serde_cbor::error::Error),
+
+    #[error("'{orig}' is a {actual} ({key}), but a {expected} was expected")]
+    WrongType {
+        orig: String,
+        key: key::Key,
+        expected: &'static str,
+        actual: ObjType,
+    },
+}
+
+/// Something a [`Keyish`] can be resolved to with [`DataStore::resolve`].
+pub trait Resolvable: Sized {
+    /// What this is called in errors.
+    const NAME: &'static str;
+
+    /// Turns `obj`, stored at `key`, into a key of this type, peeling it if that makes sense.
+    /// Returns `None` if it's the wrong type.
+    fn peel<DS: DataStore + ?Sized>(
+        ds: &DS,
+        key: key::Key,
+        obj: Object,
+    ) -> Result<Option<TypedKey<Self>>, ResolveError>;
+}
+
+#[derive(Debug, Error)]
+pub enum AbbreviateError {
+    #[error("error parsing key prefix: {_0}")]
+    KeyishParseError(#[from] crate::keyish::KeyishParseError),
+
+    #[error("error finding keys with the same prefix: {_0}")]
+    RawBetweenError(#[from] RawBetweenError),
+
+    #[error("error when converting db key: {_0}")]
+    FromDbKeyError(#[from] key::FromDbKeyError),
 }
 
 #[derive(Debug)]
@@ -56,6 +147,15 @@ pub enum GetReflogError {
 pub enum DSError {
     #[error("sqlite error: {_0}")]
     SqliteError(#[from] rusqlite::Error),
+
+    #[error("shard worker thread has exited")]
+    ShardWorkerGone,
+
+    #[error("no intact copy of the object could be found")]
+    NoIntactCopy,
+
+    #[error("the object was never stored")]
+    NotStored,
 }
 
 pub trait ToDSError {
@@ -94,6 +194,13 @@ pub enum RollbackTransError {
 pub enum CommitTransError {
     #[error(transparent)]
     DSerror(#[from] DSError),
+
+    #[error("commit was partial, {committed} of {total} parts were committed before: {source}")]
+    Partial {
+        committed: usize,
+        total: usize,
+        source: Box<CommitTransError>,
+    },
 }
 
 #[derive(Debug, Error)]
@@ -126,8 +233,56 @@ pub enum RawPutStateError {
     DSerror(#[from] DSError),
 }
 
+#[derive(Debug, Error)]
+pub enum RawReflogPushError {
+    #[error(transparent)]
+    DSerror(#[from] DSError),
+}
+
+#[derive(Debug, Error)]
+pub enum AppendOnlyError {
+    #[error("ref {refname} can only be moved to a descendant of {current} when append-only")]
+    NotDescendant {
+        refname: String,
+        current: key::Key,
+        new: key::Key,
+    },
+
+    #[error("object {_0} can't be replaced with different data in an append-only repository")]
+    Overwrite(key::Key),
+}
+
 #[derive(Debug, Error)]
 pub enum ReflogPushError {
+    #[error("error pushing reflog entry: {_0}")]
+    RawReflogPushError(#[from] RawReflogPushError),
+
+    #[error("error checking append-only flag: {_0}")]
+    RawGetStateError(#[from] RawGetStateError),
+
+    #[error("error getting current ref: {_0}")]
+    GetReflogError(#[from] GetReflogError),
+
+    #[error("error checking commit ancestry: {_0}")]
+    IsAncestorError(#[from] commit::IsAncestorError),
+
+    #[error(transparent)]
+    AppendOnly(#[from] AppendOnlyError),
+}
+
+#[derive(Debug, Error)]
+pub enum ReplaceError {
+    #[error("error replacing object: {_0}")]
+    RawPutError(#[from] RawPutError),
+
+    #[error("error checking append-only flag: {_0}")]
+    RawGetStateError(#[from] RawGetStateError),
+
+    #[error(transparent)]
+    AppendOnly(#[from] AppendOnlyError),
+}
+#[derive(Debug, Error)]
+pub enum RawReflogNamesError {
     #[error(transparent)]
     DSerror(#[from] DSError),
 }
@@ -156,6 +311,54 @@ pub enum GetHeadError {
     FromUtf8Error(#[from] std::string::FromUtf8Error),
 }
 
+#[derive(Debug, Error)]
+pub enum HashAlgorithmError {
+    #[error("error when getting state: {_0}")]
+    RawGetStateError(#[from] RawGetStateError),
+
+    #[error("unknown hash algorithm id {_0:?}")]
+    Unknown(Vec<u8>),
+}
+
+#[derive(Debug, Error)]
+pub enum ChunkerConfigError {
+    #[error("error when getting state: {_0}")]
+    RawGetStateError(#[from] RawGetStateError),
+
+    #[error("error decoding chunker config: {_0}")]
+    DecodeError(#[from] serde_cbor::error::Error),
+
+    #[error("stored chunker config is invalid: {_0}")]
+    Invalid(#[from] chunker::InvalidChunkerConfig),
+}
+
+#[derive(Debug, Error)]
+pub enum SetChunkerConfigError {
+    #[error("error when getting state: {_0}")]
+    RawGetStateError(#[from] RawGetStateError),
+
+    #[error("error when putting state: {_0}")]
+    RawPutStateError(#[from] RawPutStateError),
+
+    #[error("the repository already chunks differently, which can't be changed")]
+    AlreadySet,
+
+    #[error("error encoding chunker config: {_0}")]
+    EncodeError(#[from] serde_cbor::error::Error),
+
+    #[error("invalid chunker config: {_0}")]
+    Invalid(#[from] chunker::InvalidChunkerConfig),
+}
+
+#[derive(Debug, Error)]
+pub enum PutError {
+    #[error("error putting data: {_0}")]
+    RawPutError(#[from] RawPutError),
+
+    #[error("error getting hash algorithm: {_0}")]
+    HashAlgorithmError(#[from] HashAlgorithmError),
+}
+
 #[derive(Debug, Error)]
 pub enum GetObjError {
     #[error("error getting object: {_0}")]
@@ -168,7 +371,7 @@ pub enum GetObjError {
 #[derive(Debug, Error)]
 pub enum PutObjError {
     #[error("error putting object: {_0}")]
-    RawPutError(#[from] RawPutError),
+    PutError(#[from] PutError),
 
     #[error("error encoding object: {_0}")]
     EncodeError(#[from] serde_cbor::error::Error),
@@ -186,11 +389,28 @@ pub trait Transactional {
     }
 }
 
+const APPEND_ONLY_STATE_KEY: &[u8] = b"append_only";
+const HASH_ALGORITHM_STATE_KEY: &[u8] = b"hash_algorithm";
+const CHUNKER_STATE_KEY: &[u8] = b"chunker";
+
+/// The state key recording what the object at `db_key` was rehashed to.
+pub(crate) fn rehash_state_key(db_key: &[u8]) -> Vec<u8> {
+    [&b"rehash/"[..], db_key].concat()
+}
+
 static_assertions::assert_obj_safe!(DataStore);
+/// A content addressed store, along with some mutable state (HEAD, the reflog).
+///
+/// The `raw_*` methods are what a backend implements, and do no checking. Everything else is built
+/// on top of them, and is where repository level rules (like append-only mode) are enforced.
 pub trait DataStore: Transactional {
     fn raw_get<'a>(&'a self, key: &[u8]) -> Result<Cow<'a, [u8]>, RawGetError>;
     fn raw_put<'a>(&'a self, key: &[u8], data: &[u8]) -> Result<(), RawPutError>;
 
+    /// Like [`raw_put`](DataStore::raw_put), but overwrites any existing value. This is only
+    /// meant for repairing corrupt objects, normal puts should never need to replace anything.
+    fn raw_replace(&self, key: &[u8], data: &[u8]) -> Result<(), RawPutError>;
+
     fn raw_exists(&self, key: &[u8]) -> Result<bool, RawExistsError>;
 
     fn raw_get_state<'a>(&'a self, key: &[u8]) -> Result<Option<Vec<u8>>, RawGetStateError>;
@@ -202,13 +422,62 @@ pub trait DataStore: Transactional {
         Ok(results)
     }
 
-    fn hash(&self, data: &[u8]) -> key::Key {
-        let b3 = hash(data);
-        key::Key::Blake3B(*b3.as_bytes())
+    /// The algorithm used to hash new objects. Repositories default to BLAKE3 until
+    /// [`set_hash_algorithm`](DataStore::set_hash_algorithm) says otherwise.
+    fn hash_algorithm(&self) -> Result<key::HashAlgorithm, HashAlgorithmError> {
+        match self.raw_get_state(HASH_ALGORITHM_STATE_KEY)? {
+            None => Ok(key::HashAlgorithm::Blake3B),
+            Some(id) => match id[..] {
+                [hash_id] => {
+                    key::HashAlgorithm::from_hash_id(hash_id).ok_or(HashAlgorithmError::Unknown(id))
+                }
+                _ => Err(HashAlgorithmError::Unknown(id)),
+            },
+        }
     }
 
-    fn put(&self, data: Vec<u8>) -> Result<key::Key, RawPutError> {
-        let keybuf = self.hash(&data);
+    /// Changes the algorithm for new objects. Existing objects keep their keys, see
+    /// [`rehash`](crate::rehash::rehash) to convert them.
+    fn set_hash_algorithm(&self, algorithm: key::HashAlgorithm) -> Result<(), RawPutStateError> {
+        self.raw_put_state(HASH_ALGORITHM_STATE_KEY, &[algorithm.hash_id()])
+    }
+
+    /// How file data is chunked. Repositories without a stored config use the default.
+    fn chunker_config(&self) -> Result<ChunkerConfig, ChunkerConfigError> {
+        match self.raw_get_state(CHUNKER_STATE_KEY)? {
+            None => Ok(ChunkerConfig::default()),
+            Some(bytes) => {
+                let config: ChunkerConfig = serde_cbor::from_slice(&bytes)?;
+                config.validate()?;
+                Ok(config)
+            }
+        }
+    }
+
+    /// Records how file data is chunked. This can only be set once, at `init`: changing it later
+    /// would mean the same files get different keys, so setting a different config is an error.
+    fn set_chunker_config(&self, config: &ChunkerConfig) -> Result<(), SetChunkerConfigError> {
+        config.validate()?;
+
+        let encoded = canonical::to_vec(config)?;
+
+        match self.raw_get_state(CHUNKER_STATE_KEY)? {
+            Some(existing) if existing == encoded => return Ok(()),
+            Some(_) => return Err(SetChunkerConfigError::AlreadySet),
+            None => {}
+        }
+
+        self.raw_put_state(CHUNKER_STATE_KEY, &encoded)?;
+
+        Ok(())
+    }
+
+    fn hash(&self, data: &[u8]) -> Result<key::Key, HashAlgorithmError> {
+        Ok(self.hash_algorithm()?.hash(data))
+    }
+
+    fn put(&self, data: Vec<u8>) -> Result<key::Key, PutError> {
+        let keybuf = self.hash(&data)?;
 
         self.raw_put(&keybuf.as_db_key(), &data)?;
 
@@ -229,7 +498,56 @@ pub trait DataStore: Transactional {
         Ok(())
     }
 
-    fn reflog_push(&self, data: &Reflog) -> Result<(), ReflogPushError>;
+    fn raw_reflog_push(&self, data: &Reflog) -> Result<(), RawReflogPushError>;
+
+    /// Whether this repository is append-only.
+    ///
+    /// In an append-only repository, refs can only move forwards to descendants of where they
+    /// are now, and objects can't be replaced with anything but their own data. Once set, this
+    /// can't be turned off: any value stored for the flag counts as set.
+    fn is_append_only(&self) -> Result<bool, RawGetStateError> {
+        Ok(self.raw_get_state(APPEND_ONLY_STATE_KEY)?.is_some())
+    }
+
+    fn set_append_only(&self) -> Result<(), RawPutStateError> {
+        self.raw_put_state(APPEND_ONLY_STATE_KEY, b"1")
+    }
+
+    fn reflog_push(&self, data: &Reflog) -> Result<(), ReflogPushError> {
+        if self.is_append_only()? {
+            match self.reflog_get(&data.refname, data.remote.as_deref()) {
+                Ok(current) => {
+                    if !commit::is_ancestor(self, current, data.key)? {
+                        return Err(AppendOnlyError::NotDescendant {
+                            refname: data.refname.clone(),
+                            current: current.inner(),
+                            new: data.key.inner(),
+                        }
+                        .into());
+                    }
+                }
+                Err(GetReflogError::NotFound) => {}
+                Err(e) => return Err(e.into()),
+            }
+        }
+
+        self.raw_reflog_push(data)?;
+
+        Ok(())
+    }
+
+    /// Overwrites the stored data for `key`. Only used for repairs, in append-only repositories
+    /// `data` must hash to `key`.
+    fn replace(&self, key: key::Key, data: &[u8]) -> Result<(), ReplaceError> {
+        if self.is_append_only()? && !key.verify(data) {
+            return Err(AppendOnlyError::Overwrite(key).into());
+        }
+
+        self.raw_replace(&key.as_db_key(), data)?;
+
+        Ok(())
+    }
+
     fn reflog_get(
         &self,
         refname: &str,
@@ -241,6 +559,9 @@ pub trait DataStore: Transactional {
         remote: Option<&str>,
     ) -> Result<Vec<TypedKey<commit::Commit>>, WalkReflogError>;
 
+    /// Every ref with a reflog, as `(refname, remote)` pairs.
+    fn reflog_names(&self) -> Result<Vec<(String, Option<String>)>, RawReflogNamesError>;
+
     fn raw_between(
         &self,
         start: &[u8],
@@ -256,13 +577,31 @@ pub trait DataStore: Transactional {
             Keyish::Key(s, key) => {
                 err_str = s;
 
-                let k = self.raw_get(&key).unwrap();
-                results.push(k.to_vec());
+                let algorithm = self.hash_algorithm()?;
+                let current =
+                    matches!(key::Key::from_db_key(&key), Ok(k) if k.algorithm() == algorithm);
+
+                // A key in the current algorithm is itself. Other full keys from before a rehash
+                // lead to the rewritten object.
+                if current && self.raw_exists(&key)? {
+                    results.push(key);
+                } else if let Some(new_key) = self.raw_get_state(&rehash_state_key(&key))? {
+                    results.push(new_key);
+                } else if self.raw_exists(&key)? {
+                    results.push(key);
+                }
             }
             Keyish::Range(s, start, end) => {
+                // Like git, a ref wins over the key prefix its name also spells, like `bar`.
+                match self.reflog_get(&s, None) {
+                    Ok(key) => return Ok(key.inner()),
+                    Err(GetReflogError::NotFound) => {}
+                    Err(e) => return Err(e.into()),
+                }
+
                 err_str = s;
 
-                results = self.raw_between(&start, end.as_deref()).unwrap();
+                results = self.raw_between(&start, end.as_deref())?;
             }
             Keyish::Reflog {
                 orig,
@@ -273,6 +612,32 @@ pub trait DataStore: Transactional {
                 Err(GetReflogError::NotFound) => return Err(CanonicalizeError::NotFound(orig)),
                 Err(e) => return Err(e.into()),
             },
+            Keyish::Head { orig } => {
+                let head = self.get_head()?.ok_or(CanonicalizeError::NoHead)?;
+
+                return match self.reflog_get(&head, None) {
+                    Ok(key) => Ok(key.inner()),
+                    Err(GetReflogError::NotFound) => Err(CanonicalizeError::NotFound(orig)),
+                    Err(e) => Err(e.into()),
+                };
+            }
+            Keyish::Relative { orig, base, steps } => {
+                let mut key = self.canonicalize(*base)?;
+
+                for step in steps {
+                    key = match step {
+                        ParentStep::FirstParent(n) => {
+                            for _ in 0..n {
+                                key = nth_parent(self, &orig, key, 1)?;
+                            }
+                            key
+                        }
+                        ParentStep::Parent(n) => nth_parent(self, &orig, key, n)?,
+                    };
+                }
+
+                return Ok(key);
+            }
         };
 
         match results.len() {
@@ -290,19 +655,108 @@ pub trait DataStore: Transactional {
         }
     }
 
+    /// Like [`canonicalize`](DataStore::canonicalize), but checks the object is a `T`, peeling it
+    /// (say a commit to its tree) if needed.
+    fn resolve<T: Resolvable>(&self, search: Keyish) -> Result<TypedKey<T>, ResolveError>
+    where
+        Self: Sized,
+    {
+        let orig = search.orig().to_string();
+        let key = self.canonicalize(search)?;
+        let obj = self.get_obj(key)?;
+        let actual = obj.objtype();
+
+        T::peel(self, key, obj)?.ok_or(ResolveError::WrongType {
+            orig,
+            key,
+            expected: T::NAME,
+            actual,
+        })
+    }
+
+    /// Finds the shortest non-empty prefix of `key` (but at least `min_len` characters long)
+    /// that [`canonicalize`](DataStore::canonicalize) will resolve back to `key` and nothing else.
+    fn abbreviate(&self, key: key::Key, min_len: usize) -> Result<String, AbbreviateError> {
+        let full = key.as_user_key();
+        let min_len = min_len.max(1).min(full.len());
+
+        let (start, end) = match full[..min_len].parse()? {
+            Keyish::Range(_, start, end) => (start, end),
+            _ => return Ok(full),
+        };
+
+        // Everything that could be confused with `key` at `min_len` is in this range, so we just
+        // need to be one character longer than the longest prefix shared with any of them.
+        let mut needed = min_len;
+
+        for other in self.raw_between(&start, end.as_deref())? {
+            let other = key::Key::from_db_key(&other)?.as_user_key();
+
+            if other == full {
+                continue;
+            }
+
+            let common = full
+                .bytes()
+                .zip(other.bytes())
+                .take_while(|(a, b)| a == b)
+                .count();
+
+            needed = needed.max(common + 1);
+        }
+
+        Ok(full[..needed.min(full.len())].to_string())
+    }
+
     fn get_obj(&self, key: key::Key) -> Result<Object, GetObjError> {
         let data = self.get(key)?;
 
+        // Whether it's canonically encoded is left to fsck, re-encoding every read is too slow.
         Ok(serde_cbor::from_slice(&data)?)
     }
 
     fn put_obj(&self, data: &Object) -> Result<key::Key, PutObjError> {
-        let data = serde_cbor::to_vec(data)?;
+        let data = canonical::to_vec(data)?;
 
         Ok(self.put(data)?)
     }
 }
 
+/// Gets the `n`th parent (counting from 1) of the commit `key`, or `key` itself if `n` is 0.
+fn nth_parent<DS: DataStore + ?Sized>(
+    ds: &DS,
+    orig: &str,
+    key: key::Key,
+    n: usize,
+) -> Result<key::Key, CanonicalizeError> {
+    let obj = ds.get_obj(key)?;
+
+    if !matches!(obj.objtype(), ObjType::Commit) {
+        return Err(CanonicalizeError::NotACommit {
+            orig: orig.to_string(),
+            key,
+            objtype: obj.objtype(),
+        });
+    }
+
+    if n == 0 {
+        return Ok(key);
+    }
+
+    let commit: commit::Commit = obj.try_into()?;
+    let parents = commit.parents();
+
+    match parents.get(n - 1) {
+        Some(p) => Ok(p.inner()),
+        None => Err(CanonicalizeError::NoSuchParent {
+            orig: orig.to_string(),
+            key,
+            parent: n,
+            count: parents.len(),
+        }),
+    }
+}
+
 #[derive(Debug, Error)]
 pub enum WalkReflogError {
     #[error("error parsing db key: {_0}")]
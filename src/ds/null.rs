@@ -1,15 +1,30 @@
+use crate::chunker::ChunkerConfig;
 use crate::commit;
 use crate::ds;
 use crate::ds::{
-    GetReflogError, RawBetweenError, RawExistsError, RawGetError, RawGetStateError, RawPutError,
-    RawPutStateError, ReflogPushError, WalkReflogError,
+    ChunkerConfigError, GetReflogError, HashAlgorithmError, RawBetweenError, RawExistsError,
+    RawGetError, RawGetStateError, RawPutError, RawPutStateError, RawReflogNamesError,
+    RawReflogPushError, WalkReflogError,
 };
-use crate::key::TypedKey;
+use crate::key::{HashAlgorithm, TypedKey};
 use crate::Reflog;
 use std::borrow::Cow;
 
-#[derive(Debug)]
-pub struct NullDS;
+/// A store that keeps nothing, for working out what keys would be without storing anything.
+#[derive(Debug, Default)]
+pub struct NullDS {
+    hash_algorithm: HashAlgorithm,
+    chunker_config: ChunkerConfig,
+}
+
+impl NullDS {
+    pub fn new(hash_algorithm: HashAlgorithm, chunker_config: ChunkerConfig) -> Self {
+        Self {
+            hash_algorithm,
+            chunker_config,
+        }
+    }
+}
 
 impl ds::Transactional for NullDS {}
 
@@ -20,16 +35,26 @@ impl crate::DataStore for NullDS {
     fn raw_put<'a>(&'a self, _key: &[u8], _data: &[u8]) -> Result<(), RawPutError> {
         Ok(())
     }
+    fn raw_replace(&self, _key: &[u8], _data: &[u8]) -> Result<(), RawPutError> {
+        Ok(())
+    }
     fn raw_exists(&self, _key: &[u8]) -> Result<bool, RawExistsError> {
         unimplemented!("null datastore, no data")
     }
     fn raw_get_state<'a>(&'a self, _key: &[u8]) -> Result<Option<Vec<u8>>, RawGetStateError> {
-        unimplemented!("null datastore, no data")
+        Ok(None)
+    }
+    fn hash_algorithm(&self) -> Result<HashAlgorithm, HashAlgorithmError> {
+        Ok(self.hash_algorithm)
+    }
+    fn chunker_config(&self) -> Result<ChunkerConfig, ChunkerConfigError> {
+        Ok(self.chunker_config)
     }
+
     fn raw_put_state<'a>(&'a self, _key: &[u8], _data: &[u8]) -> Result<(), RawPutStateError> {
         Ok(())
     }
-    fn reflog_push(&self, _data: &Reflog) -> Result<(), ReflogPushError> {
+    fn raw_reflog_push(&self, _data: &Reflog) -> Result<(), RawReflogPushError> {
         Ok(())
     }
     fn reflog_get(
@@ -47,6 +72,10 @@ impl crate::DataStore for NullDS {
         unimplemented!("null datastore, no data")
     }
 
+    fn reflog_names(&self) -> Result<Vec<(String, Option<String>)>, RawReflogNamesError> {
+        Ok(vec![])
+    }
+
     fn raw_between(
         &self,
         _start: &[u8],
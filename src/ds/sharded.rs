@@ -0,0 +1,440 @@
+use std::borrow::Cow;
+use std::path::Path;
+use std::sync::{mpsc, Arc, Mutex};
+use std::thread;
+
+use crate::commit;
+use crate::ds::sqlite::{NewSqliteError, SqliteDS};
+use crate::ds::{
+    BeginTransError, CommitTransError, DSError, DataStore, GetReflogError, RawBetweenError,
+    RawExistsError, RawGetError, RawGetStateError, RawPutError, RawPutStateError,
+    RawReflogNamesError, RawReflogPushError, RollbackTransError, Transactional, WalkReflogError,
+};
+use crate::key::TypedKey;
+use crate::Reflog;
+use thiserror::Error;
+
+type Job = Box<dyn FnOnce(&mut SqliteDS) + Send>;
+
+/// A single shard, owned by its own writer thread.
+///
+/// Every operation is sent down `jobs` and run in order, so a read after a put on the same shard
+/// will always see the put. Puts don't wait for a reply; if one fails, the error is stashed in
+/// `failed` and returned from the next operation on this shard.
+#[derive(Debug)]
+struct Shard {
+    jobs: Option<mpsc::Sender<Job>>,
+    handle: Option<thread::JoinHandle<()>>,
+    failed: Arc<Mutex<Option<DSError>>>,
+}
+
+impl Shard {
+    fn spawn(idx: usize, mut ds: SqliteDS) -> Result<Self, std::io::Error> {
+        let (tx, rx) = mpsc::channel::<Job>();
+
+        let handle = thread::Builder::new()
+            .name(format!("snapcd-shard-{}", idx))
+            .spawn(move || {
+                for job in rx {
+                    job(&mut ds);
+                }
+            })?;
+
+        Ok(Self {
+            jobs: Some(tx),
+            handle: Some(handle),
+            failed: Arc::new(Mutex::new(None)),
+        })
+    }
+
+    fn take_failure(&self) -> Result<(), DSError> {
+        let mut failed = self.failed.lock().map_err(|_| DSError::ShardWorkerGone)?;
+
+        match failed.take() {
+            Some(e) => Err(e),
+            None => Ok(()),
+        }
+    }
+
+    fn send(&self, job: Job) -> Result<(), DSError> {
+        self.jobs
+            .as_ref()
+            .ok_or(DSError::ShardWorkerGone)?
+            .send(job)
+            .map_err(|_| DSError::ShardWorkerGone)
+    }
+
+    /// Runs `f` on the shard's thread and waits for the result.
+    fn call<T: Send + 'static>(
+        &self,
+        f: impl FnOnce(&mut SqliteDS) -> T + Send + 'static,
+    ) -> Result<T, DSError> {
+        self.take_failure()?;
+
+        let (tx, rx) = mpsc::channel();
+
+        self.send(Box::new(move |ds| {
+            // If the receiver is gone, nobody cares about the result.
+            let _ = tx.send(f(ds));
+        }))?;
+
+        rx.recv().map_err(|_| DSError::ShardWorkerGone)
+    }
+
+    /// Queues a put without waiting for it to complete.
+    fn put(&self, key: Vec<u8>, data: Vec<u8>) -> Result<(), DSError> {
+        self.take_failure()?;
+
+        let failed = Arc::clone(&self.failed);
+
+        self.send(Box::new(move |ds| {
+            if let Err(RawPutError::DSerror(e)) = ds.raw_put(&key, &data) {
+                if let Ok(mut f) = failed.lock() {
+                    f.get_or_insert(e);
+                }
+            }
+        }))
+    }
+
+    /// Waits for all queued puts to finish, returning the first error any of them hit.
+    fn flush(&self) -> Result<(), DSError> {
+        self.call(|_| ())?;
+        self.take_failure()
+    }
+}
+
+impl Drop for Shard {
+    fn drop(&mut self) {
+        // Hang up first so the worker's loop ends, then wait for it to finish what it has queued.
+        self.jobs = None;
+
+        if let Some(handle) = self.handle.take() {
+            let _ = handle.join();
+        }
+    }
+}
+
+/// A [`DataStore`] that spreads objects across several [`SqliteDS`] files.
+///
+/// Objects are routed by the first byte of their hash, so the shard count must never change for
+/// a given set of files (it's recorded in the first shard, and checked on open). State and the
+/// reflog are only ever stored in the first shard.
+///
+/// Commits aren't atomic across shards: each is committed on its own, the first last. See
+/// [`ShardedDS::commit`](Transactional::commit).
+#[derive(Debug)]
+pub struct ShardedDS {
+    shards: Vec<Shard>,
+}
+
+#[derive(Debug, Error)]
+pub enum NewShardedError {
+    #[error("error opening shard: {_0}")]
+    NewSqliteError(#[from] NewSqliteError),
+
+    #[error("error spawning shard thread: {_0}")]
+    SpawnError(#[from] std::io::Error),
+
+    #[error("shard count must be between 1 and 256, got {_0}")]
+    InvalidShardCount(usize),
+
+    #[error("store was created with {expected} shards, but opened with {got}")]
+    ShardCountMismatch { expected: usize, got: usize },
+
+    #[error("error reading shard count: {_0}")]
+    RawGetStateError(#[from] RawGetStateError),
+
+    #[error("error writing shard count: {_0}")]
+    RawPutStateError(#[from] RawPutStateError),
+}
+
+const SHARD_COUNT_STATE_KEY: &[u8] = b"shard_count";
+
+impl ShardedDS {
+    /// Opens (or creates) `shard_count` databases named `shard-<n>.db` inside `dir`.
+    pub fn new<S: AsRef<Path>>(dir: S, shard_count: usize) -> Result<Self, NewShardedError> {
+        if shard_count == 0 || shard_count > 256 {
+            return Err(NewShardedError::InvalidShardCount(shard_count));
+        }
+
+        let mut shards = Vec::with_capacity(shard_count);
+
+        for idx in 0..shard_count {
+            let path = dir.as_ref().join(format!("shard-{}.db", idx));
+            shards.push(SqliteDS::new(path)?);
+        }
+
+        Self::from_shards(shards)
+    }
+
+    /// Builds a store out of already opened databases. The order of `shards` matters, and must
+    /// be the same each time.
+    pub fn from_shards(shards: Vec<SqliteDS>) -> Result<Self, NewShardedError> {
+        let shard_count = shards.len();
+
+        if shard_count == 0 || shard_count > 256 {
+            return Err(NewShardedError::InvalidShardCount(shard_count));
+        }
+
+        // Check this before anything gets moved off to another thread, it's easier to do here.
+        match shards[0].raw_get_state(SHARD_COUNT_STATE_KEY)? {
+            Some(stored) => {
+                let expected = stored.first().map_or(0, |&x| usize::from(x) + 1);
+
+                if expected != shard_count {
+                    return Err(NewShardedError::ShardCountMismatch {
+                        expected,
+                        got: shard_count,
+                    });
+                }
+            }
+            None => {
+                // Stored as count - 1 so it fits in a single byte.
+                let stored = (shard_count - 1) as u8;
+                shards[0].raw_put_state(SHARD_COUNT_STATE_KEY, &[stored])?;
+            }
+        }
+
+        let mut spawned = Vec::with_capacity(shard_count);
+
+        for (idx, ds) in shards.into_iter().enumerate() {
+            spawned.push(Shard::spawn(idx, ds)?);
+        }
+
+        Ok(Self { shards: spawned })
+    }
+
+    pub fn shard_count(&self) -> usize {
+        self.shards.len()
+    }
+
+    fn state_shard(&self) -> &Shard {
+        &self.shards[0]
+    }
+
+    fn shard_for(&self, key: &[u8]) -> &Shard {
+        // The first byte of a db key is the hash id, so skip over it.
+        let byte = key.get(1).copied().unwrap_or(0);
+
+        &self.shards[usize::from(byte) % self.shards.len()]
+    }
+}
+
+impl Transactional for ShardedDS {
+    fn begin_trans(&mut self) -> Result<(), BeginTransError> {
+        for shard in &self.shards {
+            shard.call(|ds| ds.begin_trans())??;
+        }
+
+        Ok(())
+    }
+
+    /// Commits each shard in turn, which isn't atomic. If one fails, it and the ones after it are
+    /// rolled back, but the ones before stay committed, and [`CommitTransError::Partial`] says
+    /// how many. Those only ever hold objects, never state or refs, so what's left is unreferenced
+    /// objects rather than a broken store.
+    fn commit(&mut self) -> Result<(), CommitTransError> {
+        // Make sure every queued put has actually landed before committing anything, so we only
+        // commit if all shards are known to be good.
+        let flushed: Result<(), DSError> = self.shards.iter().try_for_each(Shard::flush);
+
+        if let Err(e) = flushed {
+            for shard in &self.shards {
+                if let Err(rollback_err) = shard.call(|ds| ds.rollback()) {
+                    log::warn!(
+                        "error rolling back shard after failed flush: {}",
+                        rollback_err
+                    );
+                }
+            }
+
+            return Err(e.into());
+        }
+
+        // The state shard goes last. Objects are content addressed, so if we fail halfway through
+        // we end up with some unreferenced objects, but never a ref pointing to missing data.
+        let order: Vec<&Shard> = self
+            .shards
+            .iter()
+            .skip(1)
+            .chain(std::iter::once(self.state_shard()))
+            .collect();
+
+        for (committed, shard) in order.iter().enumerate() {
+            let result = shard
+                .call(|ds| ds.commit())
+                .map_err(CommitTransError::from)
+                .and_then(|r| r);
+
+            if let Err(e) = result {
+                // Including the one that failed, in case its transaction is still open.
+                for rest in &order[committed..] {
+                    match rest.call(|ds| ds.rollback()) {
+                        Ok(Ok(())) => {}
+                        Ok(Err(RollbackTransError::DSerror(rollback_err))) | Err(rollback_err) => {
+                            log::warn!(
+                                "error rolling back shard after failed commit: {}",
+                                rollback_err
+                            );
+                        }
+                    }
+                }
+
+                if committed == 0 {
+                    return Err(e);
+                }
+
+                return Err(CommitTransError::Partial {
+                    committed,
+                    total: order.len(),
+                    source: Box::new(e),
+                });
+            }
+        }
+
+        Ok(())
+    }
+
+    fn rollback(&mut self) -> Result<(), RollbackTransError> {
+        let mut first_err = None;
+
+        // Try every shard even if one fails, leaving any open is worse.
+        for shard in &self.shards {
+            let result = shard
+                .take_failure()
+                .and_then(|()| shard.call(|ds| ds.rollback()));
+
+            match result {
+                Ok(Ok(())) => {}
+                Ok(Err(RollbackTransError::DSerror(e))) | Err(e) => {
+                    first_err.get_or_insert(e);
+                }
+            }
+        }
+
+        match first_err {
+            Some(e) => Err(e.into()),
+            None => Ok(()),
+        }
+    }
+}
+
+impl DataStore for ShardedDS {
+    fn raw_get<'a>(&'a self, key: &[u8]) -> Result<Cow<'a, [u8]>, RawGetError> {
+        let owned_key = key.to_vec();
+
+        let data = self
+            .shard_for(key)
+            .call(move |ds| ds.raw_get(&owned_key).map(Cow::into_owned))??;
+
+        Ok(Cow::Owned(data))
+    }
+
+    fn raw_put(&self, key: &[u8], data: &[u8]) -> Result<(), RawPutError> {
+        self.shard_for(key).put(key.to_vec(), data.to_vec())?;
+
+        Ok(())
+    }
+
+    fn raw_replace(&self, key: &[u8], data: &[u8]) -> Result<(), RawPutError> {
+        let owned_key = key.to_vec();
+        let owned_data = data.to_vec();
+
+        self.shard_for(key)
+            .call(move |ds| ds.raw_replace(&owned_key, &owned_data))?
+    }
+
+    fn raw_exists(&self, key: &[u8]) -> Result<bool, RawExistsError> {
+        let owned_key = key.to_vec();
+
+        self.shard_for(key)
+            .call(move |ds| ds.raw_exists(&owned_key))?
+    }
+
+    fn raw_get_state(&self, key: &[u8]) -> Result<Option<Vec<u8>>, RawGetStateError> {
+        let owned_key = key.to_vec();
+
+        self.state_shard()
+            .call(move |ds| ds.raw_get_state(&owned_key))?
+    }
+
+    fn raw_put_state(&self, key: &[u8], data: &[u8]) -> Result<(), RawPutStateError> {
+        let owned_key = key.to_vec();
+        let owned_data = data.to_vec();
+
+        self.state_shard()
+            .call(move |ds| ds.raw_put_state(&owned_key, &owned_data))?
+    }
+
+    fn raw_reflog_push(&self, data: &Reflog) -> Result<(), RawReflogPushError> {
+        let log = Reflog {
+            refname: data.refname.clone(),
+            key: data.key,
+            remote: data.remote.clone(),
+        };
+
+        self.state_shard()
+            .call(move |ds| ds.raw_reflog_push(&log))?
+    }
+
+    fn reflog_get(
+        &self,
+        refname: &str,
+        remote: Option<&str>,
+    ) -> Result<TypedKey<commit::Commit>, GetReflogError> {
+        let refname = refname.to_string();
+        let remote = remote.map(ToString::to_string);
+
+        self.state_shard()
+            .call(move |ds| ds.reflog_get(&refname, remote.as_deref()))?
+    }
+
+    fn reflog_walk(
+        &self,
+        refname: &str,
+        remote: Option<&str>,
+    ) -> Result<Vec<TypedKey<commit::Commit>>, WalkReflogError> {
+        let refname = refname.to_string();
+        let remote = remote.map(ToString::to_string);
+
+        self.state_shard()
+            .call(move |ds| ds.reflog_walk(&refname, remote.as_deref()))?
+    }
+
+    fn reflog_names(&self) -> Result<Vec<(String, Option<String>)>, RawReflogNamesError> {
+        self.state_shard().call(|ds| ds.reflog_names())?
+    }
+
+    fn raw_between(
+        &self,
+        start: &[u8],
+        end: Option<&[u8]>,
+    ) -> Result<Vec<Vec<u8>>, RawBetweenError> {
+        // Send the query to every shard first so they all run at the same time, then collect.
+        let mut pending = Vec::with_capacity(self.shards.len());
+
+        for shard in &self.shards {
+            shard.take_failure()?;
+
+            let (tx, rx) = mpsc::channel();
+            let start = start.to_vec();
+            let end = end.map(<[u8]>::to_vec);
+
+            shard.send(Box::new(move |ds| {
+                let _ = tx.send(ds.raw_between(&start, end.as_deref()));
+            }))?;
+
+            pending.push(rx);
+        }
+
+        let mut results = Vec::new();
+
+        for rx in pending {
+            results.extend(rx.recv().map_err(|_| DSError::ShardWorkerGone)??);
+        }
+
+        results.sort_unstable();
+
+        Ok(results)
+    }
+}
@@ -8,8 +8,8 @@ use crate::commit;
 use crate::ds;
 use crate::ds::{
     BeginTransError, CommitTransError, DataStore, GetReflogError, RawBetweenError, RawExistsError,
-    RawGetError, RawGetStateError, RawPutError, RawPutStateError, ReflogPushError,
-    RollbackTransError, WalkReflogError,
+    RawGetError, RawGetStateError, RawPutError, RawPutStateError, RawReflogNamesError,
+    RawReflogPushError, RollbackTransError, WalkReflogError,
 };
 use crate::ds::{ToDSError, ToDSErrorResult};
 use crate::key::{Key, TypedKey};
@@ -58,6 +58,17 @@ impl SqliteDS {
 
         Ok(Self { conn })
     }
+
+    /// Opens an existing database for reading only, e.g. to give each thread of a
+    /// [`restore`](crate::restore) its own connection.
+    pub fn open_read_only<S: AsRef<Path>>(path: S) -> Result<Self, NewSqliteError> {
+        let conn = rusqlite::Connection::open_with_flags(
+            path,
+            rusqlite::OpenFlags::SQLITE_OPEN_READ_ONLY | rusqlite::OpenFlags::SQLITE_OPEN_NO_MUTEX,
+        )?;
+
+        Ok(Self { conn })
+    }
 }
 
 impl ds::Transactional for SqliteDS {
@@ -106,7 +117,7 @@ impl DataStore for SqliteDS {
         }
     }
 
-    fn reflog_push(&self, data: &Reflog) -> Result<(), ReflogPushError> {
+    fn raw_reflog_push(&self, data: &Reflog) -> Result<(), RawReflogPushError> {
         self.conn
             .execute(
                 "INSERT INTO reflog(refname, remote, key) VALUES (?, ?, ?)",
@@ -139,6 +150,25 @@ impl DataStore for SqliteDS {
         Ok(keys)
     }
 
+    fn reflog_names(&self) -> Result<Vec<(String, Option<String>)>, RawReflogNamesError> {
+        let mut statement = self
+            .conn
+            .prepare("SELECT DISTINCT refname, remote FROM reflog ORDER BY refname, remote")
+            .to_ds_r()?;
+
+        let rows = statement
+            .query_map(params![], |row| Ok((row.get(0)?, row.get(1)?)))
+            .to_ds_r()?;
+
+        let mut names = Vec::new();
+
+        for row in rows {
+            names.push(row.to_ds_r()?);
+        }
+
+        Ok(names)
+    }
+
     fn raw_get<'a>(&'a self, key: &[u8]) -> Result<Cow<'a, [u8]>, RawGetError> {
         let results: Vec<u8> = self
             .conn
@@ -160,6 +190,17 @@ impl DataStore for SqliteDS {
         Ok(())
     }
 
+    fn raw_replace(&self, key: &[u8], data: &[u8]) -> Result<(), RawPutError> {
+        self.conn
+            .execute(
+                "INSERT OR REPLACE INTO data VALUES (?, ?)",
+                params![key, data],
+            )
+            .to_ds_r()?;
+
+        Ok(())
+    }
+
     fn raw_get_state<'a>(&'a self, key: &[u8]) -> Result<Option<Vec<u8>>, RawGetStateError> {
         let results: Result<Option<Vec<u8>>, _> = self
             .conn
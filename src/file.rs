@@ -1,9 +1,45 @@
+use crate::canonical;
+use crate::chunker::{self, ChunkerAlgorithm, ChunkerConfig};
 use crate::ds;
 use crate::object::ObjType;
 use crate::{ds::DataStore, key::Key, object::Object};
+use std::collections::HashMap;
 use std::io::prelude::*;
+use std::io::SeekFrom;
 use thiserror::Error;
 
+/// The data section of a `FileBlobTree`.
+///
+/// Trees written before sizes were recorded have an empty data section instead. They can still be
+/// read, but finding an offset in them means reading every blob before it.
+#[derive(Debug, serde::Serialize, serde::Deserialize)]
+struct BlobTreeSizes {
+    /// Where each child ends, relative to the start of the tree.
+    ends: Vec<u64>,
+}
+
+/// Puts a `FileBlobTree` over `keys`, whose data is `sizes` bytes long, returning its key and size.
+fn put_tree<DS: DataStore>(
+    ds: &mut DS,
+    keys: &[Key],
+    sizes: &[u64],
+) -> Result<(Key, u64), PutDataError> {
+    let ends: Vec<u64> = sizes
+        .iter()
+        .scan(0, |total, size| {
+            *total += size;
+            Some(*total)
+        })
+        .collect();
+
+    let size = ends.last().copied().unwrap_or(0);
+    let data = canonical::to_vec(&BlobTreeSizes { ends })?;
+
+    let key = ds.put_obj(&Object::new(&data, keys, ObjType::FileBlobTree))?;
+
+    Ok((key, size))
+}
+
 #[derive(Debug, Error)]
 pub enum PutDataError {
     #[error("error putting object: {_0}")]
@@ -11,24 +47,509 @@ pub enum PutDataError {
 
     #[error("io error: {_0}")]
     IOError(#[from] std::io::Error),
+
+    #[error("error encoding blob tree sizes: {_0}")]
+    EncodeError(#[from] serde_cbor::error::Error),
+}
+
+/// Gathers blobs into trees as they're cut, closing a tree at each level when the hash at the cut
+/// has enough zeros, or it has too many children.
+struct TreeBuilder {
+    key_bufs: [Vec<Key>; 5],
+    size_bufs: [Vec<u64>; 5],
+    per_level_count: u32,
+    per_level_count_max: u32,
+}
+
+impl TreeBuilder {
+    fn new(config: &ChunkerConfig) -> Self {
+        Self {
+            key_bufs: Default::default(),
+            size_bufs: Default::default(),
+            per_level_count: config.per_level_count,
+            per_level_count_max: config.per_level_count_max,
+        }
+    }
+
+    /// Stores `blob`, where the hash at its cut had `zeros` more zero bits than a cut needs.
+    fn push_blob<DS: DataStore>(
+        &mut self,
+        ds: &mut DS,
+        blob: &[u8],
+        zeros: u32,
+    ) -> Result<(), PutDataError> {
+        let key = ds.put_obj(&Object::new(blob, &[], ObjType::FileBlob))?;
+
+        self.push_leaf(ds, key, blob.len() as u64, zeros)
+    }
+
+    /// Stores a run of `len` zeros. There's no hash, so this only closes trees that are full.
+    fn push_zero_run<DS: DataStore>(&mut self, ds: &mut DS, len: u64) -> Result<(), PutDataError> {
+        let data = canonical::to_vec(&len)?;
+        let key = ds.put_obj(&Object::new(&data, &[], ObjType::ZeroRun))?;
+
+        self.push_leaf(ds, key, len, 0)
+    }
+
+    fn push_leaf<DS: DataStore>(
+        &mut self,
+        ds: &mut DS,
+        key: Key,
+        size: u64,
+        zeros: u32,
+    ) -> Result<(), PutDataError> {
+        self.key_bufs[0].push(key);
+        self.size_bufs[0].push(size);
+
+        for offset in 0..4 {
+            let len = self.key_bufs[offset as usize].len();
+            if zeros > (offset + 1) * self.per_level_count || len >= 1 << self.per_level_count_max {
+                let (key, size) = put_tree(
+                    ds,
+                    &self.key_bufs[offset as usize],
+                    &self.size_bufs[offset as usize],
+                )?;
+                self.key_bufs[offset as usize].clear();
+                self.size_bufs[offset as usize].clear();
+                self.key_bufs[offset as usize + 1].push(key);
+                self.size_bufs[offset as usize + 1].push(size);
+            } else {
+                break;
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Stores the data after the last cut, and closes every tree, returning the root.
+    fn finish<DS: DataStore>(mut self, ds: &mut DS, rest: &[u8]) -> Result<Key, PutDataError> {
+        let key_bufs = &mut self.key_bufs;
+        let size_bufs = &mut self.size_bufs;
+
+        if (0..4).all(|x| key_bufs[x].is_empty()) {
+            // No chunks were made.
+            return Ok(ds.put_obj(&Object::new(rest, &[], ObjType::FileBlob))?);
+        }
+
+        if !rest.is_empty() {
+            let key = ds.put_obj(&Object::new(rest, &[], ObjType::FileBlob))?;
+            key_bufs[0].push(key);
+            size_bufs[0].push(rest.len() as u64);
+        }
+
+        for offset in 0..4 {
+            let (key, size) = put_tree(ds, &key_bufs[offset], &size_bufs[offset])?;
+
+            if key_bufs[offset].len() == 1 && (1 + offset..4).all(|x| key_bufs[x].is_empty()) {
+                // We know this is safe because key_bufs[offset] has exactly 1 element
+                #[allow(clippy::option_unwrap_used)]
+                return Ok(key_bufs[offset].pop().unwrap());
+            }
+
+            key_bufs[offset + 1].push(key);
+            size_bufs[offset + 1].push(size);
+        }
+
+        Ok(put_tree(ds, &key_bufs[4], &size_bufs[4])?.0)
+    }
+}
+
+/// Reads into `buffer` until it's at least `len` long, or `data` runs out.
+fn fill<R: Read>(data: &mut R, buffer: &mut Vec<u8>, len: usize) -> Result<(), std::io::Error> {
+    // Read a good amount at a time, so this isn't called for every blob.
+    let target = len.max(1 << 20);
+
+    while buffer.len() < target {
+        let old_len = buffer.len();
+        buffer.resize(target, 0);
+
+        match data.read(&mut buffer[old_len..]) {
+            Ok(0) => {
+                buffer.truncate(old_len);
+                return Ok(());
+            }
+            Ok(n) => buffer.truncate(old_len + n),
+            Err(e) if e.kind() == std::io::ErrorKind::Interrupted => buffer.truncate(old_len),
+            Err(e) => {
+                buffer.truncate(old_len);
+                return Err(e);
+            }
+        }
+    }
+
+    Ok(())
+}
+
+/// Stores `data` as blobs, cut where `config` says, gathered into trees. Returns the root key.
+pub fn put_data<DS: DataStore, R: Read>(
+    ds: &mut DS,
+    data: R,
+    config: &ChunkerConfig,
+) -> Result<Key, PutDataError> {
+    put_extents(ds, ReadExtents(data), config)
+}
+
+/// Like [`put_data`], but holes in sparse files are skipped over rather than read. The key is the
+/// same as `put_data` would give for the same contents.
+pub fn put_file<DS: DataStore>(
+    ds: &mut DS,
+    file: &mut std::fs::File,
+    config: &ChunkerConfig,
+) -> Result<Key, PutDataError> {
+    put_extents(ds, FileExtents::new(file)?, config)
+}
+
+fn put_extents<DS: DataStore, E: Extents>(
+    ds: &mut DS,
+    extents: E,
+    config: &ChunkerConfig,
+) -> Result<Key, PutDataError> {
+    let mut tree = TreeBuilder::new(config);
+    let mut regions = Regions::new(extents, config.min_zero_run());
+
+    loop {
+        // The data between runs is chunked afresh each time, so where a run falls (and so whether
+        // it was a hole) can't change the cuts around it.
+        let rest = match config.algorithm {
+            ChunkerAlgorithm::Gear => put_gear_blobs(ds, &mut regions, config, &mut tree)?,
+            ChunkerAlgorithm::FastCdc {
+                min_size,
+                avg_size,
+                max_size,
+            } => put_fastcdc_blobs(ds, &mut regions, min_size, avg_size, max_size, &mut tree)?,
+        };
+
+        match regions.take_run() {
+            Some(len) => {
+                if !rest.is_empty() {
+                    tree.push_blob(ds, &rest, 0)?;
+                }
+
+                tree.push_zero_run(ds, len)?;
+            }
+            None => return tree.finish(ds, &rest),
+        }
+    }
+}
+
+/// Cuts blobs for [`ChunkerAlgorithm::FastCdc`], returning what's left after the last cut.
+fn put_fastcdc_blobs<DS: DataStore, R: Read>(
+    ds: &mut DS,
+    mut data: R,
+    min_size: u32,
+    avg_size: u32,
+    max_size: u32,
+    tree: &mut TreeBuilder,
+) -> Result<Vec<u8>, PutDataError> {
+    let mut buffer = Vec::new();
+    let mut start = 0;
+    let bits = avg_size.trailing_zeros();
+
+    loop {
+        if buffer.len() - start < max_size as usize {
+            // Only shuffle the buffer down when it needs topping up.
+            buffer.drain(..start);
+            start = 0;
+            fill(&mut data, &mut buffer, max_size as usize)?;
+        }
+
+        let rest = &buffer[start..];
+
+        match chunker::fastcdc_cut(rest, min_size, avg_size, max_size) {
+            Some((len, hash)) => {
+                tree.push_blob(ds, &rest[..len], hash.leading_zeros().saturating_sub(bits))?;
+                start += len;
+            }
+            None => {
+                buffer.drain(..start);
+                return Ok(buffer);
+            }
+        }
+    }
 }
 
-pub fn put_data<DS: DataStore, R: Read>(ds: &mut DS, mut data: R) -> Result<Key, PutDataError> {
-    let mut key_bufs: [Vec<Key>; 5] = Default::default();
+/// A piece of file data.
+enum Extent {
+    Data(Vec<u8>),
+
+    /// Zeros that are known about without reading them, from a hole in a sparse file.
+    Zeros(u64),
+}
+
+/// Where [`put_extents`] gets its data from. Returns `None` at the end, and keeps doing so.
+trait Extents {
+    fn next_extent(&mut self) -> std::io::Result<Option<Extent>>;
+}
+
+/// Any reader, where nothing is known to be zero without reading it.
+struct ReadExtents<R>(R);
+
+impl<R: Read> Extents for ReadExtents<R> {
+    fn next_extent(&mut self) -> std::io::Result<Option<Extent>> {
+        let mut buf = vec![0; 1 << 16];
+
+        loop {
+            match self.0.read(&mut buf) {
+                Ok(0) => return Ok(None),
+                Ok(n) => {
+                    buf.truncate(n);
+                    return Ok(Some(Extent::Data(buf)));
+                }
+                Err(e) if e.kind() == std::io::ErrorKind::Interrupted => {}
+                Err(e) => return Err(e),
+            }
+        }
+    }
+}
+
+/// A file, with its holes found by `SEEK_DATA` and `SEEK_HOLE`. Filesystems without them report
+/// the whole file as data, which is still right, just slower.
+struct FileExtents<'a> {
+    file: &'a mut std::fs::File,
+    pos: u64,
+
+    /// Where the data `pos` is in ends.
+    data_end: u64,
+}
+
+impl<'a> FileExtents<'a> {
+    fn new(file: &'a mut std::fs::File) -> std::io::Result<Self> {
+        file.seek(SeekFrom::Start(0))?;
+
+        Ok(Self {
+            file,
+            pos: 0,
+            data_end: 0,
+        })
+    }
+
+    fn lseek(&self, whence: libc::c_int) -> std::io::Result<Option<u64>> {
+        use std::os::unix::io::AsRawFd;
+
+        // Safe, as the descriptor is kept open by `self.file`, and lseek doesn't touch memory.
+        let offset = unsafe { libc::lseek(self.file.as_raw_fd(), self.pos as libc::off_t, whence) };
+
+        if offset >= 0 {
+            return Ok(Some(offset as u64));
+        }
+
+        let err = std::io::Error::last_os_error();
+
+        match err.raw_os_error() {
+            // No data after `pos`.
+            Some(libc::ENXIO) => Ok(None),
+            // Not supported here, so it's all data.
+            Some(libc::EINVAL) if whence == libc::SEEK_DATA => Ok(Some(self.pos)),
+            Some(libc::EINVAL) => Ok(Some(u64::MAX)),
+            _ => Err(err),
+        }
+    }
+}
+
+impl<'a> Extents for FileExtents<'a> {
+    fn next_extent(&mut self) -> std::io::Result<Option<Extent>> {
+        if self.pos >= self.data_end {
+            let len = self.file.metadata()?.len();
+
+            if self.pos >= len {
+                return Ok(None);
+            }
+
+            let data_start = self.lseek(libc::SEEK_DATA)?.unwrap_or(len).min(len);
+
+            if data_start > self.pos {
+                let zeros = data_start - self.pos;
+                self.pos = data_start;
+                return Ok(Some(Extent::Zeros(zeros)));
+            }
+
+            self.data_end = self.lseek(libc::SEEK_HOLE)?.unwrap_or(len).min(len);
+        }
+
+        let want = (self.data_end - self.pos).min(1 << 16) as usize;
+        let mut buf = vec![0; want];
+
+        self.file.seek(SeekFrom::Start(self.pos))?;
+
+        loop {
+            match self.file.read(&mut buf) {
+                Ok(0) => {
+                    // The file got shorter under us.
+                    self.data_end = self.pos;
+                    return Ok(None);
+                }
+                Ok(n) => {
+                    buf.truncate(n);
+                    self.pos += n as u64;
+                    return Ok(Some(Extent::Data(buf)));
+                }
+                Err(e) if e.kind() == std::io::ErrorKind::Interrupted => {}
+                Err(e) => return Err(e),
+            }
+        }
+    }
+}
+
+/// Splits [`Extents`] at runs of zeros, reading as the data up to the next run.
+///
+/// Once a read returns 0, [`take_run`](Regions::take_run) says whether that was a run (and then
+/// reading carries on after it) or the end. Runs are found in the contents, so a hole and zeros
+/// that were written out give the same regions.
+struct Regions<E> {
+    extents: E,
+    min_run: Option<u64>,
+
+    /// The extent being split, and how far through it we are.
+    data: Vec<u8>,
+    data_pos: usize,
+
+    /// Zeros seen since the last non-zero byte, which might still turn out to be a run.
+    pending_zeros: u64,
+
+    /// Data ready to be read.
+    ready: Vec<u8>,
+    ready_pos: usize,
+
+    /// The run ending the current region.
+    run: Option<u64>,
+    done: bool,
+}
+
+impl<E: Extents> Regions<E> {
+    fn new(extents: E, min_run: Option<u64>) -> Self {
+        Self {
+            extents,
+            min_run,
+            data: Vec::new(),
+            data_pos: 0,
+            pending_zeros: 0,
+            ready: Vec::new(),
+            ready_pos: 0,
+            run: None,
+            done: false,
+        }
+    }
+
+    /// The run that ended the last region, or `None` if it was the end of the data.
+    fn take_run(&mut self) -> Option<u64> {
+        self.run.take()
+    }
+
+    /// Deals with the zeros seen so far, now there's something that isn't a zero after them.
+    /// Returns true if they were a run, and so the region is over.
+    fn end_zeros(&mut self) -> bool {
+        if self.min_run.is_some_and(|min| self.pending_zeros >= min) {
+            self.run = Some(self.pending_zeros);
+            self.pending_zeros = 0;
+            return true;
+        }
+
+        // Not a run, so they're just data. This is a bit at a time, as a hole can be huge.
+        let len = self.pending_zeros.min(1 << 16);
+        self.ready.resize(len as usize, 0);
+        self.pending_zeros -= len;
+
+        false
+    }
+
+    /// Refills `ready`, or sets `run` or `done`.
+    fn refill(&mut self) -> std::io::Result<()> {
+        self.ready.clear();
+        self.ready_pos = 0;
+
+        if self.data_pos == self.data.len() {
+            match self.extents.next_extent()? {
+                Some(Extent::Data(data)) => {
+                    self.data = data;
+                    self.data_pos = 0;
+                }
+                Some(Extent::Zeros(len)) => {
+                    self.pending_zeros += len;
+                    return Ok(());
+                }
+                None => {
+                    if self.pending_zeros == 0 {
+                        self.done = true;
+                    } else {
+                        self.end_zeros();
+                    }
+                    return Ok(());
+                }
+            }
+        }
+
+        let rest = &self.data[self.data_pos..];
+
+        let zeros = rest.iter().take_while(|&&b| b == 0).count();
+        if zeros > 0 {
+            self.pending_zeros += zeros as u64;
+            self.data_pos += zeros;
+            return Ok(());
+        }
+
+        if self.pending_zeros > 0 {
+            // Either the region ends, or some of the zeros were made ready. Both need reading
+            // before the data after them.
+            self.end_zeros();
+            return Ok(());
+        }
 
+        let len = rest.iter().position(|&b| b == 0).unwrap_or(rest.len());
+        self.ready.extend_from_slice(&rest[..len]);
+        self.data_pos += len;
+
+        Ok(())
+    }
+}
+
+impl<E: Extents> Read for Regions<E> {
+    /// Fills as much of `buf` as the region has. The gear chunker's forced cuts depend on how much
+    /// each read returns, so this has to match reading the file directly.
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        let mut filled = 0;
+
+        while filled < buf.len() {
+            if self.ready_pos < self.ready.len() {
+                let available = &self.ready[self.ready_pos..];
+                let len = available.len().min(buf.len() - filled);
+
+                buf[filled..filled + len].copy_from_slice(&available[..len]);
+                self.ready_pos += len;
+                filled += len;
+            } else if self.run.is_some() || self.done {
+                break;
+            } else {
+                self.refill()?;
+            }
+        }
+
+        Ok(filled)
+    }
+}
+
+/// Cuts blobs for [`ChunkerAlgorithm::Gear`], returning what's left after the last cut.
+fn put_gear_blobs<DS: DataStore, R: Read>(
+    ds: &mut DS,
+    mut data: R,
+    config: &ChunkerConfig,
+    tree: &mut TreeBuilder,
+) -> Result<Vec<u8>, PutDataError> {
     let mut read_buffer = [0u8; 1 << 16usize];
     let mut chunk_buffer: Vec<u8> = Vec::new();
     let mut current_chunk = Vec::new();
 
     let mut hasher = gearhash::Hasher::new(&gearhash::DEFAULT_TABLE);
 
+    let bitmask = config.blob_bitmask();
+    let max_blob_len = 1 << config.blob_zero_count_max;
+
     loop {
         let m = {
-            let hasher_match = hasher.next_match(&chunk_buffer, BLOB_ZERO_COUNT_BITMASK);
+            let hasher_match = hasher.next_match(&chunk_buffer, bitmask);
 
-            if chunk_buffer.len() > 1 << BLOB_ZERO_COUNT_MAX {
+            if chunk_buffer.len() > max_blob_len {
                 // We've gone on too long, force a cut here.
-                Some(1 << BLOB_ZERO_COUNT_MAX)
+                Some(max_blob_len)
             } else {
                 hasher_match
             }
@@ -40,29 +561,15 @@ pub fn put_data<DS: DataStore, R: Read>(ds: &mut DS, mut data: R) -> Result<Key,
 
             let zeros = hasher.get_hash().leading_zeros();
 
-            debug_assert!(zeros >= BLOB_ZERO_COUNT || boundry == (1 << BLOB_ZERO_COUNT_MAX));
+            debug_assert!(zeros >= config.blob_zero_count || boundry == max_blob_len);
 
-            if current_chunk.len() >= 1 << (BLOB_ZERO_COUNT_MAX) {
-                let key = ds.put_obj(&Object::new(&current_chunk, &[], ObjType::FileBlob))?;
-                key_bufs[0].push(key);
+            if current_chunk.len() >= max_blob_len {
+                tree.push_blob(
+                    ds,
+                    &current_chunk,
+                    zeros.saturating_sub(config.blob_zero_count),
+                )?;
                 current_chunk.clear();
-
-                for offset in 0..4 {
-                    let len = key_bufs[offset as usize].len();
-                    if zeros > BLOB_ZERO_COUNT + (offset + 1) * PER_LEVEL_COUNT
-                        || len >= 1 << PER_LEVEL_COUNT_MAX
-                    {
-                        let key = ds.put_obj(&Object::new(
-                            &[],
-                            &key_bufs[offset as usize],
-                            ObjType::FileBlobTree,
-                        ))?;
-                        key_bufs[offset as usize].clear();
-                        key_bufs[offset as usize + 1].push(key);
-                    } else {
-                        break;
-                    }
-                }
             }
         } else {
             use std::io::ErrorKind;
@@ -86,29 +593,7 @@ pub fn put_data<DS: DataStore, R: Read>(ds: &mut DS, mut data: R) -> Result<Key,
 
     current_chunk.extend_from_slice(&chunk_buffer);
 
-    if (0..4).all(|x| key_bufs[x].is_empty()) {
-        // No chunks were made.
-        return Ok(ds.put_obj(&Object::new(&current_chunk, &[], ObjType::FileBlob))?);
-    }
-
-    if !current_chunk.is_empty() {
-        let key = ds.put_obj(&Object::new(&current_chunk, &[], ObjType::FileBlob))?;
-        key_bufs[0].push(key);
-    }
-
-    for offset in 0..4 {
-        let key = ds.put_obj(&Object::new(&[], &key_bufs[offset], ObjType::FileBlobTree))?;
-
-        if key_bufs[offset].len() == 1 && (1 + offset..4).all(|x| key_bufs[x].is_empty()) {
-            // We know this is safe because key_bufs[offset] has exactly 1 element
-            #[allow(clippy::option_unwrap_used)]
-            return Ok(key_bufs[offset].pop().unwrap());
-        }
-
-        key_bufs[offset + 1].push(key);
-    }
-
-    Ok(ds.put_obj(&Object::new(&[], &key_bufs[4], ObjType::FileBlobTree))?)
+    Ok(current_chunk)
 }
 
 #[derive(Debug, Error)]
@@ -118,46 +603,344 @@ pub enum ReadDataError {
 
     #[error("io error: {_0}")]
     IOError(#[from] std::io::Error),
+
+    #[error("error decoding object: {_0}")]
+    DecodeError(#[from] serde_cbor::error::Error),
+
+    #[error("{key} is a {objtype}, not file data")]
+    InvalidType { key: Key, objtype: ObjType },
+
+    #[error("file {key} should point at one piece of data, but points at {count}")]
+    InvalidFile { key: Key, count: usize },
 }
 
-pub fn read_data<DS: DataStore, W: Write>(
+/// The length of the run of zeros `obj` stands for.
+fn zero_run_len(obj: &Object) -> Result<u64, serde_cbor::error::Error> {
+    serde_cbor::from_slice(obj.data())
+}
+
+/// A piece of stored file data, in order.
+enum Piece<'a> {
+    Data(&'a [u8]),
+    Zeros(u64),
+}
+
+fn walk_data<DS: DataStore>(
     ds: &DS,
     key: Key,
-    to: &mut W,
+    f: &mut dyn FnMut(Piece<'_>) -> std::io::Result<()>,
 ) -> Result<(), ReadDataError> {
     let obj = ds.get_obj(key)?;
 
     match obj.objtype() {
         ObjType::FileBlobTree => {
             for key in obj.keys().iter().copied() {
-                read_data(ds, key, to)?;
+                walk_data(ds, key, f)?;
             }
         }
         ObjType::FileBlob => {
-            to.write_all(&obj.data())?;
+            f(Piece::Data(obj.data()))?;
         }
-        ObjType::FSItemFile => {
-            assert!(obj.keys().len() == 1);
+        ObjType::ZeroRun => {
+            f(Piece::Zeros(zero_run_len(&obj)?))?;
+        }
+        ObjType::FSItemFile => match obj.keys() {
+            &[data] => walk_data(ds, data, f)?,
+            keys => {
+                return Err(ReadDataError::InvalidFile {
+                    key,
+                    count: keys.len(),
+                })
+            }
+        },
+        objtype => return Err(ReadDataError::InvalidType { key, objtype }),
+    }
+
+    Ok(())
+}
 
-            let key = obj.keys()[0];
-            read_data(ds, key, to)?;
+pub fn read_data<DS: DataStore, W: Write>(
+    ds: &DS,
+    key: Key,
+    to: &mut W,
+) -> Result<(), ReadDataError> {
+    walk_data(ds, key, &mut |piece| match piece {
+        Piece::Data(data) => to.write_all(data),
+        Piece::Zeros(len) => std::io::copy(&mut std::io::repeat(0).take(len), to).map(|_| ()),
+    })
+}
+
+/// Like [`read_data`], but into a file, where runs of zeros are seeked over rather than written,
+/// leaving holes. `file` should be empty.
+pub fn restore_data<DS: DataStore>(
+    ds: &DS,
+    key: Key,
+    file: &mut std::fs::File,
+) -> Result<(), ReadDataError> {
+    let mut len = 0;
+
+    walk_data(ds, key, &mut |piece| {
+        match piece {
+            Piece::Data(data) => {
+                file.write_all(data)?;
+                len += data.len() as u64;
+            }
+            Piece::Zeros(zeros) => {
+                len += zeros;
+                file.seek(SeekFrom::Start(len))?;
+            }
         }
-        _ => {
-            panic!(
-                "found invalid object type {:?} when reading key {:?}",
-                obj.objtype(),
-                key
-            );
+
+        Ok(())
+    })?;
+
+    // Seeking past the end doesn't make the file any longer, so a trailing run needs this.
+    file.set_len(len)?;
+
+    Ok(())
+}
+
+#[derive(Debug, Error)]
+pub enum BlobReaderError {
+    #[error("error getting object: {_0}")]
+    GetObjError(#[from] ds::GetObjError),
+
+    #[error("error decoding blob tree sizes: {_0}")]
+    DecodeError(#[from] serde_cbor::error::Error),
+
+    #[error("blob tree {_0} has sizes that don't match its children")]
+    InvalidSizes(Key),
+
+    #[error("{key} is a {objtype}, not file data")]
+    InvalidType { key: Key, objtype: ObjType },
+}
+
+/// A blob, or a run of zeros, as [`BlobReader`] holds them.
+#[derive(Debug)]
+enum Leaf {
+    Data(Vec<u8>),
+    Zeros(u64),
+}
+
+impl Leaf {
+    fn len(&self) -> u64 {
+        match self {
+            Leaf::Data(data) => data.len() as u64,
+            Leaf::Zeros(len) => *len,
         }
     }
+}
 
-    Ok(())
+/// Reads file data from the store, like [`read_data`], but can seek to any offset without reading
+/// everything before it.
+pub struct BlobReader<'a, DS: DataStore + ?Sized> {
+    ds: &'a DS,
+    root: Key,
+    len: u64,
+    pos: u64,
+
+    /// The blob `pos` was last in, and where it starts.
+    current: Option<(u64, Leaf)>,
+
+    /// Sizes worked out for children of legacy trees, which don't record them.
+    legacy_sizes: HashMap<Key, u64>,
+}
+
+impl<'a, DS: DataStore + ?Sized> std::fmt::Debug for BlobReader<'a, DS> {
+    fn fmt(&self, fmt: &mut std::fmt::Formatter<'_>) -> Result<(), std::fmt::Error> {
+        fmt.debug_struct("BlobReader")
+            .field("root", &self.root)
+            .field("len", &self.len)
+            .field("pos", &self.pos)
+            .finish()
+    }
+}
+
+impl<'a, DS: DataStore + ?Sized> BlobReader<'a, DS> {
+    /// Opens the data at `key`, which can be a blob, a blob tree, or a file.
+    pub fn new(ds: &'a DS, key: Key) -> Result<Self, BlobReaderError> {
+        let mut reader = Self {
+            ds,
+            root: key,
+            len: 0,
+            pos: 0,
+            current: None,
+            legacy_sizes: HashMap::new(),
+        };
+
+        reader.len = reader.data_len(key)?;
+
+        Ok(reader)
+    }
+
+    /// The length of the whole file.
+    pub fn len(&self) -> u64 {
+        self.len
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    fn data_len(&mut self, key: Key) -> Result<u64, BlobReaderError> {
+        if let Some(&len) = self.legacy_sizes.get(&key) {
+            return Ok(len);
+        }
+
+        let obj = self.ds.get_obj(key)?;
+
+        match obj.objtype() {
+            ObjType::FileBlob => Ok(obj.data().len() as u64),
+            ObjType::ZeroRun => Ok(zero_run_len(&obj)?),
+            ObjType::FileBlobTree => Ok(self.child_ends(key, &obj)?.last().copied().unwrap_or(0)),
+            ObjType::FSItemFile if obj.keys().len() == 1 => self.data_len(obj.keys()[0]),
+            objtype => Err(BlobReaderError::InvalidType { key, objtype }),
+        }
+    }
+
+    /// Where each child of the blob tree `obj` ends, working it out for legacy trees.
+    fn child_ends(&mut self, key: Key, obj: &Object) -> Result<Vec<u64>, BlobReaderError> {
+        if !obj.data().is_empty() {
+            let sizes: BlobTreeSizes = serde_cbor::from_slice(obj.data())?;
+
+            if sizes.ends.len() != obj.keys().len() {
+                return Err(BlobReaderError::InvalidSizes(key));
+            }
+
+            return Ok(sizes.ends);
+        }
+
+        let mut ends = Vec::with_capacity(obj.keys().len());
+        let mut total: u64 = 0;
+
+        for &child in obj.keys() {
+            let len = self.data_len(child)?;
+            self.legacy_sizes.insert(child, len);
+
+            total = total.saturating_add(len);
+            ends.push(total);
+        }
+
+        self.legacy_sizes.insert(key, total);
+
+        Ok(ends)
+    }
+
+    /// Finds the blob containing `pos`, which must be before the end of the data.
+    fn locate(&mut self, pos: u64) -> Result<(u64, Leaf), BlobReaderError> {
+        let mut key = self.root;
+        let mut start = 0;
+
+        loop {
+            let obj = self.ds.get_obj(key)?;
+
+            match obj.objtype() {
+                ObjType::FileBlob => return Ok((start, Leaf::Data(obj.data().to_vec()))),
+                ObjType::ZeroRun => return Ok((start, Leaf::Zeros(zero_run_len(&obj)?))),
+                ObjType::FileBlobTree => {
+                    let ends = self.child_ends(key, &obj)?;
+
+                    // Empty children end where they start, so they're skipped over here.
+                    let idx = ends.partition_point(|&end| start.saturating_add(end) <= pos);
+
+                    let child = match obj.keys().get(idx) {
+                        Some(&child) => child,
+                        None => return Err(BlobReaderError::InvalidSizes(key)),
+                    };
+
+                    if idx > 0 {
+                        start = start.saturating_add(ends[idx - 1]);
+                    }
+
+                    key = child;
+                }
+                ObjType::FSItemFile if obj.keys().len() == 1 => key = obj.keys()[0],
+                objtype => return Err(BlobReaderError::InvalidType { key, objtype }),
+            }
+        }
+    }
 }
 
-const BLOB_ZERO_COUNT: u32 = 13;
-const BLOB_ZERO_COUNT_MAX: u32 = BLOB_ZERO_COUNT + 2;
+impl<'a, DS: DataStore + ?Sized> Read for BlobReader<'a, DS> {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        if self.pos >= self.len || buf.is_empty() {
+            return Ok(0);
+        }
+
+        let pos = self.pos;
+
+        let in_current = match &self.current {
+            Some((start, leaf)) => *start <= pos && pos < start + leaf.len(),
+            None => false,
+        };
+
+        if !in_current {
+            let (start, leaf) = self.locate(pos).map_err(std::io::Error::other)?;
+
+            // Only trees whose sizes don't add up can lead somewhere else.
+            if pos < start || pos - start >= leaf.len() {
+                return Err(std::io::Error::other(BlobReaderError::InvalidSizes(
+                    self.root,
+                )));
+            }
+
+            self.current = Some((start, leaf));
+        }
+
+        let (start, leaf) = match &self.current {
+            Some(current) => current,
+            None => return Ok(0),
+        };
+
+        let offset = pos - start;
 
-const BLOB_ZERO_COUNT_BITMASK: u64 = !((1 << (64 - BLOB_ZERO_COUNT)) - 1);
+        let len = match leaf {
+            Leaf::Data(data) => {
+                let available = &data[offset as usize..];
+                let len = available.len().min(buf.len());
 
-const PER_LEVEL_COUNT: u32 = 7;
-const PER_LEVEL_COUNT_MAX: u32 = PER_LEVEL_COUNT + 2;
+                buf[..len].copy_from_slice(&available[..len]);
+                len
+            }
+            Leaf::Zeros(zeros) => {
+                let len = (zeros - offset).min(buf.len() as u64) as usize;
+
+                buf[..len].iter_mut().for_each(|b| *b = 0);
+                len
+            }
+        };
+
+        self.pos += len as u64;
+
+        Ok(len)
+    }
+}
+
+impl<'a, DS: DataStore + ?Sized> Seek for BlobReader<'a, DS> {
+    fn seek(&mut self, pos: SeekFrom) -> std::io::Result<u64> {
+        let new_pos = match pos {
+            SeekFrom::Start(offset) => Some(offset),
+            SeekFrom::End(offset) => offset_by(self.len, offset),
+            SeekFrom::Current(offset) => offset_by(self.pos, offset),
+        };
+
+        match new_pos {
+            Some(p) => {
+                self.pos = p;
+                Ok(p)
+            }
+            None => Err(std::io::Error::new(
+                std::io::ErrorKind::InvalidInput,
+                "invalid seek to a negative or overflowing position",
+            )),
+        }
+    }
+}
+
+fn offset_by(base: u64, offset: i64) -> Option<u64> {
+    if offset >= 0 {
+        base.checked_add(offset as u64)
+    } else {
+        base.checked_sub(offset.unsigned_abs())
+    }
+}
@@ -1,5 +1,5 @@
 use std::fs::DirEntry;
-use std::path::PathBuf;
+use std::path::{Path, PathBuf};
 
 pub fn make_filter_fn<T: AsRef<str>>(
     excludes: &[T],
@@ -16,11 +16,20 @@ pub fn make_filter_fn<T: AsRef<str>>(
     Box::new(move |direntry: &DirEntry| -> bool {
         let path = direntry.path();
 
-        let canon_path = std::fs::canonicalize(&path).unwrap();
-        let canon_db_path = std::fs::canonicalize(&db_path).unwrap();
-
-        if canon_path.starts_with(canon_db_path) {
-            return false;
+        // Only the directory it's in, as the entry itself could be a symlink to anywhere, or to
+        // nothing at all.
+        let parent = match path.parent() {
+            Some(parent) if !parent.as_os_str().is_empty() => parent,
+            _ => Path::new("."),
+        };
+        let canon_path = std::fs::canonicalize(parent)
+            .unwrap()
+            .join(direntry.file_name());
+        // Outside of a repository (say for hash-object) there's no database to leave out.
+        if let Ok(canon_db_path) = std::fs::canonicalize(&db_path) {
+            if canon_path.starts_with(canon_db_path) {
+                return false;
+            }
         }
 
         let normalised_path = if path.starts_with("./") {
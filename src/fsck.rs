@@ -0,0 +1,111 @@
+use crate::dir::FSItem;
+use crate::ds;
+use crate::key::{self, Key};
+use crate::object::ObjType;
+use crate::DataStore;
+use std::collections::HashSet;
+use std::convert::TryInto;
+use thiserror::Error;
+
+#[derive(Debug, Default)]
+pub struct FsckReport {
+    /// Number of objects checked.
+    pub checked: usize,
+
+    /// Objects whose data doesn't match their key, or that don't decode.
+    pub corrupt: Vec<Key>,
+
+    /// Objects that are referenced by another object, but aren't in the store.
+    pub missing: Vec<Key>,
+
+    /// Objects that are intact, but weren't encoded canonically, so the same content written now
+    /// would get a different key. These aren't damage, and aren't counted by `is_clean`.
+    pub noncanonical: Vec<Key>,
+
+    /// Directories whose children aren't sorted by name, so the same directory stored now would
+    /// get a different key. Older versions stored them in the order the file system listed them.
+    /// Like `noncanonical`, these aren't damage.
+    pub unsorted: Vec<Key>,
+}
+
+impl FsckReport {
+    pub fn is_clean(&self) -> bool {
+        self.corrupt.is_empty() && self.missing.is_empty()
+    }
+
+    /// Every object that's either corrupt or missing.
+    pub fn damaged(&self) -> Vec<Key> {
+        self.corrupt
+            .iter()
+            .chain(self.missing.iter())
+            .copied()
+            .collect()
+    }
+}
+
+#[derive(Debug, Error)]
+pub enum FsckError {
+    #[error("error listing objects: {_0}")]
+    RawBetweenError(#[from] ds::RawBetweenError),
+
+    #[error("error reading object: {_0}")]
+    RawGetError(#[from] ds::RawGetError),
+
+    #[error("error parsing db key: {_0}")]
+    FromDbKeyError(#[from] key::FromDbKeyError),
+}
+
+/// Checks every object in the store, making sure it hashes to its key, is well-formed, and that
+/// every key it points to exists.
+pub fn fsck<DS: DataStore>(ds: &DS) -> Result<FsckReport, FsckError> {
+    let mut report = FsckReport::default();
+
+    let mut present = HashSet::new();
+    let mut referenced = HashSet::new();
+
+    for db_key in ds.raw_between(&[], None)? {
+        let key = Key::from_db_key(&db_key)?;
+        present.insert(key);
+        report.checked += 1;
+
+        let data = ds.raw_get(&db_key)?;
+
+        if !key.verify(&data) {
+            log::warn!("object {} does not match its key", key);
+            report.corrupt.push(key);
+            continue;
+        }
+
+        match serde_cbor::from_slice::<crate::Object>(&data) {
+            Ok(obj) => {
+                if !crate::canonical::is_canonical_object(&data, &obj) {
+                    report.noncanonical.push(key);
+                }
+
+                referenced.extend(obj.keys().iter().copied());
+
+                // Ones this version can't read (say, from a newer one) are left alone.
+                if let ObjType::FSItemDir = obj.objtype() {
+                    let item: Result<FSItem, _> = obj.try_into();
+
+                    if matches!(item, Ok(item) if !item.children_sorted()) {
+                        report.unsorted.push(key);
+                    }
+                }
+            }
+            Err(e) => {
+                log::warn!("object {} failed to decode: {}", key, e);
+                report.corrupt.push(key);
+            }
+        }
+    }
+
+    report.missing = referenced.difference(&present).copied().collect();
+
+    report.corrupt.sort();
+    report.missing.sort();
+    report.noncanonical.sort();
+    report.unsorted.sort();
+
+    Ok(report)
+}
@@ -5,6 +5,73 @@ use thiserror::Error;
 )]
 pub enum Key {
     Blake3B([u8; 32]),
+    Sha256([u8; 32]),
+}
+
+/// The hash used for new keys. Each repository picks one, see [`DataStore::hash_algorithm`].
+///
+/// [`DataStore::hash_algorithm`]: crate::DataStore::hash_algorithm
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Default)]
+pub enum HashAlgorithm {
+    #[default]
+    Blake3B,
+    Sha256,
+}
+
+impl HashAlgorithm {
+    pub fn hash(self, data: &[u8]) -> Key {
+        match self {
+            Self::Blake3B => Key::Blake3B(*blake3::hash(data).as_bytes()),
+            Self::Sha256 => {
+                use sha2::Digest;
+
+                let mut bytes = [0; 32];
+                bytes.copy_from_slice(&sha2::Sha256::digest(data));
+                Key::Sha256(bytes)
+            }
+        }
+    }
+
+    /// The first byte of db keys using this algorithm.
+    pub fn hash_id(self) -> u8 {
+        match self {
+            Self::Blake3B => 1,
+            Self::Sha256 => 2,
+        }
+    }
+
+    pub fn from_hash_id(hash_id: u8) -> Option<Self> {
+        match hash_id {
+            1 => Some(Self::Blake3B),
+            2 => Some(Self::Sha256),
+            _ => None,
+        }
+    }
+}
+
+#[derive(Debug, Error)]
+#[error("Unknown hash algorithm '{_0}', expected blake3 or sha256")]
+pub struct ParseHashAlgorithmError(String);
+
+impl std::str::FromStr for HashAlgorithm {
+    type Err = ParseHashAlgorithmError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "blake3" => Ok(Self::Blake3B),
+            "sha256" => Ok(Self::Sha256),
+            _ => Err(ParseHashAlgorithmError(s.to_string())),
+        }
+    }
+}
+
+impl std::fmt::Display for HashAlgorithm {
+    fn fmt(&self, fmt: &mut std::fmt::Formatter<'_>) -> Result<(), std::fmt::Error> {
+        match self {
+            Self::Blake3B => fmt.write_str("blake3"),
+            Self::Sha256 => fmt.write_str("sha256"),
+        }
+    }
 }
 
 #[derive(serde::Serialize, serde::Deserialize)]
@@ -90,16 +157,30 @@ pub enum FromDbKeyError {
     },
 }
 
+/// The default minimum length for abbreviated keys, see [`DataStore::abbreviate`].
+///
+/// This is the prefix character and 4 characters of hash, which hash-encoding.txt reckons is
+/// plenty for a small repository.
+///
+/// [`DataStore::abbreviate`]: crate::DataStore::abbreviate
+pub const DEFAULT_MIN_ABBREV_LEN: usize = 5;
+
 impl Key {
-    fn hash_id(&self) -> u8 {
+    pub fn algorithm(&self) -> HashAlgorithm {
         match self {
-            Self::Blake3B(_) => 1,
+            Self::Blake3B(_) => HashAlgorithm::Blake3B,
+            Self::Sha256(_) => HashAlgorithm::Sha256,
         }
     }
 
+    /// Checks `data` hashes to this key, with whichever algorithm made it.
+    pub fn verify(&self, data: &[u8]) -> bool {
+        self.algorithm().hash(data) == *self
+    }
+
     fn hash_bytes(&self) -> &[u8] {
         match self {
-            Self::Blake3B(x) => x.as_ref(),
+            Self::Blake3B(x) | Self::Sha256(x) => x.as_ref(),
         }
     }
 
@@ -113,25 +194,27 @@ impl Key {
         let hash_id = x[0];
         let hash_bytes = &x[1..];
 
-        match hash_id {
-            1 => {
-                let hash_arr = match hash_bytes.try_into() {
-                    Ok(a) => a,
-                    Err(e) => {
-                        return Err(FromDbKeyError::IncorrectLength {
-                            got: hash_bytes.len(),
-                            source: e,
-                        })
-                    }
-                };
-                Ok(Self::Blake3B(hash_arr))
+        let algorithm =
+            HashAlgorithm::from_hash_id(hash_id).ok_or(FromDbKeyError::UnknownHashId(hash_id))?;
+
+        let hash_arr = match hash_bytes.try_into() {
+            Ok(a) => a,
+            Err(e) => {
+                return Err(FromDbKeyError::IncorrectLength {
+                    got: hash_bytes.len(),
+                    source: e,
+                })
             }
-            0 | 2..=255 => Err(FromDbKeyError::UnknownHashId(hash_id)),
+        };
+
+        match algorithm {
+            HashAlgorithm::Blake3B => Ok(Self::Blake3B(hash_arr)),
+            HashAlgorithm::Sha256 => Ok(Self::Sha256(hash_arr)),
         }
     }
 
     pub fn as_db_key(&self) -> Vec<u8> {
-        let hash_id = self.hash_id();
+        let hash_id = self.algorithm().hash_id();
         let hash_bytes = self.hash_bytes();
 
         let mut result = Vec::with_capacity(hash_bytes.len() + 1);
@@ -147,6 +230,7 @@ impl Key {
 
         let prefix = match self {
             Self::Blake3B(_) => "b",
+            Self::Sha256(_) => "s",
         };
 
         result.push_str(prefix);
@@ -218,5 +302,17 @@ mod tests {
                 panic!("we asked for the full key and we got something else")
             }
         }
+
+        #[test]
+        fn round_trip_sha256_to_keyish(bytes: [u8; 32]) {
+            let k = Key::Sha256(bytes);
+            let from_user = Keyish::from_str(&k.as_user_key()).unwrap();
+
+            if let Keyish::Key(_, b) = from_user {
+                assert_eq!(Key::from_db_key(&b).unwrap(), k);
+            } else {
+                panic!("we asked for the full key and we got something else")
+            }
+        }
     }
 }
@@ -2,6 +2,8 @@ use thiserror::Error;
 
 use bitvec::prelude::*;
 
+use crate::key::HashAlgorithm;
+
 #[derive(Debug)]
 pub enum Keyish {
     /// Strictly speaking, this is for prefix searches
@@ -13,11 +15,44 @@ pub enum Keyish {
     /// An exact key.
     Key(String, Vec<u8>),
 
+    /// A ref, like `/master` or `origin/master`.
+    ///
+    /// A bare name like `master` is also a ref. One that's also a valid key prefix, like `bar`,
+    /// parses as a [`Keyish::Range`], and is looked up as a ref before as a key prefix.
     Reflog {
         orig: String,
         remote: Option<String>,
         keyname: String,
     },
+
+    /// Whatever the ref in HEAD points to.
+    Head { orig: String },
+
+    /// A commit found by walking parents from `base`, like `master~2^2`.
+    Relative {
+        orig: String,
+        base: Box<Keyish>,
+        steps: Vec<ParentStep>,
+    },
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ParentStep {
+    /// `~N`, follow the first parent N times.
+    FirstParent(usize),
+
+    /// `^N`, take the N-th parent (counting from 1). `^0` is the commit itself.
+    Parent(usize),
+}
+
+impl Keyish {
+    /// The string this was parsed from.
+    pub fn orig(&self) -> &str {
+        match self {
+            Self::Range(orig, _, _) | Self::Key(orig, _) => orig,
+            Self::Reflog { orig, .. } | Self::Head { orig } | Self::Relative { orig, .. } => orig,
+        }
+    }
 }
 
 #[derive(Debug, Error)]
@@ -30,16 +65,77 @@ pub enum KeyishParseError {
 
     #[error("no key was given")]
     Empty,
+
+    #[error("{0} is an invalid revision, expected something like ref~2 or ref^2")]
+    InvalidRevision(String),
 }
 
 impl std::str::FromStr for Keyish {
     type Err = KeyishParseError;
 
     fn from_str(s: &str) -> Result<Self, Self::Err> {
-        if s.contains('/') {
+        if let Some(idx) = s.find(['~', '^']) {
+            return parse_relative(s, idx);
+        } else if s == "HEAD" {
+            return Ok(Keyish::Head {
+                orig: s.to_string(),
+            });
+        } else if s.contains('/') {
             return parse_from_ref(s);
         } else {
-            return parse_from_base32(s);
+            return match parse_from_base32(s) {
+                Err(KeyishParseError::Invalid(_)) | Err(KeyishParseError::UnknownPrefix(_)) => {
+                    Ok(Keyish::Reflog {
+                        orig: s.to_string(),
+                        keyname: s.to_string(),
+                        remote: None,
+                    })
+                }
+                other => other,
+            };
+        }
+
+        fn parse_relative(s: &str, idx: usize) -> Result<Keyish, KeyishParseError> {
+            let (base, suffix) = s.split_at(idx);
+
+            if base.is_empty() {
+                return Err(KeyishParseError::InvalidRevision(s.to_string()));
+            }
+
+            let mut steps = Vec::new();
+
+            let mut rest = suffix;
+
+            while let Some(op) = rest.chars().next() {
+                rest = &rest[op.len_utf8()..];
+
+                let digits_len = rest
+                    .find(|c: char| !c.is_ascii_digit())
+                    .unwrap_or(rest.len());
+
+                let (digits, remaining) = rest.split_at(digits_len);
+                rest = remaining;
+
+                let count = if digits.is_empty() {
+                    1
+                } else {
+                    digits
+                        .parse()
+                        .map_err(|_| KeyishParseError::InvalidRevision(s.to_string()))?
+                };
+
+                steps.push(match op {
+                    '~' => ParentStep::FirstParent(count),
+                    '^' => ParentStep::Parent(count),
+                    _ => return Err(KeyishParseError::InvalidRevision(s.to_string())),
+                });
+            }
+
+            Ok(Keyish::Relative {
+                orig: s.to_string(),
+                base: Box::new(base.parse()?),
+                steps,
+            })
         }
 
         fn parse_from_ref(s: &str) -> Result<Keyish, KeyishParseError> {
@@ -75,8 +171,9 @@ impl std::str::FromStr for Keyish {
 
             let (prefix, bytes) = (s_bytes.get(0), s.get(1..).ok_or(KeyishParseError::Empty)?);
 
-            let max_len = match prefix {
-                Some(b'b') => 32 * 8,
+            let (max_len, hash_id) = match prefix {
+                Some(b'b') => (32 * 8, HashAlgorithm::Blake3B.hash_id()),
+                Some(b's') => (32 * 8, HashAlgorithm::Sha256.hash_id()),
                 Some(ch) => return Err(KeyishParseError::UnknownPrefix(*ch as char)),
                 _ => return Err(KeyishParseError::Invalid(s.to_string())),
             };
@@ -88,7 +185,7 @@ impl std::str::FromStr for Keyish {
 
             if input.len() == max_len {
                 let mut v = input.into_vec();
-                v.insert(0_usize, 1_u8);
+                v.insert(0_usize, hash_id);
                 return Ok(Keyish::Key(s.to_string(), v));
             }
 
@@ -98,10 +195,11 @@ impl std::str::FromStr for Keyish {
 
             let mut ret_start = start.into_vec();
 
-            ret_start.insert(0_usize, 1);
+            ret_start.insert(0_usize, hash_id);
 
+            // An all ones prefix runs up to the start of the next hash's keys.
             let ret_end = if did_overflow {
-                None
+                Some(vec![hash_id + 1])
             } else {
                 let mut end = input;
 
@@ -109,7 +207,7 @@ impl std::str::FromStr for Keyish {
 
                 let mut v = end.into_vec();
 
-                v.insert(0_usize, 1);
+                v.insert(0_usize, hash_id);
 
                 Some(v)
             };
@@ -135,5 +233,39 @@ mod tests {
             let result = format!("{}/{}", first, last);
             let _ = Keyish::from_str(&result);
         }
+
+        #[test]
+        fn keyish_relative_parse_doesnt_crash(base: String, suffix: String) {
+            let result = format!("{}~{}", base, suffix);
+            let _ = Keyish::from_str(&result);
+        }
+    }
+
+    #[test]
+    fn parses_chained_revisions() {
+        let parsed = Keyish::from_str("master~2^2^").unwrap();
+
+        if let Keyish::Relative { base, steps, .. } = parsed {
+            assert!(
+                matches!(*base, Keyish::Reflog { ref keyname, remote: None, .. } if keyname == "master")
+            );
+            assert_eq!(
+                steps,
+                vec![
+                    ParentStep::FirstParent(2),
+                    ParentStep::Parent(2),
+                    ParentStep::Parent(1)
+                ]
+            );
+        } else {
+            panic!("expected a relative revision, got {:?}", parsed);
+        }
+
+        assert!(matches!(
+            Keyish::from_str("HEAD~"),
+            Ok(Keyish::Relative { .. })
+        ));
+        assert!(Keyish::from_str("~2").is_err());
+        assert!(Keyish::from_str("HEAD~x").is_err());
     }
 }
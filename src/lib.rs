@@ -40,8 +40,11 @@ fn test_ldbg() {
     ldbg!(1, 2, 3, 4);
 }
 
+pub mod analyze;
 pub mod base32;
 pub mod cache;
+pub mod canonical;
+pub mod chunker;
 pub mod commit;
 pub mod diff;
 pub mod dir;
@@ -49,9 +52,15 @@ pub mod display;
 pub mod ds;
 pub mod file;
 pub mod filter;
+pub mod fsck;
 pub mod key;
 pub mod keyish;
+pub mod metadata;
 pub mod object;
+pub mod parity;
+pub mod rehash;
+pub mod restore;
+pub mod xattr;
 
 pub use ds::DataStore;
 pub use ds::{GetReflogError, Reflog, WalkReflogError};
@@ -5,14 +5,24 @@
 #![allow(clippy::needless_pass_by_value)]
 
 use snapcd::{
-    cache::SqliteCache, commit, diff, dir, display, ds::sqlite::SqliteDS, ds::GetReflogError,
-    ds::Transactional, filter, key, DataStore, Keyish, Reflog,
+    analyze,
+    cache::SqliteCache,
+    chunker::{self, ChunkerAlgorithm, ChunkerConfig},
+    commit, diff, dir, display,
+    ds::null::NullDS,
+    ds::sqlite::SqliteDS,
+    ds::GetReflogError,
+    ds::Transactional,
+    file, filter, fsck, key,
+    metadata::{RestoreMetadata, RestoreOwner},
+    parity, rehash, restore, DataStore, Keyish, Reflog,
 };
 
 use colored::*;
 pub use thiserror::Error;
 
 use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, Ordering};
 use structopt::StructOpt;
 
 type CMDResult = Result<(), anyhow::Error>;
@@ -45,6 +55,20 @@ struct Common {
     /// Paths to exclude
     #[structopt(short = "-e", long = "--exclude", number_of_values(1), global = true)]
     exclude: Vec<String>,
+
+    /// Print full keys, rather than the shortest unique prefix
+    #[structopt(long = "--full-keys", global = true)]
+    full_keys: bool,
+}
+
+impl Common {
+    fn key_style(&self) -> display::KeyStyle {
+        if self.full_keys {
+            display::KeyStyle::Full
+        } else {
+            display::KeyStyle::Short(key::DEFAULT_MIN_ABBREV_LEN)
+        }
+    }
 }
 
 struct State {
@@ -70,6 +94,9 @@ enum Command {
     /// Fetches a file from the database by hash
     Fetch(FetchArgs),
 
+    /// Prints a file's contents, or part of them
+    Cat(CatArgs),
+
     /// Debugging tools
     Debug(DebugCommand),
 
@@ -94,6 +121,36 @@ enum Command {
     CheckoutHead(CheckoutHeadArgs),
 
     Ref(RefCommand),
+
+    /// Computes parity data for objects that don't have any yet
+    Parity(ParityArgs),
+
+    /// Checks every object, repairing any damaged ones that have parity data
+    Fsck(FsckArgs),
+
+    /// Repairs damaged objects using parity data, and reports which could not be recovered
+    Repair(RepairArgs),
+
+    /// Makes the repository append-only. This cannot be undone
+    AppendOnly(AppendOnlyArgs),
+
+    /// Rewrites every object and ref to use a different hash algorithm
+    Rehash(RehashArgs),
+
+    /// Reports how well a path would deduplicate, without storing anything
+    Analyze(AnalyzeArgs),
+
+    /// Prints a stored object, or one part of it (plumbing)
+    CatObject(CatObjectArgs),
+
+    /// Prints the key a path would get if inserted, without storing anything (plumbing)
+    HashObject(HashObjectArgs),
+
+    /// Prints the full key something refers to (plumbing)
+    RevParse(RevParseArgs),
+
+    /// Lists the entries of a directory (plumbing)
+    LsTree(LsTreeArgs),
 }
 
 #[derive(StructOpt, Debug)]
@@ -120,7 +177,40 @@ struct CheckoutHeadArgs {
 }
 
 #[derive(StructOpt, Debug)]
-struct CheckoutArgs {}
+struct CheckoutArgs {
+    #[structopt(flatten)]
+    restore: RestoreArgs,
+}
+
+#[derive(StructOpt, Debug)]
+struct ParityArgs {
+    /// Number of objects in each parity group
+    #[structopt(long = "--data-shards", default_value = "8")]
+    data_shards: usize,
+
+    /// Number of damaged objects each parity group can recover
+    #[structopt(long = "--parity-shards", default_value = "2")]
+    parity_shards: usize,
+}
+
+#[derive(StructOpt, Debug)]
+struct FsckArgs {
+    /// Only report damaged objects, don't try to repair them
+    #[structopt(long = "--no-repair")]
+    no_repair: bool,
+}
+
+#[derive(StructOpt, Debug)]
+struct RepairArgs {}
+
+#[derive(StructOpt, Debug)]
+struct AppendOnlyArgs {}
+
+#[derive(StructOpt, Debug)]
+struct RehashArgs {
+    /// Hash algorithm to switch to (blake3 or sha256)
+    algorithm: key::HashAlgorithm,
+}
 
 #[derive(StructOpt, Debug)]
 struct StatusArgs {}
@@ -144,6 +234,9 @@ struct CommitArgs {
     message: String,
 
     refname: Option<String>,
+
+    #[structopt(flatten)]
+    put: PutArgs,
 }
 
 #[derive(StructOpt, Debug)]
@@ -162,6 +255,29 @@ struct LogArgs {
 struct InsertArgs {
     /// Path of the file to insert
     path: PathBuf,
+
+    #[structopt(flatten)]
+    put: PutArgs,
+}
+
+#[derive(StructOpt, Debug)]
+struct PutArgs {
+    /// Store what symlinks point to, rather than the links
+    #[structopt(long = "--follow-symlinks")]
+    follow_symlinks: bool,
+
+    /// Store extended attributes, including ACLs and SELinux labels
+    #[structopt(long = "--xattrs")]
+    xattrs: bool,
+}
+
+impl PutArgs {
+    fn options(&self) -> dir::PutOptions {
+        dir::PutOptions {
+            follow_symlinks: self.follow_symlinks,
+            xattrs: self.xattrs,
+        }
+    }
 }
 
 #[derive(StructOpt, Debug)]
@@ -171,6 +287,132 @@ struct FetchArgs {
 
     /// Destination path to write to
     dest: PathBuf,
+
+    #[structopt(flatten)]
+    restore: RestoreArgs,
+}
+
+#[derive(StructOpt, Debug)]
+struct RestoreArgs {
+    /// Files to write at once (defaults to the number of CPUs)
+    #[structopt(short = "j", long = "--jobs")]
+    jobs: Option<usize>,
+
+    /// Don't restore owners (by default they're only restored when running as root)
+    #[structopt(long = "--no-owner", conflicts_with = "same-owner")]
+    no_owner: bool,
+
+    /// Always restore owners, failing if that isn't allowed
+    #[structopt(long = "--same-owner")]
+    same_owner: bool,
+
+    /// Don't restore modification times
+    #[structopt(long = "--no-times")]
+    no_times: bool,
+
+    /// Don't restore extended attributes
+    #[structopt(long = "--no-xattrs")]
+    no_xattrs: bool,
+}
+
+impl RestoreArgs {
+    fn metadata(&self) -> RestoreMetadata {
+        RestoreMetadata {
+            owner: if self.no_owner {
+                RestoreOwner::Never
+            } else if self.same_owner {
+                RestoreOwner::Always
+            } else {
+                RestoreOwner::IfRoot
+            },
+            times: !self.no_times,
+            xattrs: !self.no_xattrs,
+        }
+    }
+
+    /// A read-only connection to the repository for each job.
+    fn readers(&self, ds_state: &DsState) -> Result<Vec<SqliteDS>, anyhow::Error> {
+        let jobs = match self.jobs {
+            Some(jobs) => jobs.max(1),
+            None => std::thread::available_parallelism().map_or(1, |n| n.get()),
+        };
+
+        let db = ds_state.db_folder_path.join("snapcd.db");
+
+        (0..jobs)
+            .map(|_| Ok(SqliteDS::open_read_only(&db)?))
+            .collect()
+    }
+}
+
+#[derive(StructOpt, Debug)]
+struct CatArgs {
+    /// File to print
+    key: Keyish,
+
+    /// Byte offset to start printing from
+    #[structopt(long = "--offset", default_value = "0")]
+    offset: u64,
+
+    /// Number of bytes to print, defaults to everything after the offset
+    #[structopt(long = "--length")]
+    length: Option<u64>,
+}
+
+#[derive(StructOpt, Debug)]
+struct AnalyzeArgs {
+    /// File or directory to analyze
+    path: PathBuf,
+
+    /// How many levels of subdirectories to report on
+    #[structopt(long = "--depth", default_value = "1")]
+    depth: usize,
+
+    /// Also report what committing the path on top of this would add to the repository
+    #[structopt(long = "--against")]
+    against: Option<Keyish>,
+}
+
+#[derive(StructOpt, Debug)]
+struct CatObjectArgs {
+    key: Keyish,
+
+    /// Print the object's type
+    #[structopt(long = "--type", conflicts_with_all = &["keys", "data"])]
+    objtype: bool,
+
+    /// Print the keys the object refers to, one per line
+    #[structopt(long = "--keys", conflicts_with_all = &["objtype", "data"])]
+    keys: bool,
+
+    /// Print the object's data section, as stored
+    #[structopt(long = "--data", conflicts_with_all = &["objtype", "keys"])]
+    data: bool,
+}
+
+#[derive(StructOpt, Debug)]
+struct HashObjectArgs {
+    /// File or directory to hash
+    path: PathBuf,
+
+    /// Hash algorithm to use, defaults to the repository's, or blake3 outside of one
+    #[structopt(long = "--hash")]
+    hash: Option<key::HashAlgorithm>,
+}
+
+#[derive(StructOpt, Debug)]
+struct RevParseArgs {
+    key: Keyish,
+}
+
+#[derive(StructOpt, Debug)]
+struct LsTreeArgs {
+    /// Directory to list, a commit lists its tree
+    key: Keyish,
+
+    /// List subdirectories too
+    #[structopt(short = "-r")]
+    recursive: bool,
 }
 
 #[derive(StructOpt, Debug)]
@@ -209,7 +451,88 @@ struct PrettyPrintArgs {
 }
 
 #[derive(StructOpt, Debug)]
-struct InitArgs {}
+struct InitArgs {
+    /// Make the repository append-only, refs can only move forward and nothing can be overwritten
+    #[structopt(long = "--append-only")]
+    append_only: bool,
+
+    /// Hash algorithm for new objects (blake3 or sha256)
+    #[structopt(long = "--hash", default_value = "blake3")]
+    hash: key::HashAlgorithm,
+
+    /// How to cut files into blobs (gear or fastcdc)
+    #[structopt(long = "--chunker", default_value = "gear")]
+    chunker: ChunkerAlgorithm,
+
+    /// Blobs are cut about every 2^N bytes, which is also the average size with fastcdc
+    #[structopt(long = "--chunk-bits")]
+    chunk_bits: Option<u32>,
+
+    /// Blobs are at least 2^N bytes, defaults to 4 times the cut spacing with gear, and a quarter
+    /// of it with fastcdc
+    #[structopt(long = "--min-chunk-bits")]
+    min_chunk_bits: Option<u32>,
+
+    /// Blobs are at most 2^N bytes, defaults to 8 times the average (fastcdc only)
+    #[structopt(long = "--max-chunk-bits")]
+    max_chunk_bits: Option<u32>,
+
+    /// Blob trees average 2^N children
+    #[structopt(long = "--fanout-bits")]
+    fanout_bits: Option<u32>,
+
+    /// Blob trees have at most 2^N children, defaults to 4 times the average
+    #[structopt(long = "--max-fanout-bits")]
+    max_fanout_bits: Option<u32>,
+
+    /// Runs of at least 2^N zero bytes are stored as just their length (defaults to 16), 0 turns
+    /// this off
+    #[structopt(long = "--zero-run-bits")]
+    zero_run_bits: Option<u32>,
+}
+
+impl InitArgs {
+    fn chunker_config(&self) -> ChunkerConfig {
+        let default = ChunkerConfig::default();
+
+        let blob_zero_count = self.chunk_bits.unwrap_or(default.blob_zero_count);
+        let per_level_count = self.fanout_bits.unwrap_or(default.per_level_count);
+
+        // Out of range sizes become 0, which validation rejects.
+        let size = |bits: u32| 1u32.checked_shl(bits).unwrap_or(0);
+
+        let zero_run_bits = match self.zero_run_bits {
+            Some(0) => None,
+            Some(bits) => Some(bits),
+            None => Some(chunker::DEFAULT_ZERO_RUN_BITS),
+        };
+
+        match self.chunker {
+            ChunkerAlgorithm::Gear => ChunkerConfig {
+                algorithm: ChunkerAlgorithm::Gear,
+                blob_zero_count,
+                blob_zero_count_max: self.min_chunk_bits.unwrap_or(blob_zero_count + 2),
+                per_level_count,
+                per_level_count_max: self.max_fanout_bits.unwrap_or(per_level_count + 2),
+                zero_run_bits,
+            },
+            ChunkerAlgorithm::FastCdc { .. } => ChunkerConfig {
+                algorithm: ChunkerAlgorithm::FastCdc {
+                    min_size: size(
+                        self.min_chunk_bits
+                            .unwrap_or_else(|| blob_zero_count.saturating_sub(2)),
+                    ),
+                    avg_size: size(blob_zero_count),
+                    max_size: size(self.max_chunk_bits.unwrap_or(blob_zero_count + 3)),
+                },
+                per_level_count,
+                per_level_count_max: self.max_fanout_bits.unwrap_or(per_level_count + 2),
+                zero_run_bits,
+                ..default
+            },
+        }
+    }
+}
 
 #[derive(StructOpt, Debug)]
 struct ReflogGetArgs {
@@ -234,20 +557,71 @@ struct CommitTreeArgs {
 #[error("database could not be found (maybe run snapcd init)")]
 struct DatabaseNotFoundError;
 
+#[derive(Debug, Error)]
+#[error("{} already has a repository in it", _0.display())]
+struct AlreadyInitializedError(PathBuf);
+
+#[derive(Debug, Error)]
+#[error("{_0} is a file, not a directory")]
+struct NotADirectoryError(key::Key);
+
 #[derive(Debug, Error)]
 #[error(
     "an operation that requires a HEAD was run, without being given one, and no head has been set"
 )]
 struct NoHeadError;
 
+/// Set once SIGINT arrives while [`catch_sigint`] is in effect.
+static INTERRUPTED: AtomicBool = AtomicBool::new(false);
+
+extern "C" fn on_sigint(_: libc::c_int) {
+    INTERRUPTED.store(true, Ordering::SeqCst);
+
+    // So a second Ctrl-C kills us straight away.
+    unsafe {
+        libc::signal(libc::SIGINT, libc::SIG_DFL);
+    }
+}
+
+/// Makes SIGINT set [`INTERRUPTED`] rather than kill the process.
+fn catch_sigint() {
+    unsafe {
+        libc::signal(libc::SIGINT, on_sigint as libc::sighandler_t);
+    }
+}
+
+/// Stores `path`, committing as it goes, and stopping cleanly at the next checkpoint on Ctrl-C.
+fn put_fs_item_interruptibly(
+    ds: &mut SqliteDS,
+    cache: &mut SqliteCache,
+    path: &Path,
+    filter: &dyn Fn(&std::fs::DirEntry) -> bool,
+    options: dir::PutOptions,
+) -> Result<key::Key, dir::PutFsItemError> {
+    catch_sigint();
+
+    let mut checkpoints = dir::Checkpoints::new(dir::DEFAULT_CHECKPOINT_BYTES, &INTERRUPTED);
+
+    dir::put_fs_item_checkpointed(ds, path, filter, options, cache, &mut checkpoints)
+}
+
 fn insert(state: &mut State, args: InsertArgs) -> CMDResult {
     let ds_state = state.ds_state.as_mut().ok_or(DatabaseNotFoundError)?;
 
     let filter = filter::make_filter_fn(&state.common.exclude, ds_state.db_folder_path.clone());
 
-    let hash = dir::put_fs_item(&mut ds_state.ds, &args.path, &filter)?;
+    let hash = put_fs_item_interruptibly(
+        &mut ds_state.ds,
+        &mut state.cache,
+        &args.path,
+        &filter,
+        args.put.options(),
+    )?;
 
-    println!("inserted hash {}", hash);
+    println!(
+        "inserted hash {}",
+        state.common.key_style().format(&ds_state.ds, hash)
+    );
 
     Ok(())
 }
@@ -255,9 +629,284 @@ fn insert(state: &mut State, args: InsertArgs) -> CMDResult {
 fn fetch(state: &mut State, args: FetchArgs) -> CMDResult {
     let ds_state = state.ds_state.as_ref().ok_or(DatabaseNotFoundError)?;
 
+    let key = ds_state.ds.resolve(args.key)?;
+
+    let readers = args.restore.readers(ds_state)?;
+    let stats = restore::get_fs_item(
+        &ds_state.ds,
+        key,
+        &args.dest,
+        args.restore.metadata(),
+        readers,
+    )?;
+
+    print_restore_stats(&stats);
+
+    Ok(())
+}
+
+fn print_restore_stats(stats: &restore::RestoreStats) {
+    println!(
+        "restored {} files ({}) in {:.2}s, {}/s",
+        stats.files,
+        human_bytes(stats.bytes),
+        stats.elapsed.as_secs_f64(),
+        human_bytes(stats.throughput() as u64)
+    );
+}
+
+fn cat(state: &mut State, args: CatArgs) -> CMDResult {
+    use std::io::{Read, Seek, SeekFrom};
+
+    let ds_state = state.ds_state.as_ref().ok_or(DatabaseNotFoundError)?;
+
+    let key = ds_state.ds.canonicalize(args.key)?;
+
+    let mut reader = file::BlobReader::new(&ds_state.ds, key)?;
+    reader.seek(SeekFrom::Start(args.offset))?;
+
+    let stdout = std::io::stdout();
+    let mut stdout = stdout.lock();
+
+    match args.length {
+        Some(length) => std::io::copy(&mut reader.take(length), &mut stdout)?,
+        None => std::io::copy(&mut reader, &mut stdout)?,
+    };
+
+    Ok(())
+}
+
+fn human_bytes(bytes: u64) -> String {
+    const UNITS: [&str; 5] = ["B", "KiB", "MiB", "GiB", "TiB"];
+
+    let mut size = bytes as f64;
+    let mut unit = 0;
+
+    while size >= 1024.0 && unit < UNITS.len() - 1 {
+        size /= 1024.0;
+        unit += 1;
+    }
+
+    if unit == 0 {
+        format!("{} B", bytes)
+    } else {
+        format!("{:.1} {}", size, UNITS[unit])
+    }
+}
+
+fn analyze_cmd(state: &mut State, args: AnalyzeArgs) -> CMDResult {
+    let (algorithm, chunker_config, db_folder_path) = match &state.ds_state {
+        Some(ds_state) => (
+            ds_state.ds.hash_algorithm()?,
+            ds_state.ds.chunker_config()?,
+            ds_state.db_folder_path.clone(),
+        ),
+        None => (
+            key::HashAlgorithm::default(),
+            ChunkerConfig::default(),
+            state.common.db_path.clone(),
+        ),
+    };
+
+    // Checked first, so a typo doesn't waste a long analysis.
+    let against: Option<key::TypedKey<commit::Commit>> = match (args.against, &state.ds_state) {
+        (Some(keyish), Some(ds_state)) => Some(ds_state.ds.resolve(keyish)?),
+        (Some(_), None) => return Err(DatabaseNotFoundError.into()),
+        (None, _) => None,
+    };
+
+    let filter = filter::make_filter_fn(&state.common.exclude, db_folder_path);
+
+    let (analysis, counted) =
+        analyze::analyze(&args.path, &filter, algorithm, chunker_config, args.depth)?;
+
+    println!(
+        "{} of file data, {} after deduplication ({:.2}x)",
+        human_bytes(analysis.total_bytes),
+        human_bytes(analysis.unique_bytes),
+        analysis.dedup_ratio()
+    );
+    println!(
+        "{} chunks, {} unique",
+        analysis.chunks, analysis.unique_chunks
+    );
+
+    println!();
+    println!("unique chunk sizes:");
+    for (bucket, count) in &analysis.histogram {
+        println!(
+            "  {:>9} - {:>9}: {}",
+            human_bytes(1 << bucket),
+            human_bytes(1 << (bucket + 1)),
+            count
+        );
+    }
+
+    if !analysis.directories.is_empty() {
+        println!();
+        println!("directories:");
+        for dir in &analysis.directories {
+            let name = if dir.path.as_os_str().is_empty() {
+                Path::new(".")
+            } else {
+                dir.path.as_path()
+            };
+
+            println!(
+                "  {:>10} {:>10} {:>7.2}x  {}",
+                human_bytes(dir.total_bytes),
+                human_bytes(dir.unique_bytes),
+                dir.dedup_ratio(),
+                name.display()
+            );
+        }
+    }
+
+    if let (Some(commit), Some(ds_state)) = (against, &state.ds_state) {
+        println!();
+
+        let tree = commit::Commit::from_key(&ds_state.ds, commit)?.tree();
+        let commit = state
+            .common
+            .key_style()
+            .format(&ds_state.ds, commit.inner());
+
+        if tree.inner() == analysis.root {
+            println!("identical to {}, nothing to commit", commit);
+        } else {
+            let incremental = analyze::incremental(&ds_state.ds, &counted)?;
+
+            // Plus one for the commit itself.
+            println!(
+                "committing on top of {} would add {} objects: {} of file data and {} of metadata",
+                commit,
+                incremental.new_objects + 1,
+                human_bytes(incremental.new_blob_bytes),
+                human_bytes(incremental.new_metadata_bytes)
+            );
+        }
+    }
+
+    Ok(())
+}
+
+fn cat_object(state: &mut State, args: CatObjectArgs) -> CMDResult {
+    use std::io::Write;
+
+    let ds_state = state.ds_state.as_ref().ok_or(DatabaseNotFoundError)?;
+
     let key = ds_state.ds.canonicalize(args.key)?;
 
-    dir::get_fs_item(&ds_state.ds, key.into(), &args.dest)?;
+    let stdout = std::io::stdout();
+    let mut stdout = stdout.lock();
+
+    if args.objtype {
+        let obj = ds_state.ds.get_obj(key)?;
+        writeln!(stdout, "{}", obj.type_name())?;
+    } else if args.keys {
+        let obj = ds_state.ds.get_obj(key)?;
+        for key in obj.keys() {
+            writeln!(stdout, "{}", key)?;
+        }
+    } else if args.data {
+        let obj = ds_state.ds.get_obj(key)?;
+        stdout.write_all(obj.data())?;
+    } else {
+        stdout.write_all(&ds_state.ds.get(key)?)?;
+    }
+
+    Ok(())
+}
+
+fn hash_object(state: &mut State, args: HashObjectArgs) -> CMDResult {
+    let (algorithm, chunker_config, db_folder_path) = match &state.ds_state {
+        Some(ds_state) => (
+            ds_state.ds.hash_algorithm()?,
+            ds_state.ds.chunker_config()?,
+            ds_state.db_folder_path.clone(),
+        ),
+        None => (
+            key::HashAlgorithm::default(),
+            ChunkerConfig::default(),
+            state.common.db_path.clone(),
+        ),
+    };
+
+    let mut ds = NullDS::new(args.hash.unwrap_or(algorithm), chunker_config);
+
+    let filter = filter::make_filter_fn(&state.common.exclude, db_folder_path);
+
+    let key = dir::put_fs_item(&mut ds, &args.path, &filter)?;
+
+    println!("{}", key);
+
+    Ok(())
+}
+
+fn rev_parse(state: &mut State, args: RevParseArgs) -> CMDResult {
+    let ds_state = state.ds_state.as_ref().ok_or(DatabaseNotFoundError)?;
+
+    println!("{}", ds_state.ds.canonicalize(args.key)?);
+
+    Ok(())
+}
+
+fn ls_tree(state: &mut State, args: LsTreeArgs) -> CMDResult {
+    let ds_state = state.ds_state.as_ref().ok_or(DatabaseNotFoundError)?;
+
+    let key = ds_state.ds.resolve(args.key)?;
+
+    print_tree(&ds_state.ds, key, Path::new(""), args.recursive)
+}
+
+fn print_tree(
+    ds: &impl DataStore,
+    key: key::TypedKey<dir::FSItem>,
+    prefix: &Path,
+    recursive: bool,
+) -> CMDResult {
+    let item: dir::FSItem = ds.get_obj(key.inner())?.try_into()?;
+
+    if !item.is_dir() {
+        return Err(NotADirectoryError(key.inner()).into());
+    }
+
+    for (name, child_key) in item.children() {
+        let child: dir::FSItem = ds.get_obj(child_key.inner())?.try_into()?;
+        let path = prefix.join(name);
+
+        let (mode, objtype, size) = if child.is_dir() {
+            ("040000", "dir", "-".to_string())
+        } else if let Some(special) = child.special() {
+            let (mode, objtype) = match special {
+                dir::SpecialFile::Fifo => ("010000", "fifo"),
+                dir::SpecialFile::CharDevice(_) => ("020000", "chardev"),
+                dir::SpecialFile::BlockDevice(_) => ("060000", "blockdev"),
+                dir::SpecialFile::Socket => ("140000", "socket"),
+            };
+
+            (mode, objtype, "-".to_string())
+        } else if child.is_symlink() {
+            ("120000", "link", child.size().to_string())
+        } else if child.metadata().map_or(false, |m| m.mode & 0o111 != 0) {
+            ("100755", "file", child.size().to_string())
+        } else {
+            ("100644", "file", child.size().to_string())
+        };
+
+        println!(
+            "{} {} {} {}\t{}",
+            mode,
+            objtype,
+            child_key,
+            size,
+            path.display()
+        );
+
+        if recursive && child.is_dir() {
+            print_tree(ds, child_key, &path, recursive)?;
+        }
+    }
 
     Ok(())
 }
@@ -290,8 +939,14 @@ fn ref_log(state: &mut State, args: RefLogArgs) -> CMDResult {
         "log entries are printed with most recent at top".bright_black()
     );
 
+    let style = state.common.key_style();
+
     for (idx, key) in keys.iter().enumerate() {
-        println!("{}: {}", keys.len() - idx, key);
+        println!(
+            "{}: {}",
+            keys.len() - idx,
+            style.format(&ds_state.ds, key.inner())
+        );
     }
 
     Ok(())
@@ -300,7 +955,7 @@ fn ref_log(state: &mut State, args: RefLogArgs) -> CMDResult {
 fn ref_update(state: &mut State, args: RefUpdateArgs) -> CMDResult {
     let ds_state = state.ds_state.as_mut().ok_or(DatabaseNotFoundError)?;
 
-    let key = ds_state.ds.canonicalize(args.key)?;
+    let key = ds_state.ds.resolve(args.key)?;
 
     let refname = match args.refname {
         Some(s) => s,
@@ -308,7 +963,7 @@ fn ref_update(state: &mut State, args: RefUpdateArgs) -> CMDResult {
     };
 
     let log = Reflog {
-        key: key.into(),
+        key,
         refname,
         remote: None,
     };
@@ -345,9 +1000,9 @@ fn debug_get_head(state: &mut State, _args: GetHeadArgs) -> CMDResult {
 fn debug_walk_tree(state: &mut State, args: WalkTreeArgs) -> CMDResult {
     let ds_state = state.ds_state.as_mut().ok_or(DatabaseNotFoundError)?;
 
-    let key = ds_state.ds.canonicalize(args.key)?;
+    let key = ds_state.ds.resolve(args.key)?;
 
-    let fs_items = dir::walk_fs_items(&ds_state.ds, key.into())?;
+    let fs_items = dir::walk_fs_items(&ds_state.ds, key)?;
 
     for item in fs_items {
         println!("{:?}", item)
@@ -381,23 +1036,18 @@ fn debug_pretty_print(state: &mut State, args: PrettyPrintArgs) -> CMDResult {
 fn debug_commit_tree(state: &mut State, args: CommitTreeArgs) -> CMDResult {
     let ds_state = state.ds_state.as_mut().ok_or(DatabaseNotFoundError)?;
 
-    let tree = ds_state.ds.canonicalize(args.tree)?;
+    let tree = ds_state.ds.resolve(args.tree)?;
 
     let mut parents = Vec::with_capacity(args.parents.len());
 
     for parent in args.parents {
-        let key = ds_state.ds.canonicalize(parent)?;
+        let key = ds_state.ds.resolve(parent)?;
         parents.push(key);
     }
 
     let attrs = commit::CommitAttrs::default();
 
-    let commit = commit::commit_tree(
-        &mut ds_state.ds,
-        tree.into(),
-        parents.iter().map(|&x| x.into()).collect(),
-        attrs,
-    )?;
+    let commit = commit::commit_tree(&mut ds_state.ds, tree, parents, attrs)?;
 
     println!("{}", commit);
 
@@ -419,10 +1069,10 @@ fn debug_reflog_get(state: &mut State, args: ReflogGetArgs) -> CMDResult {
 fn debug_reflog_push(state: &mut State, args: ReflogPushArgs) -> CMDResult {
     let ds_state = state.ds_state.as_mut().ok_or(DatabaseNotFoundError)?;
 
-    let key = ds_state.ds.canonicalize(args.key)?;
+    let key = ds_state.ds.resolve(args.key)?;
 
     let log = Reflog {
-        key: key.into(),
+        key,
         refname: args.refname,
         remote: args.remote,
     };
@@ -460,12 +1110,30 @@ fn get_head_key(ds: &impl DataStore) -> Result<key::TypedKey<commit::Commit>, an
     Ok(key)
 }
 
-fn init(state: &mut State, _args: InitArgs) -> CMDResult {
+fn init(state: &mut State, args: InitArgs) -> CMDResult {
+    let chunker_config = args.chunker_config();
+
+    // Checked before anything is created, so a typo doesn't leave a half made repository.
+    chunker_config.validate()?;
+
     std::fs::create_dir_all(&state.common.db_path)?;
     let ds = SqliteDS::new(&state.common.db_path.join("snapcd.db"))?;
 
+    // Otherwise its hash algorithm and chunking would be quietly replaced.
+    if ds.get_head()?.is_some() || !ds.reflog_names()?.is_empty() {
+        return Err(AlreadyInitializedError(state.common.db_path.clone()).into());
+    }
+
     ds.put_head("master")?;
 
+    ds.set_hash_algorithm(args.hash)?;
+
+    ds.set_chunker_config(&chunker_config)?;
+
+    if args.append_only {
+        ds.set_append_only()?;
+    }
+
     Ok(())
 }
 
@@ -492,7 +1160,13 @@ fn commit_cmd(state: &mut State, args: CommitArgs) -> CMDResult {
         Err(other) => return Err(other.into()),
     };
 
-    let key = dir::put_fs_item(&mut ds_state.ds, &commit_path, &filter)?;
+    let key = put_fs_item_interruptibly(
+        &mut ds_state.ds,
+        &mut state.cache,
+        &commit_path,
+        &filter,
+        args.put.options(),
+    )?;
 
     let mut attrs = commit::CommitAttrs::default();
 
@@ -519,7 +1193,12 @@ fn show(state: &mut State, args: ShowArgs) -> CMDResult {
         None => get_head_key(&ds_state.ds)?.inner(),
     };
 
-    display::display_obj(&mut ds_state.ds, key, display::Kind::Patch)?;
+    display::display_obj(
+        &mut ds_state.ds,
+        key,
+        display::Kind::Patch,
+        state.common.key_style(),
+    )?;
 
     Ok(())
 }
@@ -528,11 +1207,16 @@ fn log(state: &mut State, args: LogArgs) -> CMDResult {
     let ds_state = state.ds_state.as_mut().ok_or(DatabaseNotFoundError)?;
 
     let key = match args.key {
-        Some(k) => ds_state.ds.canonicalize(k)?,
-        None => get_head_key(&ds_state.ds)?.inner(),
+        Some(k) => ds_state.ds.resolve(k)?,
+        None => get_head_key(&ds_state.ds)?,
     };
 
-    display::log_obj(&mut ds_state.ds, key, display::Kind::Stat)?;
+    display::log_obj(
+        &mut ds_state.ds,
+        key,
+        display::Kind::Stat,
+        state.common.key_style(),
+    )?;
 
     Ok(())
 }
@@ -557,11 +1241,11 @@ fn compare(state: &mut State, args: CompareArgs) -> CMDResult {
     let ds_state = state.ds_state.as_mut().ok_or(DatabaseNotFoundError)?;
 
     let key = match args.key {
-        Some(k) => ds_state.ds.canonicalize(k)?.into(),
+        Some(k) => ds_state.ds.resolve(k)?,
         None => {
             let reflog = ds_state.ds.get_head()?.ok_or(NoHeadError)?;
             let key = ds_state.ds.reflog_get(&reflog, None)?;
-            commit::Commit::from_key(&ds_state.ds, key).tree()
+            commit::Commit::from_key(&ds_state.ds, key)?.tree()
         }
     };
 
@@ -582,7 +1266,7 @@ fn compare(state: &mut State, args: CompareArgs) -> CMDResult {
     )?;
 
     if args.stat {
-        diff::print_stat_diff_result(&ds_state.ds, result);
+        diff::print_stat_diff_result(&ds_state.ds, result)?;
     } else {
         diff::print_diff_result(result);
     }
@@ -601,15 +1285,13 @@ fn status(state: &mut State, _args: StatusArgs) -> CMDResult {
 
     match &ref_key {
         Some(k) => {
-            println!("HEAD: {} [{}]", reflog, &k.inner().as_user_key()[0..8]);
+            println!(
+                "HEAD: {} [{}]",
+                reflog,
+                state.common.key_style().format(&ds_state.ds, k.inner())
+            );
 
-            let obj: commit::Commit = ds_state
-                .ds
-                .get_obj(k.inner())
-                .unwrap()
-                .into_owned()
-                .try_into()
-                .unwrap();
+            let obj = commit::Commit::from_key(&ds_state.ds, *k)?;
 
             let result = diff::compare(
                 &mut ds_state.ds,
@@ -632,7 +1314,7 @@ fn status(state: &mut State, _args: StatusArgs) -> CMDResult {
     Ok(())
 }
 
-fn checkout(state: &mut State, _args: CheckoutArgs) -> CMDResult {
+fn checkout(state: &mut State, args: CheckoutArgs) -> CMDResult {
     let ds_state = state.ds_state.as_ref().ok_or(DatabaseNotFoundError)?;
 
     let reflog = ds_state.ds.get_head()?.ok_or(NoHeadError)?;
@@ -640,8 +1322,19 @@ fn checkout(state: &mut State, _args: CheckoutArgs) -> CMDResult {
 
     let filter = filter::make_filter_fn(&state.common.exclude, ds_state.db_folder_path.clone());
 
-    let tree_key = commit::Commit::from_key(&ds_state.ds, key).tree();
-    dir::checkout_fs_item(&ds_state.ds, tree_key, &ds_state.repo_path, &filter)?;
+    let tree_key = commit::Commit::from_key(&ds_state.ds, key)?.tree();
+    let readers = args.restore.readers(ds_state)?;
+    let stats = restore::checkout_fs_item(
+        &ds_state.ds,
+        tree_key,
+        &ds_state.repo_path,
+        &filter,
+        args.restore.metadata(),
+        readers,
+    )?;
+
+    print_restore_stats(&stats);
+
     Ok(())
 }
 
@@ -654,7 +1347,10 @@ fn checkout_head(state: &mut State, args: CheckoutHeadArgs) -> CMDResult {
 
     let ref_key = ds_state.ds.reflog_get(&reflog, None).ok();
 
-    let tree_key = ref_key.map(|key| commit::Commit::from_key(&ds_state.ds, key).tree());
+    let tree_key = match ref_key {
+        Some(key) => Some(commit::Commit::from_key(&ds_state.ds, key)?.tree()),
+        None => None,
+    };
 
     let result = diff::compare(
         &mut ds_state.ds,
@@ -681,6 +1377,116 @@ fn checkout_head(state: &mut State, args: CheckoutHeadArgs) -> CMDResult {
     Ok(())
 }
 
+fn parity_cmd(state: &mut State, args: ParityArgs) -> CMDResult {
+    let ds_state = state.ds_state.as_mut().ok_or(DatabaseNotFoundError)?;
+
+    let groups = parity::protect(&ds_state.ds, args.data_shards, args.parity_shards)?;
+
+    println!("created {} parity groups", groups.len());
+
+    Ok(())
+}
+
+fn fsck_cmd(state: &mut State, args: FsckArgs) -> CMDResult {
+    let ds_state = state.ds_state.as_mut().ok_or(DatabaseNotFoundError)?;
+
+    let report = fsck::fsck(&ds_state.ds)?;
+
+    println!("checked {} objects", report.checked);
+
+    if !report.noncanonical.is_empty() {
+        println!(
+            "{} objects are not canonically encoded (probably written by an older version)",
+            report.noncanonical.len().to_string().yellow()
+        );
+    }
+
+    if !report.unsorted.is_empty() {
+        println!(
+            "{} directories have their entries out of order (probably written by an older version)",
+            report.unsorted.len().to_string().yellow()
+        );
+    }
+
+    if report.is_clean() {
+        println!("{}", "no problems found".green());
+        return Ok(());
+    }
+
+    println!(
+        "{} corrupt, {} missing",
+        report.corrupt.len(),
+        report.missing.len()
+    );
+
+    if args.no_repair {
+        return Ok(());
+    }
+
+    let repaired = parity::repair(&ds_state.ds, &report.damaged())?;
+
+    println!(
+        "{} recovered, {} lost",
+        repaired.recovered.len().to_string().green(),
+        repaired.lost.len().to_string().red()
+    );
+
+    Ok(())
+}
+
+fn repair_cmd(state: &mut State, _args: RepairArgs) -> CMDResult {
+    let ds_state = state.ds_state.as_mut().ok_or(DatabaseNotFoundError)?;
+
+    let report = fsck::fsck(&ds_state.ds)?;
+
+    let repaired = parity::repair(&ds_state.ds, &report.damaged())?;
+
+    for key in &repaired.recovered {
+        println!("{} {}", "recovered:".green(), key);
+    }
+
+    for key in &repaired.lost {
+        println!("{} {}", "lost:".red(), key);
+    }
+
+    if repaired.recovered.is_empty() && repaired.lost.is_empty() {
+        println!("nothing to repair");
+    }
+
+    Ok(())
+}
+
+fn append_only_cmd(state: &mut State, _args: AppendOnlyArgs) -> CMDResult {
+    let ds_state = state.ds_state.as_mut().ok_or(DatabaseNotFoundError)?;
+
+    ds_state.ds.set_append_only()?;
+
+    println!("repository is now append-only");
+
+    Ok(())
+}
+
+fn rehash_cmd(state: &mut State, args: RehashArgs) -> CMDResult {
+    let ds_state = state.ds_state.as_mut().ok_or(DatabaseNotFoundError)?;
+
+    let report = rehash::rehash(&ds_state.ds, args.algorithm)?;
+
+    println!(
+        "rehashed {} objects to {}",
+        report.rewritten.len(),
+        args.algorithm
+    );
+
+    if !report.parity_groups.is_empty() {
+        println!(
+            "{} parity groups only cover the old objects, run parity to protect the new ones",
+            report.parity_groups.len()
+        );
+    }
+
+    Ok(())
+}
+
 fn setup_logging(#[allow(unused_variables)] level: u64) {
     #[cfg(feature = "logging")]
     {
@@ -773,6 +1579,7 @@ fn main() -> CMDResult {
     let result = match opt.cmd {
         Command::Insert(args) => insert(&mut state, args),
         Command::Fetch(args) => fetch(&mut state, args),
+        Command::Cat(args) => cat(&mut state, args),
         Command::Debug(args) => debug(&mut state, args),
         Command::Init(args) => init(&mut state, args),
         Command::Commit(args) => commit_cmd(&mut state, args),
@@ -783,6 +1590,16 @@ fn main() -> CMDResult {
         Command::Checkout(args) => checkout(&mut state, args),
         Command::CheckoutHead(args) => checkout_head(&mut state, args),
         Command::Ref(args) => ref_cmd(&mut state, args),
+        Command::Parity(args) => parity_cmd(&mut state, args),
+        Command::Fsck(args) => fsck_cmd(&mut state, args),
+        Command::Repair(args) => repair_cmd(&mut state, args),
+        Command::AppendOnly(args) => append_only_cmd(&mut state, args),
+        Command::Rehash(args) => rehash_cmd(&mut state, args),
+        Command::Analyze(args) => analyze_cmd(&mut state, args),
+        Command::CatObject(args) => cat_object(&mut state, args),
+        Command::HashObject(args) => hash_object(&mut state, args),
+        Command::RevParse(args) => rev_parse(&mut state, args),
+        Command::LsTree(args) => ls_tree(&mut state, args),
     };
 
     if let Err(e) = result {
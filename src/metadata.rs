@@ -0,0 +1,390 @@
+//! Unix file metadata: permissions, modification times and ownership.
+
+use std::cell::RefCell;
+use std::collections::HashMap;
+use std::ffi::{CStr, CString};
+use std::os::unix::ffi::OsStrExt;
+use std::os::unix::fs::{MetadataExt, OpenOptionsExt, PermissionsExt};
+use std::path::Path;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+/// What's recorded about a file or directory besides its contents.
+#[derive(Debug, Clone, PartialEq, Eq, serde::Serialize, serde::Deserialize)]
+pub struct UnixMetadata {
+    /// Permission bits, including setuid, setgid and sticky.
+    pub mode: u32,
+
+    /// Nanoseconds since the Unix epoch.
+    pub mtime_ns: i64,
+
+    pub uid: u32,
+    pub gid: u32,
+
+    /// The owner's name, if it had one, which is preferred to `uid` when restoring.
+    pub user: Option<String>,
+
+    /// The group's name, if it had one, which is preferred to `gid` when restoring.
+    pub group: Option<String>,
+}
+
+/// Whether to restore owners, which usually needs root.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RestoreOwner {
+    /// Leave items owned by whoever restores them.
+    Never,
+    /// Restore owners when running as root, like tar, and leave them alone otherwise.
+    IfRoot,
+    /// Restore owners, failing if that isn't allowed.
+    Always,
+}
+
+/// Which metadata to restore. Permissions are always restored.
+#[derive(Debug, Clone, Copy)]
+pub struct RestoreMetadata {
+    pub owner: RestoreOwner,
+
+    /// Restore modification times.
+    pub times: bool,
+
+    /// Restore extended attributes, for items stored with them.
+    pub xattrs: bool,
+}
+
+impl Default for RestoreMetadata {
+    fn default() -> Self {
+        Self {
+            owner: RestoreOwner::IfRoot,
+            times: true,
+            xattrs: true,
+        }
+    }
+}
+
+impl UnixMetadata {
+    pub fn from_fs(meta: &std::fs::Metadata) -> Self {
+        Self {
+            mode: meta.mode() & 0o7777,
+            mtime_ns: meta
+                .mtime()
+                .saturating_mul(1_000_000_000)
+                .saturating_add(meta.mtime_nsec()),
+            uid: meta.uid(),
+            gid: meta.gid(),
+            user: user_name(meta.uid()),
+            group: group_name(meta.gid()),
+        }
+    }
+
+    pub fn mtime(&self) -> SystemTime {
+        if self.mtime_ns >= 0 {
+            UNIX_EPOCH + Duration::from_nanos(self.mtime_ns as u64)
+        } else {
+            UNIX_EPOCH - Duration::from_nanos(self.mtime_ns.unsigned_abs())
+        }
+    }
+
+    /// The user and group to restore, by name where this system has them, or `None` if `options`
+    /// says to leave them.
+    fn owner(&self, options: RestoreMetadata) -> Option<(u32, u32)> {
+        // SAFETY: geteuid has no preconditions and can't fail.
+        match options.owner {
+            RestoreOwner::Never => return None,
+            RestoreOwner::IfRoot if unsafe { libc::geteuid() } != 0 => return None,
+            RestoreOwner::IfRoot | RestoreOwner::Always => {}
+        }
+
+        let uid = self
+            .user
+            .as_deref()
+            .and_then(uid_by_name)
+            .unwrap_or(self.uid);
+        let gid = self
+            .group
+            .as_deref()
+            .and_then(gid_by_name)
+            .unwrap_or(self.gid);
+
+        Some((uid, gid))
+    }
+
+    /// Applies this to `file`, which can be a directory.
+    pub fn restore(
+        &self,
+        file: &std::fs::File,
+        options: RestoreMetadata,
+    ) -> Result<(), std::io::Error> {
+        // Changing the owner can clear setuid and setgid, so it goes first.
+        if let Some((uid, gid)) = self.owner(options) {
+            std::os::unix::fs::fchown(file, Some(uid), Some(gid))?;
+        }
+
+        file.set_permissions(std::fs::Permissions::from_mode(self.mode))?;
+
+        if options.times {
+            file.set_modified(self.mtime())?;
+        }
+
+        Ok(())
+    }
+
+    /// Like [`restore`](UnixMetadata::restore), for a directory. Fails rather than following a
+    /// symlink at `path`.
+    pub fn restore_dir(&self, path: &Path, options: RestoreMetadata) -> Result<(), std::io::Error> {
+        let dir = std::fs::OpenOptions::new()
+            .read(true)
+            .custom_flags(libc::O_NOFOLLOW | libc::O_DIRECTORY)
+            .open(path)?;
+
+        self.restore(&dir, options)
+    }
+
+    /// Applies this to the symlink at `path`, rather than what it points to. Symlinks have no
+    /// permissions of their own, so only the owner and time are restored.
+    pub fn restore_symlink(
+        &self,
+        path: &Path,
+        options: RestoreMetadata,
+    ) -> Result<(), std::io::Error> {
+        if let Some((uid, gid)) = self.owner(options) {
+            std::os::unix::fs::lchown(path, Some(uid), Some(gid))?;
+        }
+
+        if options.times {
+            self.restore_mtime_at(path)?;
+        }
+
+        Ok(())
+    }
+
+    /// Applies this to the FIFO or device node at `path`, which can't be opened for
+    /// [`restore`](UnixMetadata::restore) without waiting for a writer or touching the device.
+    pub fn restore_special(
+        &self,
+        path: &Path,
+        options: RestoreMetadata,
+    ) -> Result<(), std::io::Error> {
+        if let Some((uid, gid)) = self.owner(options) {
+            std::os::unix::fs::lchown(path, Some(uid), Some(gid))?;
+        }
+
+        std::fs::set_permissions(path, std::fs::Permissions::from_mode(self.mode))?;
+
+        if options.times {
+            self.restore_mtime_at(path)?;
+        }
+
+        Ok(())
+    }
+
+    /// Sets the modification time of `path` itself, without opening it or following it if it's
+    /// a symlink.
+    fn restore_mtime_at(&self, path: &Path) -> Result<(), std::io::Error> {
+        let c_path = CString::new(path.as_os_str().as_bytes())?;
+
+        // Not the same width everywhere.
+        #[allow(trivial_numeric_casts)]
+        let mtime = libc::timespec {
+            tv_sec: self.mtime_ns.div_euclid(1_000_000_000) as libc::time_t,
+            tv_nsec: self.mtime_ns.rem_euclid(1_000_000_000) as libc::c_long,
+        };
+        let atime = libc::timespec {
+            tv_sec: 0,
+            tv_nsec: libc::UTIME_OMIT,
+        };
+
+        // SAFETY: c_path is NUL-terminated, and times has the two entries utimensat reads.
+        let times = [atime, mtime];
+        let ret = unsafe {
+            libc::utimensat(
+                libc::AT_FDCWD,
+                c_path.as_ptr(),
+                times.as_ptr(),
+                libc::AT_SYMLINK_NOFOLLOW,
+            )
+        };
+
+        if ret != 0 {
+            return Err(std::io::Error::last_os_error());
+        }
+
+        Ok(())
+    }
+}
+
+thread_local! {
+    static USER_NAMES: RefCell<HashMap<u32, Option<String>>> = RefCell::new(HashMap::new());
+    static GROUP_NAMES: RefCell<HashMap<u32, Option<String>>> = RefCell::new(HashMap::new());
+    static USER_IDS: RefCell<HashMap<String, Option<u32>>> = RefCell::new(HashMap::new());
+    static GROUP_IDS: RefCell<HashMap<String, Option<u32>>> = RefCell::new(HashMap::new());
+}
+
+/// Calls `f` with a buffer for a `get*_r` function, growing it until it's big enough. `f` gives
+/// back what it found, or the function's error.
+fn with_buffer<T>(
+    mut f: impl FnMut(&mut [libc::c_char]) -> Result<Option<T>, libc::c_int>,
+) -> Option<T> {
+    let mut buf = vec![0; 1024];
+
+    loop {
+        match f(&mut buf) {
+            Ok(found) => return found,
+            Err(libc::ERANGE) if buf.len() < 1 << 20 => {
+                let len = buf.len() * 2;
+                buf.resize(len, 0);
+            }
+            Err(_) => return None,
+        }
+    }
+}
+
+/// Reads a name out of a `passwd` or `group` that was filled in, while its buffer is still alive.
+///
+/// # Safety
+///
+/// `name` must point at a NUL-terminated string.
+unsafe fn read_name(name: *const libc::c_char) -> Option<String> {
+    CStr::from_ptr(name).to_str().ok().map(str::to_string)
+}
+
+fn user_name(uid: u32) -> Option<String> {
+    USER_NAMES.with(|names| {
+        names
+            .borrow_mut()
+            .entry(uid)
+            .or_insert_with(|| {
+                with_buffer(|buf| {
+                    // SAFETY: passwd is plain data, and is only read if getpwuid_r filled it in.
+                    let mut pwd: libc::passwd = unsafe { std::mem::zeroed() };
+                    let mut result = std::ptr::null_mut();
+
+                    match unsafe {
+                        libc::getpwuid_r(uid, &mut pwd, buf.as_mut_ptr(), buf.len(), &mut result)
+                    } {
+                        0 if result.is_null() => Ok(None),
+                        0 => Ok(unsafe { read_name(pwd.pw_name) }),
+                        err => Err(err),
+                    }
+                })
+            })
+            .clone()
+    })
+}
+
+fn group_name(gid: u32) -> Option<String> {
+    GROUP_NAMES.with(|names| {
+        names
+            .borrow_mut()
+            .entry(gid)
+            .or_insert_with(|| {
+                with_buffer(|buf| {
+                    // SAFETY: group is plain data, and is only read if getgrgid_r filled it in.
+                    let mut grp: libc::group = unsafe { std::mem::zeroed() };
+                    let mut result = std::ptr::null_mut();
+
+                    match unsafe {
+                        libc::getgrgid_r(gid, &mut grp, buf.as_mut_ptr(), buf.len(), &mut result)
+                    } {
+                        0 if result.is_null() => Ok(None),
+                        0 => Ok(unsafe { read_name(grp.gr_name) }),
+                        err => Err(err),
+                    }
+                })
+            })
+            .clone()
+    })
+}
+
+fn uid_by_name(name: &str) -> Option<u32> {
+    USER_IDS.with(|ids| {
+        *ids.borrow_mut().entry(name.to_string()).or_insert_with(|| {
+            let c_name = CString::new(name).ok()?;
+
+            with_buffer(|buf| {
+                // SAFETY: as in user_name.
+                let mut pwd: libc::passwd = unsafe { std::mem::zeroed() };
+                let mut result = std::ptr::null_mut();
+
+                match unsafe {
+                    libc::getpwnam_r(
+                        c_name.as_ptr(),
+                        &mut pwd,
+                        buf.as_mut_ptr(),
+                        buf.len(),
+                        &mut result,
+                    )
+                } {
+                    0 if result.is_null() => Ok(None),
+                    0 => Ok(Some(pwd.pw_uid)),
+                    err => Err(err),
+                }
+            })
+        })
+    })
+}
+
+fn gid_by_name(name: &str) -> Option<u32> {
+    GROUP_IDS.with(|ids| {
+        *ids.borrow_mut().entry(name.to_string()).or_insert_with(|| {
+            let c_name = CString::new(name).ok()?;
+
+            with_buffer(|buf| {
+                // SAFETY: as in group_name.
+                let mut grp: libc::group = unsafe { std::mem::zeroed() };
+                let mut result = std::ptr::null_mut();
+
+                match unsafe {
+                    libc::getgrnam_r(
+                        c_name.as_ptr(),
+                        &mut grp,
+                        buf.as_mut_ptr(),
+                        buf.len(),
+                        &mut result,
+                    )
+                } {
+                    0 if result.is_null() => Ok(None),
+                    0 => Ok(Some(grp.gr_gid)),
+                    err => Err(err),
+                }
+            })
+        })
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn mtimes_round_trip() {
+        for &ns in &[0, 1, 1_600_000_000_123_456_789, -1, -1_000_000_001] {
+            let meta = UnixMetadata {
+                mode: 0o644,
+                mtime_ns: ns,
+                uid: 0,
+                gid: 0,
+                user: None,
+                group: None,
+            };
+
+            let since = match meta.mtime().duration_since(UNIX_EPOCH) {
+                Ok(d) => d.as_nanos() as i64,
+                Err(e) => -(e.duration().as_nanos() as i64),
+            };
+
+            assert_eq!(since, ns);
+        }
+    }
+
+    #[test]
+    fn names_match_ids() {
+        if let Some(name) = user_name(0) {
+            assert_eq!(uid_by_name(&name), Some(0));
+        }
+
+        if let Some(name) = group_name(0) {
+            assert_eq!(gid_by_name(&name), Some(0));
+        }
+
+        assert_eq!(uid_by_name("no such user, surely"), None);
+    }
+}
@@ -5,21 +5,90 @@ use std::borrow::ToOwned;
 pub struct Object {
     data: serde_bytes::ByteBuf,
     keys: Vec<Key>,
-    objtype: ObjType,
+
+    /// Kept as the stored string, so that types from newer versions survive being read and
+    /// written back.
+    objtype: String,
 }
 
-#[derive(Debug, Clone, Copy, serde::Serialize, serde::Deserialize)]
+#[derive(Debug, Clone, Copy)]
 pub enum ObjType {
     FileBlobTree,
     FileBlob,
     Commit,
     FSItemDir,
     FSItemFile,
+    FSItemSymlink,
+
+    /// A FIFO, socket or device node.
+    FSItemSpecial,
+    ParityGroup,
+
+    /// The extended attributes of a file, directory or symlink.
+    XattrSet,
 
-    #[serde(other)]
+    /// A run of zero bytes in file data, stored as just its length.
+    ZeroRun,
+
+    /// A type this version doesn't know about. Only the object's `keys` can be relied on.
     Unknown,
 }
 
+impl ObjType {
+    /// The name objects of this type are stored with.
+    fn stored_name(self) -> &'static str {
+        match self {
+            ObjType::FileBlobTree => "FileBlobTree",
+            ObjType::FileBlob => "FileBlob",
+            ObjType::Commit => "Commit",
+            ObjType::FSItemDir => "FSItemDir",
+            ObjType::FSItemFile => "FSItemFile",
+            ObjType::FSItemSymlink => "FSItemSymlink",
+            ObjType::FSItemSpecial => "FSItemSpecial",
+            ObjType::ParityGroup => "ParityGroup",
+            ObjType::XattrSet => "XattrSet",
+            ObjType::ZeroRun => "ZeroRun",
+            ObjType::Unknown => "Unknown",
+        }
+    }
+
+    fn from_stored_name(name: &str) -> Self {
+        match name {
+            "FileBlobTree" => ObjType::FileBlobTree,
+            "FileBlob" => ObjType::FileBlob,
+            "Commit" => ObjType::Commit,
+            "FSItemDir" => ObjType::FSItemDir,
+            "FSItemFile" => ObjType::FSItemFile,
+            "FSItemSymlink" => ObjType::FSItemSymlink,
+            "FSItemSpecial" => ObjType::FSItemSpecial,
+            "ParityGroup" => ObjType::ParityGroup,
+            "XattrSet" => ObjType::XattrSet,
+            "ZeroRun" => ObjType::ZeroRun,
+            _ => ObjType::Unknown,
+        }
+    }
+}
+
+impl std::fmt::Display for ObjType {
+    fn fmt(&self, fmt: &mut std::fmt::Formatter<'_>) -> Result<(), std::fmt::Error> {
+        let name = match self {
+            ObjType::FileBlobTree => "file.blobtree",
+            ObjType::FileBlob => "file.blob",
+            ObjType::Commit => "commit.commit",
+            ObjType::FSItemDir => "dir.FSItem.dir",
+            ObjType::FSItemFile => "dir.FSItem.file",
+            ObjType::FSItemSymlink => "dir.FSItem.symlink",
+            ObjType::FSItemSpecial => "dir.FSItem.special",
+            ObjType::ParityGroup => "parity.group",
+            ObjType::XattrSet => "dir.xattrs",
+            ObjType::ZeroRun => "file.zerorun",
+            ObjType::Unknown => "unknown object",
+        };
+
+        fmt.write_str(name)
+    }
+}
+
 #[derive(Debug, Copy, Clone)]
 pub enum ObjectShowFormat {
     Oneline,
@@ -37,7 +106,7 @@ impl Object {
         Self {
             data: serde_bytes::ByteBuf::from(data),
             keys: keys.to_owned(),
-            objtype,
+            objtype: objtype.stored_name().to_string(),
         }
     }
 
@@ -45,16 +114,26 @@ impl Object {
         Self {
             data: serde_bytes::ByteBuf::from(data),
             keys,
-            objtype,
+            objtype: objtype.stored_name().to_string(),
         }
     }
 
+    /// The same object, pointing at `keys` instead. The type is kept as is, even if it's unknown.
+    pub fn with_keys(self, keys: Vec<Key>) -> Self {
+        Self { keys, ..self }
+    }
+
     pub fn debug_pretty_print(&self) -> Result<(), std::io::Error> {
         pretty_print(self, std::io::stdout().lock())
     }
 
     pub fn objtype(&self) -> ObjType {
-        self.objtype
+        ObjType::from_stored_name(&self.objtype)
+    }
+
+    /// The type as stored, which is the only way to tell unknown types apart.
+    pub fn type_name(&self) -> &str {
+        &self.objtype
     }
 
     pub fn keys(&self) -> &[Key] {
@@ -72,7 +151,7 @@ const DISPLAY_CHUNK_SIZE: usize = 20;
 // This will assume `to` is stdout and will color based on that (and envars)
 // see: https://docs.rs/colored/1.9.2/colored/control/index.html
 fn pretty_print(obj: &Object, mut to: impl std::io::Write) -> Result<(), std::io::Error> {
-    writeln!(to, "--type: {:?}--", obj.objtype)?;
+    writeln!(to, "--type: {}--", obj.objtype)?;
 
     writeln!(to, "--keys--")?;
     if !obj.keys.is_empty() {
@@ -0,0 +1,316 @@
+use crate::ds;
+use crate::key::{self, Key};
+use crate::object::ObjType;
+use crate::{DataStore, Object};
+use reed_solomon_erasure::galois_8::ReedSolomon;
+use std::collections::{HashMap, HashSet};
+use std::convert::TryInto;
+use thiserror::Error;
+
+/// Reed-Solomon parity over a batch of stored objects.
+///
+/// Each member's raw (encoded) data is zero padded to the length of the largest member and used as
+/// a data shard. Members are listed in the data section rather than `keys`, since a parity group
+/// doesn't depend on its members, and shouldn't keep them alive.
+#[derive(Debug, serde::Serialize, serde::Deserialize)]
+pub struct ParityGroup {
+    members: Vec<Key>,
+    lengths: Vec<u64>,
+    parity: Vec<serde_bytes::ByteBuf>,
+}
+
+impl ParityGroup {
+    pub fn members(&self) -> &[Key] {
+        &self.members
+    }
+}
+
+impl TryInto<Object> for ParityGroup {
+    type Error = serde_cbor::error::Error;
+
+    fn try_into(self) -> Result<Object, serde_cbor::error::Error> {
+        let data = crate::canonical::to_vec(&self)?;
+
+        Ok(Object::new_owned(data, vec![], ObjType::ParityGroup))
+    }
+}
+
+impl TryInto<ParityGroup> for Object {
+    type Error = serde_cbor::error::Error;
+
+    fn try_into(self) -> Result<ParityGroup, serde_cbor::error::Error> {
+        let group: ParityGroup = serde_cbor::from_slice(self.data())?;
+
+        if group.lengths.len() != group.members.len() {
+            return Err(serde::de::Error::custom(format!(
+                "parity group has {} members but {} lengths",
+                group.members.len(),
+                group.lengths.len()
+            )));
+        }
+
+        Ok(group)
+    }
+}
+
+#[derive(Debug, Error)]
+pub enum FindGroupsError {
+    #[error("error listing objects: {_0}")]
+    RawBetweenError(#[from] ds::RawBetweenError),
+
+    #[error("error parsing db key: {_0}")]
+    FromDbKeyError(#[from] key::FromDbKeyError),
+}
+
+fn all_keys<DS: DataStore>(ds: &DS) -> Result<Vec<Key>, FindGroupsError> {
+    let mut keys = Vec::new();
+
+    for db_key in ds.raw_between(&[], None)? {
+        keys.push(Key::from_db_key(&db_key)?);
+    }
+
+    Ok(keys)
+}
+
+/// Finds every readable parity group, returning a map from each member to the group covering it.
+pub fn find_groups<DS: DataStore>(ds: &DS) -> Result<HashMap<Key, Key>, FindGroupsError> {
+    let mut groups = HashMap::new();
+
+    for key in all_keys(ds)? {
+        let obj = match ds.get_obj(key) {
+            Ok(o) => o,
+            // Damaged objects are fsck's problem, we only care about groups we can read.
+            Err(_) => continue,
+        };
+
+        if let ObjType::ParityGroup = obj.objtype() {
+            let group: ParityGroup = match obj.try_into() {
+                Ok(g) => g,
+                Err(e) => {
+                    log::warn!("failed to decode parity group {}: {}", key, e);
+                    continue;
+                }
+            };
+
+            for &member in group.members() {
+                groups.insert(member, key);
+            }
+        }
+    }
+
+    Ok(groups)
+}
+
+#[derive(Debug, Error)]
+pub enum ProtectError {
+    #[error("error finding existing parity groups: {_0}")]
+    FindGroupsError(#[from] FindGroupsError),
+
+    #[error("error reading object: {_0}")]
+    RawGetError(#[from] ds::RawGetError),
+
+    #[error("error putting parity group: {_0}")]
+    PutObjError(#[from] ds::PutObjError),
+
+    #[error("error encoding parity group: {_0}")]
+    EncodeError(#[from] serde_cbor::error::Error),
+
+    #[error("error computing parity: {_0}")]
+    ReedSolomonError(#[from] reed_solomon_erasure::Error),
+
+    #[error("can't make groups of {data_shards} data and {parity_shards} parity shards, there must be at least one of each and at most 256 in total")]
+    ShardCountError {
+        data_shards: usize,
+        parity_shards: usize,
+    },
+}
+
+/// Computes parity groups for every object that isn't in one yet, returning the keys of the new
+/// groups.
+///
+/// Objects are sorted by size before being batched into groups of `data_shards`, so that members
+/// of a group are of similar sizes, and there's less padding.
+pub fn protect<DS: DataStore>(
+    ds: &DS,
+    data_shards: usize,
+    parity_shards: usize,
+) -> Result<Vec<Key>, ProtectError> {
+    if data_shards == 0 || parity_shards == 0 || data_shards + parity_shards > 256 {
+        return Err(ProtectError::ShardCountError {
+            data_shards,
+            parity_shards,
+        });
+    }
+
+    let covered = find_groups(ds)?;
+
+    // Only sizes are kept here, each batch's data is read again when it's encoded.
+    let mut uncovered = Vec::new();
+
+    for key in all_keys(ds)? {
+        if covered.contains_key(&key) {
+            continue;
+        }
+
+        let data = ds.get(key)?;
+
+        // Parity groups themselves aren't covered, fsck can find damaged ones and they can be
+        // regenerated.
+        match serde_cbor::from_slice::<Object>(&data) {
+            Ok(obj) if matches!(obj.objtype(), ObjType::ParityGroup) => continue,
+            _ => {}
+        }
+
+        uncovered.push((key, data.len()));
+    }
+
+    uncovered.sort_by_key(|&(key, len)| (len, key));
+
+    let mut new_groups = Vec::new();
+
+    for batch in uncovered.chunks(data_shards) {
+        let shard_len = batch.iter().map(|&(_, len)| len).max().unwrap_or(0);
+
+        let rs = ReedSolomon::new(batch.len(), parity_shards)?;
+
+        let mut lengths = Vec::with_capacity(batch.len());
+        let mut shards = Vec::with_capacity(batch.len() + parity_shards);
+
+        for &(key, _) in batch {
+            let mut shard = ds.get(key)?.into_owned();
+            lengths.push(shard.len() as u64);
+            shard.resize(shard_len, 0);
+            shards.push(shard);
+        }
+
+        shards.resize(batch.len() + parity_shards, vec![0; shard_len]);
+
+        rs.encode(&mut shards)?;
+
+        let group = ParityGroup {
+            members: batch.iter().map(|&(key, _)| key).collect(),
+            lengths,
+            parity: shards
+                .split_off(batch.len())
+                .into_iter()
+                .map(serde_bytes::ByteBuf::from)
+                .collect(),
+        };
+
+        let obj: Object = group.try_into()?;
+
+        new_groups.push(ds.put_obj(&obj)?);
+    }
+
+    Ok(new_groups)
+}
+
+#[derive(Debug, Default)]
+pub struct RepairReport {
+    pub recovered: Vec<Key>,
+    pub lost: Vec<Key>,
+}
+
+#[derive(Debug, Error)]
+pub enum RepairError {
+    #[error("error finding parity groups: {_0}")]
+    FindGroupsError(#[from] FindGroupsError),
+
+    #[error("error reading parity group: {_0}")]
+    GetObjError(#[from] ds::GetObjError),
+
+    #[error("error decoding parity group: {_0}")]
+    DecodeError(#[from] serde_cbor::error::Error),
+
+    #[error("error writing repaired object: {_0}")]
+    ReplaceError(#[from] ds::ReplaceError),
+}
+
+/// Tries to rebuild each of the `damaged` objects from the parity group covering it.
+///
+/// Objects which aren't in a group, or whose group has too many other damaged members, end up in
+/// [`RepairReport::lost`].
+pub fn repair<DS: DataStore>(ds: &DS, damaged: &[Key]) -> Result<RepairReport, RepairError> {
+    let groups = find_groups(ds)?;
+
+    let mut report = RepairReport::default();
+    let mut by_group: HashMap<Key, Vec<Key>> = HashMap::new();
+
+    for &key in damaged {
+        match groups.get(&key) {
+            Some(&group) => by_group.entry(group).or_default().push(key),
+            None => report.lost.push(key),
+        }
+    }
+
+    for (group_key, keys) in by_group {
+        let recovered = repair_group(ds, group_key)?;
+
+        for key in keys {
+            if recovered.contains(&key) {
+                report.recovered.push(key);
+            } else {
+                report.lost.push(key);
+            }
+        }
+    }
+
+    report.recovered.sort();
+    report.lost.sort();
+
+    Ok(report)
+}
+
+fn repair_group<DS: DataStore>(ds: &DS, group_key: Key) -> Result<HashSet<Key>, RepairError> {
+    let group: ParityGroup = ds.get_obj(group_key)?.try_into()?;
+
+    let shard_len = group.parity.first().map_or(0, |p| p.len());
+
+    let mut shards: Vec<Option<Vec<u8>>> = group
+        .members
+        .iter()
+        .map(|&member| match ds.get(member) {
+            Ok(data) if member.verify(&data) => {
+                let mut shard = data.into_owned();
+                shard.resize(shard_len, 0);
+                Some(shard)
+            }
+            _ => None,
+        })
+        .collect();
+
+    let damaged: Vec<usize> = (0..shards.len()).filter(|&i| shards[i].is_none()).collect();
+
+    shards.extend(group.parity.iter().map(|p| Some(p.to_vec())));
+
+    let reconstructed = ReedSolomon::new(group.members.len(), group.parity.len())
+        .and_then(|rs| rs.reconstruct_data(&mut shards));
+
+    if let Err(e) = reconstructed {
+        log::warn!("could not reconstruct parity group {}: {}", group_key, e);
+        return Ok(HashSet::new());
+    }
+
+    let mut recovered = HashSet::new();
+
+    for idx in damaged {
+        let member = group.members[idx];
+
+        let mut data = shards[idx].take().unwrap_or_default();
+        data.truncate(group.lengths[idx] as usize);
+
+        if !member.verify(&data) {
+            log::warn!(
+                "reconstructed {} from {} but it didn't match",
+                member,
+                group_key
+            );
+            continue;
+        }
+
+        ds.replace(member, &data)?;
+        recovered.insert(member);
+    }
+
+    Ok(recovered)
+}
@@ -0,0 +1,149 @@
+use crate::ds;
+use crate::key::{self, HashAlgorithm, Key};
+use crate::object::ObjType;
+use crate::{DataStore, Reflog};
+use std::collections::HashMap;
+use thiserror::Error;
+
+#[derive(Debug, Default)]
+pub struct RehashReport {
+    /// Every object that was rewritten, from its old key to its new one.
+    pub rewritten: HashMap<Key, Key>,
+
+    /// Parity groups that were left alone. They name their members by key, so they only cover the
+    /// old objects.
+    pub parity_groups: Vec<Key>,
+}
+
+#[derive(Debug, Error)]
+pub enum RehashError {
+    #[error("append-only repositories can't be rehashed")]
+    AppendOnly,
+
+    #[error("error checking append-only flag: {_0}")]
+    RawGetStateError(#[from] ds::RawGetStateError),
+
+    #[error("error writing state: {_0}")]
+    RawPutStateError(#[from] ds::RawPutStateError),
+
+    #[error("error listing objects: {_0}")]
+    RawBetweenError(#[from] ds::RawBetweenError),
+
+    #[error("error parsing db key: {_0}")]
+    FromDbKeyError(#[from] key::FromDbKeyError),
+
+    #[error("error reading object: {_0}")]
+    GetObjError(#[from] ds::GetObjError),
+
+    #[error("error writing rehashed object: {_0}")]
+    PutObjError(#[from] ds::PutObjError),
+
+    #[error("error listing refs: {_0}")]
+    RawReflogNamesError(#[from] ds::RawReflogNamesError),
+
+    #[error("error getting ref: {_0}")]
+    GetReflogError(#[from] ds::GetReflogError),
+
+    #[error("error moving ref: {_0}")]
+    ReflogPushError(#[from] ds::ReflogPushError),
+}
+
+/// Rewrites every object in the store to use `to`, and moves every ref to its rewritten commit.
+///
+/// Each object is rewritten after the objects it points to, so it can point to their new keys.
+/// The mapping from old keys to new is recorded in the store, and old keys given in full resolve to
+/// the rewritten objects. Old objects are left where they are.
+pub fn rehash<DS: DataStore>(ds: &DS, to: HashAlgorithm) -> Result<RehashReport, RehashError> {
+    if ds.is_append_only()? {
+        return Err(RehashError::AppendOnly);
+    }
+
+    ds.set_hash_algorithm(to)?;
+
+    let mut report = RehashReport::default();
+
+    for db_key in ds.raw_between(&[], None)? {
+        let key = Key::from_db_key(&db_key)?;
+
+        // Left by an earlier rehash away from `to`, and would lead this key back to an object in
+        // the algorithm it's leaving.
+        if key.algorithm() == to && ds.raw_get_state(&ds::rehash_state_key(&db_key))?.is_some() {
+            ds.raw_put_state(&ds::rehash_state_key(&db_key), &db_key)?;
+        }
+
+        rehash_key(ds, key, to, &mut report)?;
+    }
+
+    for (refname, remote) in ds.reflog_names()? {
+        let current = ds.reflog_get(&refname, remote.as_deref())?.inner();
+
+        if let Some(&new) = report.rewritten.get(&current) {
+            ds.reflog_push(&Reflog {
+                refname,
+                remote,
+                key: new.into(),
+            })?;
+        }
+    }
+
+    Ok(report)
+}
+
+fn rehash_key<DS: DataStore>(
+    ds: &DS,
+    key: Key,
+    to: HashAlgorithm,
+    report: &mut RehashReport,
+) -> Result<(), RehashError> {
+    // Trees and commit histories can be deep, so this keeps its own stack rather than recursing.
+    let mut stack = vec![key];
+
+    while let Some(&current) = stack.last() {
+        if current.algorithm() == to
+            || report.rewritten.contains_key(&current)
+            || report.parity_groups.contains(&current)
+        {
+            stack.pop();
+            continue;
+        }
+
+        let obj = ds.get_obj(current)?;
+
+        if let ObjType::ParityGroup = obj.objtype() {
+            report.parity_groups.push(current);
+            stack.pop();
+            continue;
+        }
+
+        let pending: Vec<Key> = obj
+            .keys()
+            .iter()
+            .filter(|k| k.algorithm() != to && !report.rewritten.contains_key(k))
+            .copied()
+            .collect();
+
+        if !pending.is_empty() {
+            stack.extend(pending);
+            continue;
+        }
+
+        stack.pop();
+
+        let keys = obj
+            .keys()
+            .iter()
+            .map(|k| *report.rewritten.get(k).unwrap_or(k))
+            .collect();
+
+        let new = ds.put_obj(&obj.with_keys(keys))?;
+
+        ds.raw_put_state(
+            &ds::rehash_state_key(&current.as_db_key()),
+            &new.as_db_key(),
+        )?;
+
+        report.rewritten.insert(current, new);
+    }
+
+    Ok(())
+}
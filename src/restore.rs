@@ -0,0 +1,167 @@
+//! Restoring trees with several threads writing files at once.
+//!
+//! The tree is walked first with the store that was passed in, which also creates and removes
+//! directories, then the files are shared out between workers that each read with their own
+//! store. Stores that can't be shared between threads, like [`SqliteDS`](crate::ds::sqlite::SqliteDS),
+//! can open one reader per worker.
+
+use crate::dir::{self, FSItem, RestorePlan};
+use crate::key::TypedKey;
+use crate::metadata::RestoreMetadata;
+use crate::DataStore;
+use std::fs::DirEntry;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering};
+use std::time::{Duration, Instant};
+use thiserror::Error;
+
+#[derive(Debug, Error)]
+pub enum RestoreError {
+    #[error("error walking tree to fetch: {_0}")]
+    GetFsItemError(#[from] dir::GetFsItemError),
+
+    #[error("error walking tree to check out: {_0}")]
+    CheckoutFsItemError(#[from] dir::CheckoutFsItemError),
+
+    #[error("error restoring {path}: {source}")]
+    RestoreFileError {
+        path: PathBuf,
+        source: dir::RestoreFileError,
+    },
+
+    #[error("error restoring directories, links or special files: {_0}")]
+    IOError(#[from] std::io::Error),
+
+    #[error("a restore needs at least one reader")]
+    NoReaders,
+}
+
+/// How a restore went.
+#[derive(Debug, Default)]
+pub struct RestoreStats {
+    pub files: u64,
+
+    /// Bytes of file data written, counting the holes left for runs of zeros.
+    pub bytes: u64,
+
+    pub elapsed: Duration,
+}
+
+impl RestoreStats {
+    /// Bytes written per second.
+    pub fn throughput(&self) -> f64 {
+        let secs = self.elapsed.as_secs_f64();
+
+        if secs == 0.0 {
+            0.0
+        } else {
+            self.bytes as f64 / secs
+        }
+    }
+}
+
+/// Like [`dir::get_fs_item`], with a worker thread for each of `readers`. Nothing that already
+/// exists is overwritten.
+pub fn get_fs_item<DS: DataStore, R: DataStore + Send>(
+    ds: &DS,
+    key: TypedKey<FSItem>,
+    path: &Path,
+    options: RestoreMetadata,
+    readers: Vec<R>,
+) -> Result<RestoreStats, RestoreError> {
+    let start = Instant::now();
+
+    let mut plan = RestorePlan::default();
+    dir::plan_get_fs_item(ds, key, path, None, &mut plan)?;
+
+    restore_plan(plan, options, readers, start)
+}
+
+/// Like [`dir::checkout_fs_item`], with a worker thread for each of `readers`.
+pub fn checkout_fs_item<DS: DataStore, R: DataStore + Send>(
+    ds: &DS,
+    key: TypedKey<FSItem>,
+    path: &Path,
+    filter: &dyn Fn(&DirEntry) -> bool,
+    options: RestoreMetadata,
+    readers: Vec<R>,
+) -> Result<RestoreStats, RestoreError> {
+    let start = Instant::now();
+
+    let mut plan = RestorePlan::default();
+    dir::plan_checkout_fs_item(ds, key, path, path, filter, None, &mut plan)?;
+
+    restore_plan(plan, options, readers, start)
+}
+
+fn restore_plan<R: DataStore + Send>(
+    mut plan: RestorePlan,
+    options: RestoreMetadata,
+    readers: Vec<R>,
+    start: Instant,
+) -> Result<RestoreStats, RestoreError> {
+    if readers.is_empty() {
+        return Err(RestoreError::NoReaders);
+    }
+
+    let files = &mut plan.files;
+
+    // Biggest first, so one big file started last doesn't leave the other workers idle.
+    files.sort_by_key(|file| std::cmp::Reverse(file.size));
+
+    let next = AtomicUsize::new(0);
+    let failed = AtomicBool::new(false);
+    let restored = AtomicU64::new(0);
+    let bytes = AtomicU64::new(0);
+
+    let worker = |reader: R| -> Result<(), RestoreError> {
+        while !failed.load(Ordering::Relaxed) {
+            let file = match files.get(next.fetch_add(1, Ordering::Relaxed)) {
+                Some(file) => file,
+                None => break,
+            };
+
+            if let Err(source) = file.restore(&reader, options) {
+                failed.store(true, Ordering::Relaxed);
+
+                return Err(RestoreError::RestoreFileError {
+                    path: file.path.clone(),
+                    source,
+                });
+            }
+
+            restored.fetch_add(1, Ordering::Relaxed);
+            bytes.fetch_add(file.size, Ordering::Relaxed);
+        }
+
+        Ok(())
+    };
+
+    let results: Vec<_> = std::thread::scope(|scope| {
+        let handles: Vec<_> = readers
+            .into_iter()
+            .map(|reader| scope.spawn(move || worker(reader)))
+            .collect();
+
+        handles
+            .into_iter()
+            .map(|handle| match handle.join() {
+                Ok(result) => result,
+                Err(panic) => std::panic::resume_unwind(panic),
+            })
+            .collect()
+    });
+
+    for result in results {
+        result?;
+    }
+
+    // Only now, as writing the files would change the directories' modification times.
+    plan.finish(options)?;
+
+    Ok(RestoreStats {
+        files: restored.into_inner(),
+        bytes: bytes.into_inner(),
+        elapsed: start.elapsed(),
+    })
+}
@@ -0,0 +1,231 @@
+//! Extended attributes, including POSIX ACLs, which Linux keeps as `system.posix_acl_access` and
+//! `system.posix_acl_default`.
+
+use crate::object::ObjType;
+use crate::Object;
+use serde_bytes::ByteBuf;
+use std::collections::BTreeMap;
+use std::convert::TryInto;
+use std::ffi::CString;
+use std::os::unix::ffi::OsStrExt;
+use std::path::Path;
+
+/// The extended attributes of a file, directory or symlink. Stored as an object of its own, so
+/// everything with the same attributes shares one.
+#[derive(Debug, Default, Clone, PartialEq, Eq, serde::Serialize, serde::Deserialize)]
+pub struct XattrSet {
+    attrs: BTreeMap<ByteBuf, ByteBuf>,
+}
+
+impl TryInto<Object> for XattrSet {
+    type Error = serde_cbor::error::Error;
+
+    fn try_into(self) -> Result<Object, serde_cbor::error::Error> {
+        let data = crate::canonical::to_vec(&self)?;
+
+        Ok(Object::new_owned(data, vec![], ObjType::XattrSet))
+    }
+}
+
+impl TryInto<XattrSet> for Object {
+    type Error = serde_cbor::error::Error;
+
+    fn try_into(self) -> Result<XattrSet, serde_cbor::error::Error> {
+        serde_cbor::from_slice(self.data())
+    }
+}
+
+/// A path to get or set attributes on, and whether to go through it if it's a symlink.
+struct Target {
+    path: CString,
+    follow_symlinks: bool,
+}
+
+impl Target {
+    fn new(path: &Path, follow_symlinks: bool) -> Result<Self, std::io::Error> {
+        Ok(Self {
+            path: CString::new(path.as_os_str().as_bytes())?,
+            follow_symlinks,
+        })
+    }
+
+    fn list(&self, buf: &mut [u8]) -> isize {
+        let list = buf.as_mut_ptr().cast();
+
+        // SAFETY: the path is NUL-terminated, and nothing past `buf.len()` is written.
+        unsafe {
+            if self.follow_symlinks {
+                libc::listxattr(self.path.as_ptr(), list, buf.len())
+            } else {
+                libc::llistxattr(self.path.as_ptr(), list, buf.len())
+            }
+        }
+    }
+
+    fn get(&self, name: &CString, buf: &mut [u8]) -> isize {
+        let value = buf.as_mut_ptr().cast();
+
+        // SAFETY: as in list.
+        unsafe {
+            if self.follow_symlinks {
+                libc::getxattr(self.path.as_ptr(), name.as_ptr(), value, buf.len())
+            } else {
+                libc::lgetxattr(self.path.as_ptr(), name.as_ptr(), value, buf.len())
+            }
+        }
+    }
+
+    fn set(&self, name: &CString, value: &[u8]) -> Result<(), std::io::Error> {
+        let ptr = value.as_ptr().cast();
+
+        // SAFETY: the path and name are NUL-terminated, and nothing past `value.len()` is read.
+        let ret = unsafe {
+            if self.follow_symlinks {
+                libc::setxattr(self.path.as_ptr(), name.as_ptr(), ptr, value.len(), 0)
+            } else {
+                libc::lsetxattr(self.path.as_ptr(), name.as_ptr(), ptr, value.len(), 0)
+            }
+        };
+
+        if ret != 0 {
+            return Err(std::io::Error::last_os_error());
+        }
+
+        Ok(())
+    }
+}
+
+/// Calls `f` once to find how big a buffer it needs, then again to fill it, starting over if it
+/// grew in between.
+fn sized(mut f: impl FnMut(&mut [u8]) -> isize) -> Result<Vec<u8>, std::io::Error> {
+    loop {
+        let len = f(&mut []);
+
+        if len < 0 {
+            return Err(std::io::Error::last_os_error());
+        }
+
+        let mut buf = vec![0; len as usize];
+        let len = f(&mut buf);
+
+        if len >= 0 {
+            buf.truncate(len as usize);
+            return Ok(buf);
+        }
+
+        let err = std::io::Error::last_os_error();
+
+        if err.raw_os_error() != Some(libc::ERANGE) {
+            return Err(err);
+        }
+    }
+}
+
+impl XattrSet {
+    /// Reads the attributes of `path`, or of what it points to with `follow_symlinks`. A file
+    /// system without extended attributes gives an empty set.
+    pub fn read(path: &Path, follow_symlinks: bool) -> Result<Self, std::io::Error> {
+        let target = Target::new(path, follow_symlinks)?;
+
+        let names = match sized(|buf| target.list(buf)) {
+            Ok(names) => names,
+            Err(e) if e.raw_os_error() == Some(libc::ENOTSUP) => return Ok(Self::default()),
+            Err(e) => return Err(e),
+        };
+
+        let mut attrs = BTreeMap::new();
+
+        for name in names.split(|&b| b == 0).filter(|name| !name.is_empty()) {
+            let c_name = CString::new(name)?;
+
+            match sized(|buf| target.get(&c_name, buf)) {
+                Ok(value) => {
+                    attrs.insert(ByteBuf::from(name), ByteBuf::from(value));
+                }
+                // Removed since it was listed.
+                Err(e) if e.raw_os_error() == Some(libc::ENODATA) => {}
+                Err(e) => return Err(e),
+            }
+        }
+
+        Ok(Self { attrs })
+    }
+
+    /// Sets these attributes on `path`, or on what it points to with `follow_symlinks`.
+    ///
+    /// Attributes that aren't permitted (say `trusted.*` without root) are skipped, as is
+    /// everything if the file system doesn't support them, with a warning either way.
+    pub fn apply(&self, path: &Path, follow_symlinks: bool) -> Result<(), std::io::Error> {
+        let target = Target::new(path, follow_symlinks)?;
+
+        for (name, value) in &self.attrs {
+            let c_name = CString::new(name.as_slice())?;
+
+            match target.set(&c_name, value) {
+                Ok(()) => {}
+                Err(e) if e.raw_os_error() == Some(libc::ENOTSUP) => {
+                    log::warn!(
+                        "{} doesn't support extended attributes, so none were restored",
+                        path.display()
+                    );
+                    return Ok(());
+                }
+                Err(e) if matches!(e.raw_os_error(), Some(libc::EPERM) | Some(libc::EACCES)) => {
+                    log::warn!(
+                        "not permitted to restore extended attribute {} on {}",
+                        String::from_utf8_lossy(name),
+                        path.display()
+                    );
+                }
+                Err(e) => return Err(e),
+            }
+        }
+
+        Ok(())
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.attrs.is_empty()
+    }
+
+    /// Each attribute's name and value.
+    pub fn iter(&self) -> impl Iterator<Item = (&[u8], &[u8])> {
+        self.attrs
+            .iter()
+            .map(|(name, value)| (name.as_slice(), value.as_slice()))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn user_attributes_round_trip() {
+        let dir = std::env::temp_dir().join(format!("snapcd-xattr-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+
+        let from = dir.join("from");
+        let to = dir.join("to");
+        std::fs::write(&from, "").unwrap();
+        std::fs::write(&to, "").unwrap();
+
+        let name = CString::new("user.snapcd.test").unwrap();
+        if let Err(e) = Target::new(&from, false).unwrap().set(&name, b"\0value") {
+            // Not every file system the tests run on has user attributes.
+            assert_eq!(e.raw_os_error(), Some(libc::ENOTSUP));
+            std::fs::remove_dir_all(&dir).unwrap();
+            return;
+        }
+
+        let attrs = XattrSet::read(&from, false).unwrap();
+        assert!(attrs
+            .iter()
+            .any(|(name, value)| name == b"user.snapcd.test" && value == b"\0value"));
+
+        attrs.apply(&to, false).unwrap();
+        assert_eq!(XattrSet::read(&to, false).unwrap(), attrs);
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -1,7 +1,12 @@
 use rand::prelude::*;
 use rand_chacha::ChaChaRng;
+use snapcd::chunker::ChunkerConfig;
 use snapcd::file::{put_data, read_data};
-use snapcd::{ds::sqlite::SqliteDS, DataStore};
+use snapcd::{
+    commit, ds::AppendOnlyError, ds::CanonicalizeError, ds::ReflogPushError, ds::ResolveError,
+    fsck, key::Key, parity, Keyish, Reflog,
+};
+use snapcd::{ds::mirror::MirrorDS, ds::sharded::ShardedDS, ds::sqlite::SqliteDS, DataStore};
 use std::collections::HashSet;
 
 fn internal_test<T: DataStore, F: FnMut() -> T>(
@@ -21,7 +26,7 @@ fn internal_test<T: DataStore, F: FnMut() -> T>(
 
         rng.fill(&mut test_vector[..]);
 
-        let hash = put_data(&mut data, &test_vector[..]).unwrap();
+        let hash = put_data(&mut data, &test_vector[..], &ChunkerConfig::default()).unwrap();
 
         let mut to = Vec::new();
 
@@ -43,12 +48,2052 @@ fn sanity_check() {
     internal_test(&mut sqlite_ds, 1 << 10, 64, 128);
 }
 
+fn sharded_memory_ds(shard_count: usize) -> ShardedDS {
+    let shards = (0..shard_count)
+        .map(|_| SqliteDS::new(":memory:").unwrap())
+        .collect();
+
+    ShardedDS::from_shards(shards).unwrap()
+}
+
+#[test]
+fn sanity_check_sharded() {
+    let mut sharded_ds = || sharded_memory_ds(4);
+
+    internal_test(&mut sharded_ds, 1 << 20, 0, 4);
+    internal_test(&mut sharded_ds, 1 << 14, 4, 32);
+}
+
+#[test]
+fn sharded_rollback_discards_all_shards() {
+    use snapcd::ds::Transactional;
+
+    let mut ds = sharded_memory_ds(4);
+
+    ds.begin_trans().unwrap();
+
+    let keys: Vec<_> = (0..64_u8).map(|x| ds.put(vec![x]).unwrap()).collect();
+
+    for key in &keys {
+        assert!(ds.raw_exists(&key.as_db_key()).unwrap());
+    }
+
+    ds.rollback().unwrap();
+
+    for key in &keys {
+        assert!(!ds.raw_exists(&key.as_db_key()).unwrap());
+    }
+}
+
+#[test]
+fn sharded_commit_rolls_back_what_it_cant_commit() {
+    use snapcd::ds::{CommitTransError, Transactional};
+
+    let dir = std::path::Path::new(env!("CARGO_TARGET_TMPDIR"))
+        .join("sharded_commit_rolls_back_what_it_cant_commit");
+    let _ = std::fs::remove_dir_all(&dir);
+    std::fs::create_dir_all(&dir).unwrap();
+
+    let mut ds = ShardedDS::new(&dir, 2).unwrap();
+    ds.begin_trans().unwrap();
+
+    let keys: Vec<_> = (0..16_u8).map(|x| ds.put(vec![x]).unwrap()).collect();
+
+    // A reader on the state shard stops it committing, once the other shard already has.
+    let reader = rusqlite::Connection::open(dir.join("shard-0.db")).unwrap();
+    reader
+        .execute_batch("BEGIN; SELECT * FROM sqlite_master;")
+        .unwrap();
+
+    assert!(matches!(
+        ds.commit(),
+        Err(CommitTransError::Partial {
+            committed: 1,
+            total: 2,
+            ..
+        })
+    ));
+
+    drop(reader);
+    drop(ds);
+
+    let ds = ShardedDS::new(&dir, 2).unwrap();
+    let stored = keys
+        .iter()
+        .filter(|key| ds.raw_exists(&key.as_db_key()).unwrap())
+        .count();
+    assert!(stored > 0 && stored < keys.len());
+
+    drop(ds);
+    std::fs::remove_dir_all(&dir).unwrap();
+}
+
+fn mirror_memory_ds(heal: bool) -> MirrorDS {
+    let children: Vec<Box<dyn DataStore>> = vec![
+        Box::new(SqliteDS::new(":memory:").unwrap()),
+        Box::new(SqliteDS::new(":memory:").unwrap()),
+    ];
+
+    MirrorDS::new(children, heal).unwrap()
+}
+
+#[test]
+fn sanity_check_mirror() {
+    let mut mirror_ds = || mirror_memory_ds(false);
+
+    internal_test(&mut mirror_ds, 1 << 20, 0, 4);
+    internal_test(&mut mirror_ds, 1 << 14, 4, 32);
+}
+
+#[test]
+fn mirror_falls_back_and_heals() {
+    let mut ds = mirror_memory_ds(true);
+    let mut rng = ChaChaRng::seed_from_u64(0);
+
+    let mut test_vector = vec![0; 1 << 16];
+    rng.fill(&mut test_vector[..]);
+
+    let hash = put_data(&mut ds, &test_vector[..], &ChunkerConfig::default()).unwrap();
+
+    let all_keys = ds.raw_between(&[], None).unwrap();
+
+    for db_key in &all_keys {
+        ds.children()[0].raw_replace(db_key, b"garbage").unwrap();
+    }
+
+    let mut to = Vec::new();
+    read_data(&ds, hash, &mut to).unwrap();
+    assert_eq!(to, test_vector);
+
+    // Reading should write the good copies back over the corrupt ones.
+    for db_key in &all_keys {
+        ds.raw_get(db_key).unwrap();
+
+        let healed = ds.children()[0].raw_get(db_key).unwrap();
+        assert_eq!(healed, ds.children()[1].raw_get(db_key).unwrap());
+    }
+}
+
+#[test]
+fn parity_repairs_corrupt_objects() {
+    let mut ds = SqliteDS::new(":memory:").unwrap();
+    let mut rng = ChaChaRng::seed_from_u64(0);
+
+    let mut test_vector = vec![0; 1 << 18];
+    rng.fill(&mut test_vector[..]);
+
+    let hash = put_data(&mut ds, &test_vector[..], &ChunkerConfig::default()).unwrap();
+
+    parity::protect(&ds, 4, 2).unwrap();
+    assert!(fsck::fsck(&ds).unwrap().is_clean());
+
+    let groups = parity::find_groups(&ds).unwrap();
+
+    // Damage one member from each of two different groups.
+    let mut damaged_groups = HashSet::new();
+    let damaged: Vec<_> = groups
+        .iter()
+        .filter(|(_, group)| damaged_groups.insert(**group))
+        .map(|(member, _)| *member)
+        .take(2)
+        .collect();
+
+    for key in &damaged {
+        ds.raw_replace(&key.as_db_key(), b"garbage").unwrap();
+    }
+
+    let report = fsck::fsck(&ds).unwrap();
+    assert_eq!(report.corrupt.len(), 2);
+
+    let repaired = parity::repair(&ds, &report.damaged()).unwrap();
+    assert_eq!(repaired.recovered.len(), 2);
+    assert!(repaired.lost.is_empty());
+
+    assert!(fsck::fsck(&ds).unwrap().is_clean());
+
+    let mut to = Vec::new();
+    read_data(&ds, hash, &mut to).unwrap();
+    assert_eq!(to, test_vector);
+}
+
+#[test]
+fn parity_reports_unrecoverable_objects() {
+    let mut ds = SqliteDS::new(":memory:").unwrap();
+
+    for x in 0..4_u8 {
+        put_data(&mut ds, &[x; 100][..], &ChunkerConfig::default()).unwrap();
+    }
+
+    parity::protect(&ds, 4, 1).unwrap();
+
+    let groups = parity::find_groups(&ds).unwrap();
+    assert_eq!(groups.len(), 4);
+
+    // With a single parity shard, two damaged members in the same group can't be recovered.
+    for key in groups.keys().take(2) {
+        ds.raw_replace(&key.as_db_key(), b"garbage").unwrap();
+    }
+
+    let report = fsck::fsck(&ds).unwrap();
+    let repaired = parity::repair(&ds, &report.damaged()).unwrap();
+
+    assert!(repaired.recovered.is_empty());
+    assert_eq!(repaired.lost.len(), 2);
+}
+
+#[test]
+fn parity_rejects_bad_groups() {
+    use snapcd::object::ObjType;
+    use snapcd::Object;
+
+    let mut ds = SqliteDS::new(":memory:").unwrap();
+    let key = put_data(&mut ds, &[1; 100][..], &ChunkerConfig::default()).unwrap();
+
+    assert!(matches!(
+        parity::protect(&ds, 0, 2),
+        Err(parity::ProtectError::ShardCountError { .. })
+    ));
+    assert!(matches!(
+        parity::protect(&ds, 200, 100),
+        Err(parity::ProtectError::ShardCountError { .. })
+    ));
+
+    // A group whose lengths don't line up with its members is ignored, rather than trusted.
+    #[derive(serde::Serialize)]
+    struct BadGroup {
+        members: Vec<Key>,
+        lengths: Vec<u64>,
+        parity: Vec<serde_bytes::ByteBuf>,
+    }
+
+    let data = serde_cbor::to_vec(&BadGroup {
+        members: vec![key],
+        lengths: vec![],
+        parity: vec![serde_bytes::ByteBuf::from(vec![0; 100])],
+    })
+    .unwrap();
+    ds.put_obj(&Object::new(&data, &[], ObjType::ParityGroup))
+        .unwrap();
+
+    assert!(parity::find_groups(&ds).unwrap().is_empty());
+
+    ds.raw_replace(&key.as_db_key(), b"garbage").unwrap();
+    let repaired = parity::repair(&ds, &[key]).unwrap();
+    assert_eq!(repaired.lost, vec![key]);
+}
+
+#[test]
+fn append_only_refs_only_move_forward() {
+    let mut ds = SqliteDS::new(":memory:").unwrap();
+
+    let tree = put_data(&mut ds, &b"tree"[..], &ChunkerConfig::default())
+        .unwrap()
+        .into();
+    let mut commit = |parents| {
+        commit::commit_tree(&mut ds, tree, parents, commit::CommitAttrs::default()).unwrap()
+    };
+
+    let first = commit(vec![]);
+    let second = commit(vec![first]);
+    let mut attrs = commit::CommitAttrs::default();
+    attrs.set_message("unrelated".to_string());
+    let unrelated = commit::commit_tree(&mut ds, tree, vec![], attrs).unwrap();
+
+    ds.set_append_only().unwrap();
+
+    // Trying to turn it back off doesn't work.
+    ds.raw_put_state(b"append_only", b"0").unwrap();
+    assert!(ds.is_append_only().unwrap());
+
+    let push = |key| Reflog {
+        refname: "master".to_string(),
+        key,
+        remote: None,
+    };
+
+    ds.reflog_push(&push(first)).unwrap();
+    ds.reflog_push(&push(second)).unwrap();
+
+    assert!(matches!(
+        ds.reflog_push(&push(unrelated)),
+        Err(ReflogPushError::AppendOnly(
+            AppendOnlyError::NotDescendant { .. }
+        ))
+    ));
+    assert!(matches!(
+        ds.reflog_push(&push(first)),
+        Err(ReflogPushError::AppendOnly(
+            AppendOnlyError::NotDescendant { .. }
+        ))
+    ));
+
+    assert_eq!(ds.reflog_get("master", None).unwrap(), second);
+
+    assert!(ds.replace(second.inner(), b"garbage").is_err());
+}
+
+#[test]
+fn canonicalize_revisions() {
+    use std::str::FromStr;
+
+    let mut ds = SqliteDS::new(":memory:").unwrap();
+
+    let tree = put_data(&mut ds, &b"tree"[..], &ChunkerConfig::default()).unwrap();
+    let mut commit = |parents, msg: &str| {
+        let mut attrs = commit::CommitAttrs::default();
+        attrs.set_message(msg.to_string());
+        commit::commit_tree(&mut ds, tree.into(), parents, attrs).unwrap()
+    };
+
+    let root = commit(vec![], "root");
+    let left = commit(vec![root], "left");
+    let right = commit(vec![root], "right");
+    let merge = commit(vec![left, right], "merge");
+
+    ds.put_head("master").unwrap();
+    ds.reflog_push(&Reflog {
+        refname: "master".to_string(),
+        key: merge,
+        remote: None,
+    })
+    .unwrap();
+
+    let resolve = |s: &str| ds.canonicalize(Keyish::from_str(s).unwrap());
+
+    // Parents are stored sorted, so we can't assume they're in the order we gave them.
+    let parents = commit::Commit::from_key(&ds, merge)
+        .unwrap()
+        .parents()
+        .to_vec();
+
+    assert_eq!(resolve("HEAD").unwrap(), merge.inner());
+    assert_eq!(resolve("master^0").unwrap(), merge.inner());
+    assert_eq!(resolve("master~").unwrap(), parents[0].inner());
+    assert_eq!(resolve("/master^2").unwrap(), parents[1].inner());
+    assert_eq!(resolve("HEAD~2").unwrap(), root.inner());
+    assert_eq!(resolve("master^2~1").unwrap(), root.inner());
+
+    assert!(matches!(
+        resolve("master~3"),
+        Err(CanonicalizeError::NoSuchParent {
+            parent: 1,
+            count: 0,
+            ..
+        })
+    ));
+    assert!(matches!(
+        resolve("master^3"),
+        Err(CanonicalizeError::NoSuchParent {
+            parent: 3,
+            count: 2,
+            ..
+        })
+    ));
+
+    // A ref that reads as a key prefix too is found first, even when a key has that prefix.
+    let prefix = tree.as_user_key()[0..6].to_string();
+    assert_eq!(resolve(&prefix).unwrap(), tree);
+
+    for name in &["bar", prefix.as_str()] {
+        ds.reflog_push(&Reflog {
+            refname: name.to_string(),
+            key: root,
+            remote: None,
+        })
+        .unwrap();
+        assert!(matches!(Keyish::from_str(name), Ok(Keyish::Range(..))));
+        assert_eq!(resolve(name).unwrap(), root.inner());
+        assert_eq!(resolve(&format!("{}^0", name)).unwrap(), root.inner());
+    }
+
+    let tree_prefix = format!("{}~1", &tree.as_user_key()[0..20]);
+    assert!(matches!(
+        resolve(&tree_prefix),
+        Err(CanonicalizeError::NotACommit { .. })
+    ));
+}
+
+#[test]
+fn abbreviated_keys_are_unique() {
+    use std::str::FromStr;
+
+    let ds = SqliteDS::new(":memory:").unwrap();
+
+    let keys: Vec<_> = (0..200u32)
+        .map(|i| ds.put(i.to_le_bytes().to_vec()).unwrap())
+        .collect();
+
+    for &key in &keys {
+        // A short minimum makes sure some of these collide, and need to be longer.
+        let short = ds.abbreviate(key, 2).unwrap();
+
+        assert!(short.len() >= 2);
+        assert!(key.as_user_key().starts_with(&short));
+        assert_eq!(
+            ds.canonicalize(Keyish::from_str(&short).unwrap()).unwrap(),
+            key
+        );
+
+        if short.len() > 2 {
+            let shorter = &short[..short.len() - 1];
+            assert!(matches!(
+                ds.canonicalize(Keyish::from_str(shorter).unwrap()),
+                Err(CanonicalizeError::Ambigious(..))
+            ));
+        }
+
+        assert_eq!(ds.abbreviate(key, 1000).unwrap(), key.as_user_key());
+    }
+
+    // No minimum still gives the shortest unique prefix, rather than an empty one.
+    assert_eq!(
+        ds.abbreviate(keys[0], 0).unwrap(),
+        ds.abbreviate(keys[0], 1).unwrap()
+    );
+}
+
+/// Fills `dir` with a small tree of files with seeded contents, some of them the same, and one
+/// big enough to be chunked. Their times are in the past, so the stat cache trusts them.
+fn seeded_tree(dir: &std::path::Path) {
+    use std::os::unix::fs::MetadataExt;
+    use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+    let _ = std::fs::remove_dir_all(dir);
+
+    let mut rng = ChaChaRng::seed_from_u64(0);
+    let mut files = Vec::new();
+
+    for i in 0..16 {
+        let parent = ["", "sub", "sub/deeper", "other"][i % 4];
+        let mut data = vec![0; i * 997];
+        rng.fill(&mut data[..]);
+
+        files.push((dir.join(parent).join(format!("file{}", i)), data));
+    }
+
+    let mut big = vec![0; 200_000];
+    rng.fill(&mut big[..]);
+    files.push((dir.join("big"), big.clone()));
+    files.push((dir.join("other/big.copy"), big));
+
+    let mtime = UNIX_EPOCH + Duration::from_secs(1_600_000_000);
+    let mut ctime = 0;
+
+    for (path, data) in files {
+        std::fs::create_dir_all(path.parent().unwrap()).unwrap();
+        std::fs::write(&path, data).unwrap();
+
+        let file = std::fs::File::options().write(true).open(&path).unwrap();
+        file.set_modified(mtime).unwrap();
+        ctime = ctime.max(file.metadata().unwrap().ctime());
+    }
+
+    // Change times can't be set, so wait for them to be in the past.
+    let now = || {
+        SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap()
+            .as_secs() as i64
+    };
+    while now() <= ctime {
+        std::thread::sleep(Duration::from_millis(10));
+    }
+}
+
+#[test]
+fn resolve_checks_and_peels_types() {
+    use snapcd::dir::FSItem;
+    use std::str::FromStr;
+
+    let mut ds = SqliteDS::new(":memory:").unwrap();
+
+    let src =
+        std::path::Path::new(env!("CARGO_TARGET_TMPDIR")).join("resolve_checks_and_peels_types");
+    seeded_tree(&src);
+    let tree = snapcd::dir::put_fs_item(&mut ds, &src, &|_| true).unwrap();
+
+    let cmt = commit::commit_tree(&mut ds, tree.into(), vec![], Default::default()).unwrap();
+
+    let keyish = |k: Key| Keyish::from_str(&k.as_user_key()).unwrap();
+
+    // Full keys must resolve, not just prefixes.
+    assert_eq!(ds.canonicalize(keyish(tree)).unwrap(), tree);
+
+    assert_eq!(
+        ds.resolve::<commit::Commit>(keyish(cmt.inner())).unwrap(),
+        cmt
+    );
+    assert_eq!(ds.resolve::<FSItem>(keyish(tree)).unwrap().inner(), tree);
+    assert_eq!(
+        ds.resolve::<FSItem>(keyish(cmt.inner())).unwrap().inner(),
+        tree
+    );
+
+    assert!(matches!(
+        ds.resolve::<commit::Commit>(keyish(tree)),
+        Err(ResolveError::WrongType {
+            expected: "commit",
+            ..
+        })
+    ));
+
+    std::fs::remove_dir_all(&src).unwrap();
+}
+
+#[test]
+fn rehash_rewrites_objects_and_refs() {
+    use snapcd::key::HashAlgorithm;
+    use std::str::FromStr;
+
+    let mut ds = SqliteDS::new(":memory:").unwrap();
+
+    let data: Vec<u8> = (0..100_000_u32).map(|x| (x % 251) as u8).collect();
+    let tree = put_data(&mut ds, &data[..], &ChunkerConfig::default()).unwrap();
+    let old = commit::commit_tree(&mut ds, tree.into(), vec![], Default::default()).unwrap();
+
+    ds.put_head("master").unwrap();
+    ds.reflog_push(&Reflog {
+        refname: "master".to_string(),
+        key: old,
+        remote: None,
+    })
+    .unwrap();
+
+    let report = snapcd::rehash::rehash(&ds, HashAlgorithm::Sha256).unwrap();
+
+    assert_eq!(ds.hash_algorithm().unwrap(), HashAlgorithm::Sha256);
+
+    let new = ds.reflog_get("master", None).unwrap();
+    assert_eq!(new.inner(), report.rewritten[&old.inner()]);
+    assert_eq!(new.inner().algorithm(), HashAlgorithm::Sha256);
+
+    let new_tree = commit::Commit::from_key(&ds, new).unwrap().tree().inner();
+    assert_eq!(new_tree.algorithm(), HashAlgorithm::Sha256);
+
+    let mut read = Vec::new();
+    read_data(&ds, new_tree, &mut read).unwrap();
+    assert_eq!(read, data);
+
+    // Old keys still work in full, and lead to the new objects.
+    let old_keyish = Keyish::from_str(&old.inner().as_user_key()).unwrap();
+    assert_eq!(ds.canonicalize(old_keyish).unwrap(), new.inner());
+
+    // Going back, keys in the current algorithm are themselves again, not what they were
+    // rehashed to the first time.
+    snapcd::rehash::rehash(&ds, HashAlgorithm::Blake3B).unwrap();
+    let old_keyish = Keyish::from_str(&old.inner().as_user_key()).unwrap();
+    assert_eq!(ds.canonicalize(old_keyish).unwrap(), old.inner());
+
+    let new_keyish = Keyish::from_str(&new.inner().as_user_key()).unwrap();
+    assert_eq!(ds.canonicalize(new_keyish).unwrap(), old.inner());
+    assert_eq!(ds.reflog_get("master", None).unwrap(), old);
+
+    assert!(fsck::fsck(&ds).unwrap().is_clean());
+}
+
+#[test]
+fn unknown_object_types_round_trip() {
+    use snapcd::object::ObjType;
+
+    #[derive(serde::Serialize)]
+    struct FutureObject {
+        data: serde_bytes::ByteBuf,
+        keys: Vec<snapcd::key::Key>,
+        objtype: &'static str,
+    }
+
+    let mut ds = SqliteDS::new(":memory:").unwrap();
+
+    let child = put_data(&mut ds, &b"child"[..], &ChunkerConfig::default()).unwrap();
+
+    let encoded = serde_cbor::to_vec(&FutureObject {
+        data: serde_bytes::ByteBuf::from(&b"from the future"[..]),
+        keys: vec![child],
+        objtype: "FromTheFuture",
+    })
+    .unwrap();
+
+    let key = ds.put(encoded.clone()).unwrap();
+    let obj = ds.get_obj(key).unwrap();
+
+    assert!(matches!(obj.objtype(), ObjType::Unknown));
+    assert_eq!(obj.type_name(), "FromTheFuture");
+    assert_eq!(obj.keys(), &[child]);
+    assert_eq!(serde_cbor::to_vec(&obj).unwrap(), encoded);
+
+    assert!(matches!(
+        snapcd::display::display_obj(
+            &mut ds,
+            key,
+            snapcd::display::Kind::Stat,
+            snapcd::display::KeyStyle::Full
+        ),
+        Err(snapcd::display::ShowError::UnknownType { .. })
+    ));
+
+    let report = fsck::fsck(&ds).unwrap();
+    assert!(report.is_clean());
+}
+
+fn check_seeks<DS: DataStore>(ds: &DS, key: snapcd::key::Key, data: &[u8], rng: &mut ChaChaRng) {
+    use snapcd::file::BlobReader;
+    use std::io::{Read, Seek, SeekFrom};
+
+    let mut reader = BlobReader::new(ds, key).unwrap();
+    assert_eq!(reader.len(), data.len() as u64);
+
+    for _ in 0..32 {
+        let offset = rng.gen_range(0, data.len() + 1);
+        let len = rng.gen_range(0, 1 << 16);
+
+        reader.seek(SeekFrom::Start(offset as u64)).unwrap();
+
+        let mut read = Vec::new();
+        (&mut reader).take(len).read_to_end(&mut read).unwrap();
+
+        let end = (offset + len as usize).min(data.len());
+        assert_eq!(read, &data[offset..end], "offset {} length {}", offset, len);
+    }
+
+    reader.seek(SeekFrom::End(-1)).unwrap();
+    let mut last = Vec::new();
+    reader.read_to_end(&mut last).unwrap();
+    assert_eq!(last, &data[data.len() - 1..]);
+}
+
+#[test]
+fn blob_reader_seeks() {
+    let mut rng = ChaChaRng::seed_from_u64(0);
+    let mut ds = SqliteDS::new(":memory:").unwrap();
+
+    let mut data = vec![0; 1 << 21];
+    rng.fill(&mut data[..]);
+
+    let key = put_data(&mut ds, &data[..], &ChunkerConfig::default()).unwrap();
+
+    check_seeks(&ds, key, &data, &mut rng);
+}
+
+#[test]
+fn blob_reader_seeks_legacy_trees() {
+    use snapcd::object::{ObjType, Object};
+
+    let mut rng = ChaChaRng::seed_from_u64(1);
+    let ds = SqliteDS::new(":memory:").unwrap();
+
+    let mut data = vec![0; 100_000];
+    rng.fill(&mut data[..]);
+
+    // Trees from before sizes were recorded have no data section.
+    let blobs: Vec<_> = data
+        .chunks(7_000)
+        .map(|chunk| {
+            ds.put_obj(&Object::new(chunk, &[], ObjType::FileBlob))
+                .unwrap()
+        })
+        .collect();
+
+    let subtrees: Vec<_> = blobs
+        .chunks(4)
+        .map(|keys| {
+            ds.put_obj(&Object::new(&[], keys, ObjType::FileBlobTree))
+                .unwrap()
+        })
+        .collect();
+
+    let key = ds
+        .put_obj(&Object::new(&[], &subtrees, ObjType::FileBlobTree))
+        .unwrap();
+
+    check_seeks(&ds, key, &data, &mut rng);
+}
+
+#[test]
+fn commit_keys_dont_depend_on_map_order() {
+    let mut ds = SqliteDS::new(":memory:").unwrap();
+    let tree = put_data(&mut ds, &b"tree"[..], &ChunkerConfig::default()).unwrap();
+
+    let names: Vec<String> = (0..32).map(|i| format!("attr{}", i)).collect();
+
+    let mut forwards = commit::CommitAttrs::default();
+    for name in &names {
+        forwards
+            .extra_mut()
+            .insert(name.clone(), serde_cbor::Value::Text(name.clone()));
+    }
+
+    let mut backwards = commit::CommitAttrs::default();
+    for name in names.iter().rev() {
+        backwards
+            .extra_mut()
+            .insert(name.clone(), serde_cbor::Value::Text(name.clone()));
+    }
+
+    let a = commit::commit_tree(&mut ds, tree.into(), vec![], forwards).unwrap();
+    let b = commit::commit_tree(&mut ds, tree.into(), vec![], backwards).unwrap();
+
+    assert_eq!(a, b);
+}
+
+#[test]
+fn written_objects_are_canonical() {
+    use snapcd::canonical;
+
+    let mut ds = SqliteDS::new(":memory:").unwrap();
+
+    let src =
+        std::path::Path::new(env!("CARGO_TARGET_TMPDIR")).join("written_objects_are_canonical");
+    seeded_tree(&src);
+    let tree = snapcd::dir::put_fs_item(&mut ds, &src, &|_| true).unwrap();
+    commit::commit_tree(&mut ds, tree.into(), vec![], Default::default()).unwrap();
+    parity::protect(&ds, 4, 2).unwrap();
+
+    for db_key in ds.raw_between(&[], None).unwrap() {
+        let raw = ds.raw_get(&db_key).unwrap();
+        let obj: snapcd::Object = serde_cbor::from_slice(&raw).unwrap();
+
+        assert!(canonical::is_canonical_object(&raw, &obj));
+
+        // Decoding and encoding again must give back the same bytes, or keys aren't stable.
+        assert_eq!(canonical::to_vec(&obj).unwrap(), raw.to_vec());
+    }
+
+    let report = fsck::fsck(&ds).unwrap();
+    assert!(report.noncanonical.is_empty());
+    assert!(report.unsorted.is_empty());
+
+    std::fs::remove_dir_all(&src).unwrap();
+}
+
+#[test]
+fn null_ds_hashes_like_a_real_store() {
+    use snapcd::{ds::null::NullDS, key::HashAlgorithm};
+
+    let src =
+        std::path::Path::new(env!("CARGO_TARGET_TMPDIR")).join("null_ds_hashes_like_a_real_store");
+    seeded_tree(&src);
+
+    for &algorithm in &[HashAlgorithm::Blake3B, HashAlgorithm::Sha256] {
+        let mut ds = SqliteDS::new(":memory:").unwrap();
+        ds.set_hash_algorithm(algorithm).unwrap();
+
+        let stored = snapcd::dir::put_fs_item(&mut ds, &src, &|_| true).unwrap();
+
+        let mut null = NullDS::new(algorithm, ChunkerConfig::default());
+        let hashed = snapcd::dir::put_fs_item(&mut null, &src, &|_| true).unwrap();
+
+        assert_eq!(stored, hashed);
+        assert_eq!(hashed.algorithm(), algorithm);
+    }
+
+    std::fs::remove_dir_all(&src).unwrap();
+}
+
+#[test]
+fn chunker_config_is_stored_and_used() {
+    let mut ds = SqliteDS::new(":memory:").unwrap();
+
+    assert_eq!(ds.chunker_config().unwrap(), ChunkerConfig::default());
+
+    let bad = ChunkerConfig {
+        blob_zero_count: 40,
+        ..ChunkerConfig::default()
+    };
+    assert!(ds.set_chunker_config(&bad).is_err());
+
+    let small = ChunkerConfig {
+        blob_zero_count: 8,
+        blob_zero_count_max: 10,
+        per_level_count: 4,
+        per_level_count_max: 6,
+        ..ChunkerConfig::default()
+    };
+    ds.set_chunker_config(&small).unwrap();
+    assert_eq!(ds.chunker_config().unwrap(), small);
+
+    // Once it's set, it stays.
+    ds.set_chunker_config(&small).unwrap();
+    assert!(matches!(
+        ds.set_chunker_config(&ChunkerConfig::default()),
+        Err(snapcd::ds::SetChunkerConfigError::AlreadySet)
+    ));
+    assert_eq!(ds.chunker_config().unwrap(), small);
+
+    let mut rng = ChaChaRng::seed_from_u64(0);
+    let mut data = vec![0; 1 << 16];
+    rng.fill(&mut data[..]);
+
+    let default_key = put_data(&mut ds, &data[..], &ChunkerConfig::default()).unwrap();
+    let small_key = put_data(&mut ds, &data[..], &small).unwrap();
+    assert_ne!(default_key, small_key);
+
+    // Smaller blobs and trees mean a deeper tree, with at most 2^6 children at each level.
+    let root = ds.get_obj(small_key).unwrap();
+    assert!(!root.keys().is_empty() && root.keys().len() <= 1 << 6);
+
+    let mut read = Vec::new();
+    read_data(&ds, small_key, &mut read).unwrap();
+    assert_eq!(read, data);
+}
+
+#[test]
+fn shared_cache_is_only_used_for_stored_keys() {
+    use snapcd::cache::SqliteCache;
+    use std::time::{Duration, UNIX_EPOCH};
+
+    let dir = std::path::Path::new(env!("CARGO_TARGET_TMPDIR"))
+        .join("shared_cache_is_only_used_for_stored_keys");
+    let _ = std::fs::remove_dir_all(&dir);
+    std::fs::create_dir_all(&dir).unwrap();
+
+    let mut rng = ChaChaRng::seed_from_u64(0);
+    let mut data = vec![0; 1 << 16];
+    rng.fill(&mut data[..]);
+
+    let file = dir.join("file");
+    std::fs::write(&file, &data).unwrap();
+    std::fs::File::options()
+        .write(true)
+        .open(&file)
+        .unwrap()
+        .set_modified(UNIX_EPOCH + Duration::from_secs(1_600_000_000))
+        .unwrap();
+
+    let cache = SqliteCache::new(":memory:").unwrap();
+
+    let mut default = SqliteDS::new(":memory:").unwrap();
+    let mut small = SqliteDS::new(":memory:").unwrap();
+    small
+        .set_chunker_config(&ChunkerConfig {
+            blob_zero_count: 8,
+            blob_zero_count_max: 10,
+            per_level_count: 4,
+            per_level_count_max: 6,
+            ..ChunkerConfig::default()
+        })
+        .unwrap();
+
+    // The first store fills in the cache, which the second, chunking differently, can't use.
+    let first = snapcd::dir::hash_fs_item(&mut default, &file, &cache, false).unwrap();
+    let second = snapcd::dir::hash_fs_item(&mut small, &file, &cache, false).unwrap();
+
+    assert_ne!(first, second);
+    assert_eq!(
+        second,
+        snapcd::dir::put_fs_item(&mut small, &file, &|_| true).unwrap()
+    );
+
+    std::fs::remove_dir_all(&dir).unwrap();
+}
+
+#[test]
+fn fastcdc_dedups_shifted_data() {
+    use snapcd::chunker::ChunkerAlgorithm;
+
+    let mut ds = SqliteDS::new(":memory:").unwrap();
+
+    let config = ChunkerConfig {
+        algorithm: ChunkerAlgorithm::fastcdc(),
+        ..ChunkerConfig::default()
+    };
+    ds.set_chunker_config(&config).unwrap();
+    assert_eq!(ds.chunker_config().unwrap(), config);
+
+    let mut rng = ChaChaRng::seed_from_u64(0);
+    let mut data = vec![0; 1 << 20];
+    rng.fill(&mut data[..]);
+
+    let mut shifted = data.clone();
+    shifted.splice(1000..1000, b"inserted".iter().copied());
+
+    fn blobs(ds: &SqliteDS, key: Key, out: &mut HashSet<Key>) {
+        let obj = ds.get_obj(key).unwrap();
+        if obj.keys().is_empty() {
+            out.insert(key);
+        }
+        for &child in obj.keys() {
+            blobs(ds, child, out);
+        }
+    }
+
+    let (mut before, mut after) = (HashSet::new(), HashSet::new());
+
+    let key = put_data(&mut ds, &data[..], &config).unwrap();
+    blobs(&ds, key, &mut before);
+
+    let shifted_key = put_data(&mut ds, &shifted[..], &config).unwrap();
+    blobs(&ds, shifted_key, &mut after);
+
+    // Only the blob with the insertion (and maybe its neighbour) should change.
+    assert!(after.difference(&before).count() <= 2);
+    assert!(before.len() > 64);
+
+    for (key, expected) in &[(key, &data), (shifted_key, &shifted)] {
+        let mut read = Vec::new();
+        read_data(&ds, *key, &mut read).unwrap();
+        assert_eq!(&read, *expected);
+    }
+}
+
+#[test]
+fn analyze_matches_a_real_insert() {
+    use snapcd::{analyze, key::HashAlgorithm};
+
+    let src =
+        std::path::Path::new(env!("CARGO_TARGET_TMPDIR")).join("analyze_matches_a_real_insert");
+    seeded_tree(&src);
+
+    let (analysis, counted) = analyze::analyze(
+        &src,
+        &|_| true,
+        HashAlgorithm::Blake3B,
+        ChunkerConfig::default(),
+        1,
+    )
+    .unwrap();
+
+    let mut ds = SqliteDS::new(":memory:").unwrap();
+
+    // Nothing has been stored yet, so everything would be new.
+    let before = analyze::incremental(&ds, &counted).unwrap();
+    assert_eq!(before.new_blob_bytes, analysis.unique_bytes);
+
+    let key = snapcd::dir::put_fs_item(&mut ds, &src, &|_| true).unwrap();
+    assert_eq!(key, analysis.root);
+
+    let after = analyze::incremental(&ds, &counted).unwrap();
+    assert_eq!(after.new_objects, 0);
+
+    let total: u64 = analysis
+        .directories
+        .iter()
+        .skip(1)
+        .map(|d| d.total_bytes)
+        .sum();
+    assert!(total <= analysis.total_bytes);
+    assert!(analysis.directories[0].path.as_os_str().is_empty());
+    assert!(analysis.directories.iter().any(|d| d.path.ends_with("sub")));
+
+    assert!(analysis.unique_bytes <= analysis.total_bytes);
+    assert_eq!(
+        analysis.histogram.values().sum::<u64>(),
+        analysis.unique_chunks
+    );
+
+    std::fs::remove_dir_all(&src).unwrap();
+}
+
+#[test]
+fn sparse_files_round_trip() {
+    use snapcd::file::{put_file, BlobReader};
+    use std::io::{Read, Seek, SeekFrom, Write};
+    use std::os::unix::fs::MetadataExt;
+
+    let dir = std::path::Path::new(env!("CARGO_TARGET_TMPDIR")).join("sparse_files_round_trip");
+    let _ = std::fs::remove_dir_all(&dir);
+    std::fs::create_dir_all(&dir).unwrap();
+
+    let mut rng = ChaChaRng::seed_from_u64(0);
+    let mut data = vec![0; 1 << 24];
+    rng.fill(&mut data[..1 << 16]);
+    rng.fill(&mut data[(1 << 23)..(1 << 23) + 1000]);
+
+    // Data, a hole, more data, and a hole running to the end.
+    let sparse_path = dir.join("sparse");
+    let mut sparse = std::fs::File::create(&sparse_path).unwrap();
+    sparse.set_len(data.len() as u64).unwrap();
+    sparse.write_all(&data[..1 << 16]).unwrap();
+    sparse.seek(SeekFrom::Start(1 << 23)).unwrap();
+    sparse
+        .write_all(&data[(1 << 23)..(1 << 23) + 1000])
+        .unwrap();
+    drop(sparse);
+
+    let config = ChunkerConfig {
+        zero_run_bits: Some(snapcd::chunker::DEFAULT_ZERO_RUN_BITS),
+        ..ChunkerConfig::default()
+    };
+
+    let mut ds = SqliteDS::new(":memory:").unwrap();
+
+    // Holes are only an optimisation: the key is the same as for the same bytes written out.
+    let dense_key = put_data(&mut ds, &data[..], &config).unwrap();
+    let mut f = std::fs::File::open(&sparse_path).unwrap();
+    let sparse_key = put_file(&mut ds, &mut f, &config).unwrap();
+    assert_eq!(sparse_key, dense_key);
+
+    // Without zero runs, nothing changes.
+    let legacy_key = put_data(&mut ds, &data[..], &ChunkerConfig::default()).unwrap();
+    let mut f = std::fs::File::open(&sparse_path).unwrap();
+    let legacy_file_key = put_file(&mut ds, &mut f, &ChunkerConfig::default()).unwrap();
+    assert_eq!(legacy_file_key, legacy_key);
+    assert_ne!(legacy_key, dense_key);
+
+    let mut read = Vec::new();
+    read_data(&ds, dense_key, &mut read).unwrap();
+    assert!(read == data);
+
+    let mut reader = BlobReader::new(&ds, dense_key).unwrap();
+    assert_eq!(reader.len(), data.len() as u64);
+    reader.seek(SeekFrom::Start((1 << 23) - 10)).unwrap();
+    let mut middle = vec![0; 100];
+    reader.read_exact(&mut middle).unwrap();
+    assert_eq!(middle, &data[(1 << 23) - 10..(1 << 23) + 90]);
+
+    let restored_path = dir.join("restored");
+    let mut restored = std::fs::File::create(&restored_path).unwrap();
+    snapcd::file::restore_data(&ds, dense_key, &mut restored).unwrap();
+    drop(restored);
+
+    assert!(std::fs::read(&restored_path).unwrap() == data);
+
+    // Most of the file is holes, unless the filesystem doesn't support them.
+    let blocks = std::fs::metadata(&restored_path).unwrap().blocks();
+    let sparse_blocks = std::fs::metadata(&sparse_path).unwrap().blocks();
+    if sparse_blocks * 512 < data.len() as u64 / 2 {
+        assert!(blocks * 512 < data.len() as u64 / 2);
+    }
+
+    std::fs::remove_dir_all(&dir).unwrap();
+}
+
+/// A store with two commits, the second changing, adding and removing files, and every key
+/// reachable from it.
+fn corruption_fixture() -> (SqliteDS, snapcd::key::TypedKey<commit::Commit>, Vec<Key>) {
+    let dir = std::path::Path::new(env!("CARGO_TARGET_TMPDIR")).join("corruption_fixture");
+
+    let mut rng = ChaChaRng::seed_from_u64(0);
+    let mut big = vec![0; 100_000];
+    rng.fill(&mut big[..]);
+
+    let files: &[(&str, &[u8])] = &[
+        ("v1/a.txt", b"one\ntwo\n"),
+        ("v1/sub/b.txt", b"b\n"),
+        ("v1/big", &big),
+        ("v2/a.txt", b"one\nthree\n"),
+        ("v2/c.txt", b"c\n"),
+        ("v2/big", &big[1000..]),
+    ];
+
+    for (path, data) in files {
+        let path = dir.join(path);
+        std::fs::create_dir_all(path.parent().unwrap()).unwrap();
+        std::fs::write(path, data).unwrap();
+    }
+
+    let mut ds = SqliteDS::new(":memory:").unwrap();
+
+    let v1 = snapcd::dir::put_fs_item(&mut ds, &dir.join("v1"), &|_| true).unwrap();
+    let c1 =
+        commit::commit_tree(&mut ds, v1.into(), vec![], commit::CommitAttrs::default()).unwrap();
+
+    let v2 = snapcd::dir::put_fs_item(&mut ds, &dir.join("v2"), &|_| true).unwrap();
+    let c2 =
+        commit::commit_tree(&mut ds, v2.into(), vec![c1], commit::CommitAttrs::default()).unwrap();
+
+    let mut keys = vec![c2.inner()];
+    let mut i = 0;
+    while let Some(&key) = keys.get(i) {
+        for &child in ds.get_obj(key).unwrap().keys() {
+            if !keys.contains(&child) {
+                keys.push(child);
+            }
+        }
+        i += 1;
+    }
+
+    (ds, c2, keys)
+}
+
+#[test]
+fn parallel_restore_matches_sequential() {
+    use snapcd::metadata::RestoreMetadata;
+    use snapcd::restore::{self, RestoreError};
+
+    let dir = std::path::Path::new(env!("CARGO_TARGET_TMPDIR")).join("parallel_restore");
+    let _ = std::fs::remove_dir_all(&dir);
+    std::fs::create_dir_all(&dir).unwrap();
+
+    let db = dir.join("snapcd.db");
+    let mut ds = SqliteDS::new(&db).unwrap();
+
+    let src = dir.join("src");
+    seeded_tree(&src);
+    let key = snapcd::dir::put_fs_item(&mut ds, &src, &|_| true).unwrap();
+
+    let readers = || {
+        (0..4)
+            .map(|_| SqliteDS::open_read_only(&db).unwrap())
+            .collect::<Vec<_>>()
+    };
+
+    let all = RestoreMetadata::default();
+
+    let stats =
+        restore::get_fs_item(&ds, key.into(), &dir.join("parallel"), all, readers()).unwrap();
+    snapcd::dir::get_fs_item(&ds, key.into(), &dir.join("sequential"), all).unwrap();
+
+    assert!(stats.files > 10);
+    assert!(stats.bytes > 0);
+
+    for restored in &["parallel", "sequential"] {
+        let again = snapcd::dir::put_fs_item(&mut ds, &dir.join(restored), &|_| true).unwrap();
+        assert_eq!(again, key);
+    }
+
+    // Fetching never overwrites anything.
+    assert!(matches!(
+        restore::get_fs_item(&ds, key.into(), &dir.join("parallel"), all, readers()),
+        Err(RestoreError::RestoreFileError { .. })
+    ));
+
+    assert!(matches!(
+        restore::get_fs_item::<_, SqliteDS>(&ds, key.into(), &dir.join("none"), all, vec![]),
+        Err(RestoreError::NoReaders)
+    ));
+
+    std::fs::remove_dir_all(&dir).unwrap();
+}
+
+#[test]
+fn checkout_replaces_what_changed() {
+    use snapcd::metadata::RestoreMetadata;
+    use snapcd::restore;
+
+    let dir =
+        std::path::Path::new(env!("CARGO_TARGET_TMPDIR")).join("checkout_replaces_what_changed");
+    let _ = std::fs::remove_dir_all(&dir);
+    std::fs::create_dir_all(&dir).unwrap();
+
+    let db = dir.join("snapcd.db");
+    let mut ds = SqliteDS::new(&db).unwrap();
+
+    let src = dir.join("src");
+    seeded_tree(&src);
+    let key = snapcd::dir::put_fs_item(&mut ds, &src, &|_| true).unwrap();
+
+    let all = RestoreMetadata::default();
+
+    for parallel in &[false, true] {
+        let out = dir.join(if *parallel { "parallel" } else { "sequential" });
+        seeded_tree(&out);
+
+        // Something extra, something changed, and something missing.
+        std::fs::write(out.join("sub/extra"), "extra\n").unwrap();
+        std::fs::create_dir_all(out.join("other/extra")).unwrap();
+        std::fs::write(out.join("file4"), "changed\n").unwrap();
+        std::fs::remove_file(out.join("sub/deeper/file2")).unwrap();
+
+        if *parallel {
+            let readers = vec![SqliteDS::open_read_only(&db).unwrap()];
+            restore::checkout_fs_item(&ds, key.into(), &out, &|_| true, all, readers).unwrap();
+        } else {
+            snapcd::dir::checkout_fs_item(&ds, key.into(), &out, &|_| true, all).unwrap();
+        }
+
+        assert!(!out.join("sub/extra").exists());
+        assert!(!out.join("other/extra").exists());
+        assert_eq!(
+            std::fs::read(out.join("file4")).unwrap(),
+            std::fs::read(src.join("file4")).unwrap()
+        );
+
+        let again = snapcd::dir::put_fs_item(&mut ds, &out, &|_| true).unwrap();
+        assert_eq!(again, key);
+    }
+
+    std::fs::remove_dir_all(&dir).unwrap();
+}
+
+#[test]
+fn checkout_restores_symlinks_and_fifos() {
+    use snapcd::metadata::RestoreMetadata;
+    use std::os::unix::ffi::OsStrExt;
+    use std::os::unix::fs::FileTypeExt;
+    use std::path::Path;
+
+    let dir = Path::new(env!("CARGO_TARGET_TMPDIR")).join("checkout_restores_symlinks_and_fifos");
+    let _ = std::fs::remove_dir_all(&dir);
+    let src = dir.join("src");
+    std::fs::create_dir_all(src.join("sub")).unwrap();
+
+    std::fs::write(src.join("file"), "file\n").unwrap();
+    std::os::unix::fs::symlink("file", src.join("link")).unwrap();
+    std::os::unix::fs::symlink("sub", src.join("dirlink")).unwrap();
+
+    let fifo = std::ffi::CString::new(src.join("fifo").as_os_str().as_bytes()).unwrap();
+    assert_eq!(unsafe { libc::mkfifo(fifo.as_ptr(), 0o644) }, 0);
+
+    let mut ds = SqliteDS::new(":memory:").unwrap();
+    let key = snapcd::dir::put_fs_item(&mut ds, &src, &|_| true).unwrap();
+
+    // Whatever's in their place is replaced.
+    let out = dir.join("out");
+    std::fs::create_dir_all(out.join("dirlink")).unwrap();
+    std::fs::write(out.join("link"), "not a link\n").unwrap();
+    std::fs::write(out.join("fifo"), "not a fifo\n").unwrap();
+
+    snapcd::dir::checkout_fs_item(&ds, key.into(), &out, &|_| true, RestoreMetadata::default())
+        .unwrap();
+
+    assert_eq!(
+        std::fs::read_link(out.join("link")).unwrap(),
+        Path::new("file")
+    );
+    assert_eq!(
+        std::fs::read_link(out.join("dirlink")).unwrap(),
+        Path::new("sub")
+    );
+    assert!(std::fs::symlink_metadata(out.join("fifo"))
+        .unwrap()
+        .file_type()
+        .is_fifo());
+    assert_eq!(std::fs::read(out.join("link")).unwrap(), b"file\n");
+
+    std::fs::remove_dir_all(&dir).unwrap();
+}
+
+#[test]
+fn checkpointed_inserts_keep_progress() {
+    use snapcd::cache::{Cache, CacheKey, SqliteCache};
+    use snapcd::dir::{self, Checkpoints, PutFsItemError, PutOptions};
+    use snapcd::ds::Transactional;
+    use std::sync::atomic::AtomicBool;
+
+    let src = std::path::Path::new(env!("CARGO_TARGET_TMPDIR"))
+        .join("checkpointed_inserts_keep_progress");
+    seeded_tree(&src);
+
+    let mut ds = SqliteDS::new(":memory:").unwrap();
+    let mut cache = SqliteCache::new(":memory:").unwrap();
+    ds.begin_trans().unwrap();
+    cache.begin_trans().unwrap();
+
+    // Stops after the first file, but keeps it.
+    let cancel = AtomicBool::new(true);
+    let mut checkpoints = Checkpoints::new(u64::MAX, &cancel);
+    assert!(matches!(
+        dir::put_fs_item_checkpointed(
+            &mut ds,
+            &src,
+            &|_| true,
+            PutOptions::default(),
+            &mut cache,
+            &mut checkpoints
+        ),
+        Err(PutFsItemError::Cancelled)
+    ));
+
+    ds.rollback().unwrap();
+    cache.rollback().unwrap();
+    assert!(!ds.raw_between(&[], None).unwrap().is_empty());
+
+    ds.begin_trans().unwrap();
+    cache.begin_trans().unwrap();
+
+    let cancel = AtomicBool::new(false);
+    let mut checkpoints = Checkpoints::new(1, &cancel);
+    let key = dir::put_fs_item_checkpointed(
+        &mut ds,
+        &src,
+        &|_| true,
+        PutOptions::default(),
+        &mut cache,
+        &mut checkpoints,
+    )
+    .unwrap();
+
+    ds.commit().unwrap();
+    cache.commit().unwrap();
+
+    let mut fresh = SqliteDS::new(":memory:").unwrap();
+    assert_eq!(dir::put_fs_item(&mut fresh, &src, &|_| true).unwrap(), key);
+
+    // Files are in the cache, so a rerun needn't read them.
+    let file = std::fs::metadata(src.join("file4")).unwrap();
+    let cached = cache.get(CacheKey::from_metadata(&file)).unwrap().unwrap();
+    assert!(ds.raw_exists(&cached.as_db_key()).unwrap());
+
+    ds.begin_trans().unwrap();
+    cache.begin_trans().unwrap();
+    let again = dir::put_fs_item_checkpointed(
+        &mut ds,
+        &src,
+        &|_| true,
+        PutOptions::default(),
+        &mut cache,
+        &mut checkpoints,
+    )
+    .unwrap();
+    assert_eq!(again, key);
+
+    std::fs::remove_dir_all(&src).unwrap();
+}
+
+#[test]
+fn metadata_is_restored() {
+    use snapcd::dir::{self, FSItem};
+    use snapcd::metadata::{RestoreMetadata, RestoreOwner};
+    use snapcd::object::{ObjType, Object};
+    use std::convert::TryInto;
+    use std::os::unix::fs::{MetadataExt, PermissionsExt};
+
+    let dir = std::path::Path::new(env!("CARGO_TARGET_TMPDIR")).join("metadata_is_restored");
+    let _ = std::fs::remove_dir_all(&dir);
+    std::fs::create_dir_all(dir.join("src/empty")).unwrap();
+
+    let old = std::time::UNIX_EPOCH + std::time::Duration::new(1_000_000_000, 123_456_789);
+
+    for (name, mode) in &[("script", 0o755), ("secret", 0o600)] {
+        let path = dir.join("src").join(name);
+        std::fs::write(&path, name).unwrap();
+        std::fs::set_permissions(&path, std::fs::Permissions::from_mode(*mode)).unwrap();
+        std::fs::File::open(&path)
+            .unwrap()
+            .set_modified(old)
+            .unwrap();
+    }
+
+    let mut ds = SqliteDS::new(":memory:").unwrap();
+    let key = dir::put_fs_item(&mut ds, &dir.join("src"), &|_| true).unwrap();
+
+    let item: FSItem = ds.get_obj(key).unwrap().try_into().unwrap();
+    assert!(item.metadata().is_some());
+
+    dir::get_fs_item(
+        &ds,
+        key.into(),
+        &dir.join("all"),
+        RestoreMetadata::default(),
+    )
+    .unwrap();
+    let no_times = RestoreMetadata {
+        owner: RestoreOwner::Never,
+        times: false,
+        ..RestoreMetadata::default()
+    };
+    dir::get_fs_item(&ds, key.into(), &dir.join("no_times"), no_times).unwrap();
+
+    for (name, mode) in &[("script", 0o755), ("secret", 0o600)] {
+        let all = std::fs::metadata(dir.join("all").join(name)).unwrap();
+        assert_eq!(all.mode() & 0o7777, *mode);
+        assert_eq!(all.modified().unwrap(), old);
+
+        let no_times = std::fs::metadata(dir.join("no_times").join(name)).unwrap();
+        assert_eq!(no_times.mode() & 0o7777, *mode);
+        assert_ne!(no_times.modified().unwrap(), old);
+    }
+
+    // Empty directories come back too.
+    assert!(dir.join("all/empty").is_dir());
+    assert_eq!(
+        dir::put_fs_item(&mut ds, &dir.join("all"), &|_| true).unwrap(),
+        key
+    );
+
+    #[derive(serde::Serialize)]
+    struct Revision0 {
+        size: u64,
+        itemtype: &'static str,
+        children_names: Vec<String>,
+    }
+
+    #[derive(serde::Serialize)]
+    struct FromTheFuture {
+        size: u64,
+        itemtype: &'static str,
+        children_names: Vec<String>,
+        revision: u32,
+    }
+
+    let data = put_data(&mut ds, &b"old"[..], &ChunkerConfig::default()).unwrap();
+
+    let legacy = Revision0 {
+        size: 3,
+        itemtype: "File",
+        children_names: vec![],
+    };
+    let legacy = Object::new(
+        &serde_cbor::to_vec(&legacy).unwrap(),
+        &[data],
+        ObjType::FSItemFile,
+    );
+    let item: FSItem = legacy.try_into().unwrap();
+    assert!(item.metadata().is_none());
+    assert_eq!(item.data_key(), Some(data));
+
+    let newer = FromTheFuture {
+        size: 3,
+        itemtype: "File",
+        children_names: vec![],
+        revision: 99,
+    };
+    let newer = Object::new(
+        &serde_cbor::to_vec(&newer).unwrap(),
+        &[data],
+        ObjType::FSItemFile,
+    );
+    assert!(TryInto::<FSItem>::try_into(newer).is_err());
+
+    std::fs::remove_dir_all(&dir).unwrap();
+}
+
+#[test]
+fn symlinks_round_trip() {
+    use snapcd::cache::SqliteCache;
+    use snapcd::diff::{self, DiffTarget};
+    use snapcd::dir::{self, Checkpoints, FSItem, PutFsItemError, PutOptions};
+    use snapcd::metadata::RestoreMetadata;
+    use std::convert::TryInto;
+    use std::os::unix::fs::symlink;
+    use std::path::Path;
+    use std::sync::atomic::AtomicBool;
+
+    let dir = Path::new(env!("CARGO_TARGET_TMPDIR")).join("symlinks_round_trip");
+    let _ = std::fs::remove_dir_all(&dir);
+    let src = dir.join("src");
+    std::fs::create_dir_all(src.join("sub")).unwrap();
+
+    std::fs::write(src.join("a"), "a\n").unwrap();
+    std::fs::write(src.join("sub/b"), "b\n").unwrap();
+    symlink("a", src.join("link")).unwrap();
+    symlink("sub", src.join("sub_link")).unwrap();
+    symlink("nowhere", src.join("dangling")).unwrap();
+
+    let children = |ds: &SqliteDS, key: snapcd::key::Key| {
+        let item: FSItem = ds.get_obj(key).unwrap().try_into().unwrap();
+        item.children()
+            .map(|(name, key)| {
+                let child: FSItem = ds.get_obj(key.inner()).unwrap().try_into().unwrap();
+                (name.to_path_buf(), child)
+            })
+            .collect::<std::collections::HashMap<_, _>>()
+    };
+
+    let mut ds = SqliteDS::new(":memory:").unwrap();
+    let key = dir::put_fs_item(&mut ds, &src, &|_| true).unwrap();
+
+    let items = children(&ds, key);
+    assert_eq!(
+        items[Path::new("link")].symlink_target(),
+        Some(Path::new("a"))
+    );
+    assert_eq!(
+        items[Path::new("sub_link")].symlink_target(),
+        Some(Path::new("sub"))
+    );
+    assert_eq!(
+        items[Path::new("dangling")].symlink_target(),
+        Some(Path::new("nowhere"))
+    );
+
+    dir::get_fs_item(
+        &ds,
+        key.into(),
+        &dir.join("out"),
+        RestoreMetadata::default(),
+    )
+    .unwrap();
+    assert_eq!(
+        std::fs::read_link(dir.join("out/link")).unwrap(),
+        Path::new("a")
+    );
+    assert_eq!(
+        std::fs::read_link(dir.join("out/dangling")).unwrap(),
+        Path::new("nowhere")
+    );
+    assert_eq!(std::fs::read(dir.join("out/sub_link/b")).unwrap(), b"b\n");
+    assert_eq!(
+        dir::put_fs_item(&mut ds, &dir.join("out"), &|_| true).unwrap(),
+        key
+    );
+
+    // Following stores what links point to, but links to nothing are still links.
+    let mut cache = SqliteCache::new(":memory:").unwrap();
+    let cancel = AtomicBool::new(false);
+    let mut checkpoints = Checkpoints::new(u64::MAX, &cancel);
+    let follow = PutOptions {
+        follow_symlinks: true,
+        ..PutOptions::default()
+    };
+    let followed = dir::put_fs_item_checkpointed(
+        &mut ds,
+        &src,
+        &|_| true,
+        follow,
+        &mut cache,
+        &mut checkpoints,
+    )
+    .unwrap();
+
+    let items = children(&ds, followed);
+    assert_eq!(items[Path::new("link")].size(), 2);
+    assert!(!items[Path::new("link")].is_symlink());
+    assert!(items[Path::new("sub_link")].is_dir());
+    assert!(items[Path::new("dangling")].is_symlink());
+
+    // A change of target is a modification, and diffs as the target.
+    std::fs::remove_file(src.join("link")).unwrap();
+    symlink("sub/b", src.join("link")).unwrap();
+
+    let result = diff::compare(
+        &mut ds,
+        DiffTarget::FileSystem(src.clone(), vec![], dir.join(".snapcd")),
+        Some(key.into()),
+        &mut cache,
+    )
+    .unwrap();
+    let patch = diff::create_diff_patch_result(&ds, result).unwrap();
+    assert!(patch.contains("link"));
+    assert!(patch.contains("-a"));
+    assert!(patch.contains("+sub/b"));
+
+    // Following a link back up the tree would never end.
+    symlink(".", src.join("sub/loop")).unwrap();
+    assert!(matches!(
+        dir::put_fs_item_checkpointed(
+            &mut ds,
+            &src,
+            &|_| true,
+            follow,
+            &mut cache,
+            &mut checkpoints
+        ),
+        Err(PutFsItemError::SymlinkLoop(_))
+    ));
+    dir::put_fs_item(&mut ds, &src, &|_| true).unwrap();
+
+    std::fs::remove_dir_all(&dir).unwrap();
+}
+
+#[test]
+fn xattrs_round_trip() {
+    use snapcd::cache::SqliteCache;
+    use snapcd::diff::{self, DiffTarget};
+    use snapcd::dir::{self, Checkpoints, FSItem, PutOptions};
+    use snapcd::metadata::RestoreMetadata;
+    use snapcd::xattr::XattrSet;
+    use std::convert::TryInto;
+    use std::os::unix::ffi::OsStrExt;
+    use std::path::Path;
+    use std::sync::atomic::AtomicBool;
+
+    fn set_xattr(path: &Path, name: &str, value: &[u8]) -> std::io::Result<()> {
+        let path = std::ffi::CString::new(path.as_os_str().as_bytes()).unwrap();
+        let name = std::ffi::CString::new(name).unwrap();
+
+        let ret = unsafe {
+            libc::setxattr(
+                path.as_ptr(),
+                name.as_ptr(),
+                value.as_ptr().cast(),
+                value.len(),
+                0,
+            )
+        };
+
+        if ret == 0 {
+            Ok(())
+        } else {
+            Err(std::io::Error::last_os_error())
+        }
+    }
+
+    let dir = Path::new(env!("CARGO_TARGET_TMPDIR")).join("xattrs_round_trip");
+    let _ = std::fs::remove_dir_all(&dir);
+    let src = dir.join("src");
+    std::fs::create_dir_all(&src).unwrap();
+
+    for name in &["a", "b"] {
+        std::fs::write(src.join(name), name).unwrap();
+
+        if set_xattr(&src.join(name), "user.snapcd.label", b"same").is_err() {
+            // Not every file system the tests run on has user attributes.
+            std::fs::remove_dir_all(&dir).unwrap();
+            return;
+        }
+    }
+    set_xattr(&src, "user.snapcd.dir", b"\0binary\xff").unwrap();
+
+    let item = |ds: &SqliteDS, key: snapcd::key::Key| -> FSItem {
+        ds.get_obj(key).unwrap().try_into().unwrap()
+    };
+    let child = |ds: &SqliteDS, key: snapcd::key::Key, name: &str| -> FSItem {
+        let (_, key) = item(ds, key)
+            .children()
+            .find(|(n, _)| *n == Path::new(name))
+            .unwrap();
+        item(ds, key.inner())
+    };
+
+    let mut ds = SqliteDS::new(":memory:").unwrap();
+
+    // Not stored unless asked for.
+    let without = dir::put_fs_item(&mut ds, &src, &|_| true).unwrap();
+    assert!(item(&ds, without).xattrs().is_none());
+
+    let mut cache = SqliteCache::new(":memory:").unwrap();
+    let cancel = AtomicBool::new(false);
+    let mut checkpoints = Checkpoints::new(u64::MAX, &cancel);
+    let options = PutOptions {
+        xattrs: true,
+        ..PutOptions::default()
+    };
+    let key = dir::put_fs_item_checkpointed(
+        &mut ds,
+        &src,
+        &|_| true,
+        options,
+        &mut cache,
+        &mut checkpoints,
+    )
+    .unwrap();
+    assert_ne!(key, without);
+
+    // Files with the same attributes share them.
+    let a = child(&ds, key, "a").xattrs().unwrap();
+    assert_eq!(Some(a), child(&ds, key, "b").xattrs());
+    assert_ne!(Some(a), item(&ds, key).xattrs());
+
+    // The cache knows which way a file was stored.
+    let again = dir::put_fs_item_checkpointed(
+        &mut ds,
+        &src,
+        &|_| true,
+        PutOptions::default(),
+        &mut cache,
+        &mut checkpoints,
+    )
+    .unwrap();
+    assert_eq!(again, without);
+
+    dir::get_fs_item(
+        &ds,
+        key.into(),
+        &dir.join("all"),
+        RestoreMetadata::default(),
+    )
+    .unwrap();
+    assert_eq!(
+        XattrSet::read(&dir.join("all/a"), false).unwrap(),
+        XattrSet::read(&src.join("a"), false).unwrap()
+    );
+    assert_eq!(
+        XattrSet::read(&dir.join("all"), true).unwrap(),
+        XattrSet::read(&src, true).unwrap()
+    );
+
+    let no_xattrs = RestoreMetadata {
+        xattrs: false,
+        ..RestoreMetadata::default()
+    };
+    dir::get_fs_item(&ds, key.into(), &dir.join("none"), no_xattrs).unwrap();
+    assert!(XattrSet::read(&dir.join("none/a"), false)
+        .unwrap()
+        .is_empty());
+
+    // Comparing looks at attributes only where they were stored.
+    for tree in &[key, without] {
+        let result = diff::compare(
+            &mut ds,
+            DiffTarget::FileSystem(src.clone(), vec![], dir.join(".snapcd")),
+            Some((*tree).into()),
+            &mut cache,
+        )
+        .unwrap();
+        assert!(diff::diff_result_empty(&result));
+    }
+
+    // With a fresh cache, as the change time it goes by is only to the second.
+    set_xattr(&src.join("a"), "user.snapcd.label", b"changed").unwrap();
+    let result = diff::compare(
+        &mut ds,
+        DiffTarget::FileSystem(src.clone(), vec![], dir.join(".snapcd")),
+        Some(key.into()),
+        &mut SqliteCache::new(":memory:").unwrap(),
+    )
+    .unwrap();
+    assert!(!diff::diff_result_empty(&result));
+
+    std::fs::remove_dir_all(&dir).unwrap();
+}
+
+#[test]
+fn hardlinks_round_trip() {
+    use snapcd::cache::SqliteCache;
+    use snapcd::diff::{self, DiffTarget};
+    use snapcd::dir;
+    use snapcd::metadata::RestoreMetadata;
+    use std::os::unix::fs::MetadataExt;
+    use std::path::Path;
+
+    let dir = Path::new(env!("CARGO_TARGET_TMPDIR")).join("hardlinks_round_trip");
+    let _ = std::fs::remove_dir_all(&dir);
+    let src = dir.join("src");
+    std::fs::create_dir_all(src.join("sub")).unwrap();
+
+    std::fs::write(src.join("a"), "linked\n").unwrap();
+    std::fs::hard_link(src.join("a"), src.join("sub/b")).unwrap();
+    std::fs::write(src.join("c"), "linked\n").unwrap();
+
+    let ino = |path: &Path| std::fs::metadata(path).unwrap().ino();
+
+    let mut ds = SqliteDS::new(":memory:").unwrap();
+    let key = dir::put_fs_item(&mut ds, &src, &|_| true).unwrap();
+
+    let out = dir.join("out");
+    dir::get_fs_item(&ds, key.into(), &out, RestoreMetadata::default()).unwrap();
+    assert_eq!(ino(&out.join("a")), ino(&out.join("sub/b")));
+    assert_ne!(ino(&out.join("a")), ino(&out.join("c")));
+    assert_eq!(std::fs::read(out.join("sub/b")).unwrap(), b"linked\n");
+
+    // The same file, no longer linked, has the same key, so only the links change.
+    let mtime = std::fs::metadata(src.join("a"))
+        .unwrap()
+        .modified()
+        .unwrap();
+    std::fs::remove_file(src.join("sub/b")).unwrap();
+    std::fs::write(src.join("sub/b"), "linked\n").unwrap();
+    std::fs::File::options()
+        .write(true)
+        .open(src.join("sub/b"))
+        .unwrap()
+        .set_modified(mtime)
+        .unwrap();
+
+    let unlinked = dir::put_fs_item(&mut ds, &src, &|_| true).unwrap();
+
+    let result = diff::compare(
+        &mut ds,
+        DiffTarget::Database(unlinked.into()),
+        Some(key.into()),
+        None,
+    )
+    .unwrap();
+    assert!(!diff::diff_result_empty(&result));
+    let result = format!("{:?}", result);
+    assert!(!result.contains(r#"ModifiedDiffResult { path: "a""#));
+    assert!(!result.contains(r#"ModifiedDiffResult { path: "sub/b""#));
+    assert!(result.contains(r#"path: "a", original_links: ["sub/b"], new_links: []"#));
+    assert!(result.contains(r#"path: "sub/b", original_links: ["a"], new_links: []"#));
+
+    // And the same against the files themselves, once they're linked again.
+    std::fs::remove_file(src.join("sub/b")).unwrap();
+    std::fs::hard_link(src.join("c"), src.join("sub/b")).unwrap();
+
+    let result = diff::compare(
+        &mut ds,
+        DiffTarget::FileSystem(src.clone(), vec![], dir.join(".snapcd")),
+        Some(unlinked.into()),
+        &mut SqliteCache::new(":memory:").unwrap(),
+    )
+    .unwrap();
+    let result = format!("{:?}", result);
+    assert!(result.contains(r#"path: "c", original_links: [], new_links: ["sub/b"]"#));
+    assert!(result.contains(r#"path: "sub/b", original_links: [], new_links: ["c"]"#));
+
+    // Links are numbered within the directories they're under, so one has the same key on its
+    // own as in a tree with other links, and only links inside what's stored count.
+    std::fs::create_dir_all(src.join("pair")).unwrap();
+    std::fs::write(src.join("pair/x"), "x\n").unwrap();
+    std::fs::hard_link(src.join("pair/x"), src.join("pair/y")).unwrap();
+
+    let whole = dir::put_fs_item(&mut ds, &src, &|_| true).unwrap();
+    let pair = dir::put_fs_item(&mut ds, &src.join("pair"), &|_| true).unwrap();
+    let stored = dir::walk_fs_items(&ds, whole.into()).unwrap();
+    assert_eq!(stored[Path::new("pair")].0.inner(), pair);
+
+    let sub = dir::put_fs_item(&mut ds, &src.join("sub"), &|_| true).unwrap();
+    assert!(dir::walk_fs_items_and_links(&ds, sub.into())
+        .unwrap()
+        .links
+        .is_empty());
+
+    let out = dir.join("whole");
+    dir::get_fs_item(&ds, whole.into(), &out, RestoreMetadata::default()).unwrap();
+    assert_eq!(ino(&out.join("pair/x")), ino(&out.join("pair/y")));
+    assert_eq!(ino(&out.join("c")), ino(&out.join("sub/b")));
+    assert_ne!(ino(&out.join("c")), ino(&out.join("pair/x")));
+
+    std::fs::remove_dir_all(&dir).unwrap();
+}
+
+#[test]
+fn special_files_round_trip() {
+    use snapcd::dir::{self, DeviceNumber, FSItem, SpecialFile};
+    use snapcd::metadata::RestoreMetadata;
+    use std::convert::TryInto;
+    use std::os::unix::ffi::OsStrExt;
+    use std::os::unix::fs::{FileTypeExt, MetadataExt, PermissionsExt};
+    use std::path::Path;
+    use std::str::FromStr;
+
+    fn mknod(path: &Path, mode: libc::mode_t, dev: libc::dev_t) -> std::io::Result<()> {
+        let path = std::ffi::CString::new(path.as_os_str().as_bytes()).unwrap();
+
+        if unsafe { libc::mknod(path.as_ptr(), mode, dev) } != 0 {
+            return Err(std::io::Error::last_os_error());
+        }
+
+        Ok(())
+    }
+
+    let dir = Path::new(env!("CARGO_TARGET_TMPDIR")).join("special_files_round_trip");
+    let _ = std::fs::remove_dir_all(&dir);
+    let src = dir.join("src");
+    std::fs::create_dir_all(&src).unwrap();
+
+    mknod(&src.join("fifo"), libc::S_IFIFO | 0o640, 0).unwrap();
+    let _socket = std::os::unix::net::UnixListener::bind(src.join("socket")).unwrap();
+
+    // Only root can make device nodes.
+    let null = unsafe { libc::makedev(1, 3) };
+    let devices = mknod(&src.join("null"), libc::S_IFCHR | 0o666, null).is_ok();
+
+    let mut ds = SqliteDS::new(":memory:").unwrap();
+    let key = dir::put_fs_item(&mut ds, &src, &|_| true).unwrap();
+
+    let item: FSItem = ds.get_obj(key).unwrap().try_into().unwrap();
+    let specials: std::collections::HashMap<_, _> = item
+        .children()
+        .map(|(name, key)| {
+            let child: FSItem = ds.get_obj(key.inner()).unwrap().try_into().unwrap();
+            (name.to_path_buf(), child.special())
+        })
+        .collect();
+    assert_eq!(specials[Path::new("fifo")], Some(SpecialFile::Fifo));
+    assert_eq!(specials[Path::new("socket")], Some(SpecialFile::Socket));
+
+    // They can be named wherever a tree can, e.g. by fetch and ls-tree.
+    let fifo = item
+        .children()
+        .find(|(name, _)| *name == Path::new("fifo"))
+        .unwrap()
+        .1
+        .inner();
+    let keyish = Keyish::from_str(&fifo.as_user_key()).unwrap();
+    assert_eq!(ds.resolve::<FSItem>(keyish).unwrap().inner(), fifo);
+
+    if devices {
+        assert_eq!(
+            specials[Path::new("null")],
+            Some(SpecialFile::CharDevice(DeviceNumber { major: 1, minor: 3 }))
+        );
+    }
+
+    let out = dir.join("out");
+    dir::get_fs_item(&ds, key.into(), &out, RestoreMetadata::default()).unwrap();
+
+    let fifo = std::fs::symlink_metadata(out.join("fifo")).unwrap();
+    assert!(fifo.file_type().is_fifo());
+    assert_eq!(fifo.permissions().mode() & 0o7777, 0o640);
+
+    // Sockets are recorded, but there's nothing listening to bring back.
+    assert!(!out.join("socket").exists());
+
+    if devices {
+        let null = std::fs::symlink_metadata(out.join("null")).unwrap();
+        assert!(null.file_type().is_char_device());
+        assert_eq!(
+            null.rdev(),
+            std::fs::metadata(src.join("null")).unwrap().rdev()
+        );
+    }
+
+    std::fs::remove_dir_all(&dir).unwrap();
+}
+
+#[test]
+fn directory_order_is_deterministic() {
+    use snapcd::dir::{self, FSItem};
+    use snapcd::object::ObjType;
+    use snapcd::Object;
+    use std::convert::TryInto;
+    use std::path::Path;
+    use std::time::{Duration, UNIX_EPOCH};
+
+    let dir = Path::new(env!("CARGO_TARGET_TMPDIR")).join("directory_order_is_deterministic");
+    let _ = std::fs::remove_dir_all(&dir);
+
+    let names = ["b", "a", "c", "B", "a.txt", "\u{e9}", "e"];
+    let mtime = UNIX_EPOCH + Duration::from_secs(1_600_000_000);
+
+    // The same tree, with everything created in a different order.
+    let build = |root: &Path, names: &mut dyn Iterator<Item = &&str>| {
+        std::fs::create_dir_all(root).unwrap();
+
+        for name in names {
+            let path = root.join(name);
+            std::fs::write(&path, name).unwrap();
+            std::fs::File::options()
+                .write(true)
+                .open(&path)
+                .unwrap()
+                .set_modified(mtime)
+                .unwrap();
+        }
+
+        std::fs::File::open(root)
+            .unwrap()
+            .set_modified(mtime)
+            .unwrap();
+    };
+
+    build(&dir.join("one"), &mut names.iter());
+    build(&dir.join("two"), &mut names.iter().rev());
+
+    let mut ds = SqliteDS::new(":memory:").unwrap();
+    let one = dir::put_fs_item(&mut ds, &dir.join("one"), &|_| true).unwrap();
+    let two = dir::put_fs_item(&mut ds, &dir.join("two"), &|_| true).unwrap();
+    assert_eq!(one, two);
+
+    let item: FSItem = ds.get_obj(one).unwrap().try_into().unwrap();
+    assert!(item.children_sorted());
+    let stored: Vec<_> = item
+        .children()
+        .map(|(name, _)| name.to_path_buf())
+        .collect();
+    assert_eq!(
+        stored,
+        ["B", "a", "a.txt", "b", "c", "e", "\u{e9}"]
+            .iter()
+            .map(Path::new)
+            .collect::<Vec<_>>()
+    );
+
+    // Older versions stored entries in whatever order they were listed, which still reads, but
+    // fsck points out.
+    #[derive(serde::Serialize)]
+    struct OldDir {
+        size: u64,
+        itemtype: &'static str,
+        children_names: Vec<&'static str>,
+    }
+
+    let children: std::collections::HashMap<_, _> = item
+        .children()
+        .map(|(name, key)| (name.to_path_buf(), key.inner()))
+        .collect();
+    let data = serde_cbor::to_vec(&OldDir {
+        size: 2,
+        itemtype: "Dir",
+        children_names: vec!["b", "a"],
+    })
+    .unwrap();
+    let old = ds
+        .put_obj(&Object::new(
+            &data,
+            &[children[Path::new("b")], children[Path::new("a")]],
+            ObjType::FSItemDir,
+        ))
+        .unwrap();
+
+    let old_item: FSItem = ds.get_obj(old).unwrap().try_into().unwrap();
+    assert!(!old_item.children_sorted());
+    assert_eq!(dir::walk_fs_items(&ds, old.into()).unwrap().len(), 2);
+
+    let report = fsck::fsck(&ds).unwrap();
+    assert_eq!(report.unsorted, vec![old]);
+    assert!(report.is_clean());
+
+    // Two entries with one name would be restored over each other.
+    let data = serde_cbor::to_vec(&OldDir {
+        size: 2,
+        itemtype: "Dir",
+        children_names: vec!["a", "a"],
+    })
+    .unwrap();
+    let duplicated = ds
+        .put_obj(&Object::new(
+            &data,
+            &[children[Path::new("a")], children[Path::new("b")]],
+            ObjType::FSItemDir,
+        ))
+        .unwrap();
+    let decoded: Result<FSItem, _> = ds.get_obj(duplicated).unwrap().try_into();
+    assert!(decoded.is_err());
+
+    std::fs::remove_dir_all(&dir).unwrap();
+}
+
+#[test]
+fn cache_notices_rewrites_within_a_second() {
+    use snapcd::cache::{Cache, CacheKey, SqliteCache};
+    use snapcd::dir::{self, Checkpoints, PutOptions};
+    use std::path::Path;
+    use std::sync::atomic::AtomicBool;
+    use std::time::{Duration, UNIX_EPOCH};
+
+    let dir = Path::new(env!("CARGO_TARGET_TMPDIR")).join("cache_notices_rewrites_within_a_second");
+    let _ = std::fs::remove_dir_all(&dir);
+    std::fs::create_dir_all(&dir).unwrap();
+    let file = dir.join("file");
+
+    let mtime = UNIX_EPOCH + Duration::from_secs(1_600_000_000);
+    let write = |contents: &str| {
+        std::fs::write(&file, contents).unwrap();
+        std::fs::File::options()
+            .write(true)
+            .open(&file)
+            .unwrap()
+            .set_modified(mtime)
+            .unwrap();
+    };
+
+    let mut ds = SqliteDS::new(":memory:").unwrap();
+    let mut cache = SqliteCache::new(":memory:").unwrap();
+    let cancel = AtomicBool::new(false);
+    let mut checkpoints = Checkpoints::new(u64::MAX, &cancel);
+
+    let mut put = |ds: &mut SqliteDS| {
+        dir::put_fs_item_checkpointed(
+            ds,
+            &file,
+            &|_| true,
+            PutOptions::default(),
+            &mut cache,
+            &mut checkpoints,
+        )
+        .unwrap()
+    };
+
+    // The same size and modification time, and most likely the same second of change time.
+    write("first");
+    let first = put(&mut ds);
+    write("again");
+    let again = put(&mut ds);
+    assert_ne!(first, again);
+
+    let mut fresh = SqliteDS::new(":memory:").unwrap();
+    assert_eq!(
+        dir::put_fs_item(&mut fresh, &file, &|_| true).unwrap(),
+        again
+    );
+
+    // Anything modified this second could change again unnoticed, so isn't trusted.
+    std::fs::write(&file, "now").unwrap();
+    let now = std::fs::metadata(&file).unwrap();
+    let key = dir::put_fs_item(&mut ds, &file, &|_| true).unwrap();
+    cache.put(CacheKey::from_metadata(&now), key).unwrap();
+    assert_eq!(cache.get(CacheKey::from_metadata(&now)).unwrap(), None);
+
+    // Nor is anything changed this second, even with its modification time set back.
+    write("touched");
+    let touched = std::fs::metadata(&file).unwrap();
+    assert_eq!(touched.modified().unwrap(), mtime);
+    let key = dir::put_fs_item(&mut ds, &file, &|_| true).unwrap();
+    cache.put(CacheKey::from_metadata(&touched), key).unwrap();
+    assert_eq!(cache.get(CacheKey::from_metadata(&touched)).unwrap(), None);
+
+    std::fs::remove_dir_all(&dir).unwrap();
+}
+
 proptest::proptest! {
     #[test]
     fn identity_read_write(value: Vec<u8>) {
         let mut ds = SqliteDS::new(":memory:").unwrap();
 
-        let key = put_data(&mut ds, &value[..]).unwrap();
+        let key = put_data(&mut ds, &value[..], &ChunkerConfig::default()).unwrap();
 
         let mut to = Vec::new();
 
@@ -77,4 +2122,74 @@ proptest::proptest! {
 
         assert_eq!(expected_keys, got_keys);
     }
+
+    #[test]
+    fn between_test_sharded(mut keys: HashSet<Vec<u8>>, start: Vec<u8>, end: Option<Vec<u8>>) {
+        let ds = sharded_memory_ds(3);
+
+        keys.retain(|x| !x.is_empty());
+
+        for key in &keys {
+            ds.raw_put(key, key).expect("failed to put key");
+        }
+
+        let mut expected_keys: Vec<Vec<u8>> = if let Some(e) = &end {
+            keys.iter().filter(|x| (&start..&e).contains(x)).cloned().collect()
+        } else {
+            keys.iter().filter(|x| (&start..).contains(x)).cloned().collect()
+        };
+
+        expected_keys.sort();
+
+        let got_keys = ds.raw_between(&start, end.as_deref()).expect("failed to get keys between");
+
+        assert_eq!(expected_keys, got_keys);
+    }
+
+    #[test]
+    fn corrupted_objects_dont_panic(
+        object: proptest::sample::Index,
+        position: proptest::sample::Index,
+        change in 1u8..,
+        truncate: bool,
+    ) {
+        use snapcd::display::{self, Kind, KeyStyle};
+        use snapcd::diff::{self, DiffTarget};
+
+        let (mut ds, head, keys) = corruption_fixture();
+
+        let key = *object.get(&keys);
+        let mut data = ds.get(key).unwrap().into_owned();
+        let at = position.index(data.len());
+        if truncate {
+            data.truncate(at);
+        } else {
+            data[at] ^= change;
+        }
+        ds.raw_replace(&key.as_db_key(), &data).unwrap();
+
+        // Any of these may fail, but none of them may panic.
+        let _ = display::log_obj(&mut ds, head, Kind::Stat, KeyStyle::Full);
+        let _ = display::log_obj(&mut ds, head, Kind::Patch, KeyStyle::Full);
+
+        if let Ok(c) = commit::Commit::from_key(&ds, head) {
+            let parent = c.parents().first().and_then(|&p| commit::Commit::from_key(&ds, p).ok());
+
+            if let Ok(r) = diff::compare(&mut ds, DiffTarget::Database(c.tree()), parent.map(|p| p.tree()), None) {
+                let _ = diff::line_stat(&ds, r);
+            }
+
+            let out = std::path::Path::new(env!("CARGO_TARGET_TMPDIR")).join("corrupted_objects_dont_panic");
+            let _ = std::fs::remove_dir_all(&out);
+            let _ = snapcd::dir::get_fs_item(&ds, c.tree(), &out, Default::default());
+        }
+
+        for &key in &keys {
+            let _ = read_data(&ds, key, &mut std::io::sink());
+
+            if let Ok(mut reader) = snapcd::file::BlobReader::new(&ds, key) {
+                let _ = std::io::copy(&mut reader, &mut std::io::sink());
+            }
+        }
+    }
 }